@@ -0,0 +1,370 @@
+//! Backoff and circuit breaking for the Govee cloud APIs.
+//!
+//! Each upstream API has a breaker per account and host that is consulted
+//! before a request is sent, so that one account being rate limited, or
+//! one host having trouble, doesn't suspend requests to the others.
+//! When the server asks us to slow down with a 429, we hold off for as
+//! long as its `Retry-After` header says. Server errors and transport
+//! failures hold off for an exponentially increasing period, or for
+//! longer if a `Retry-After` header asks for it, and after several of
+//! them in a row the circuit is opened for a longer period, so that a
+//! struggling service isn't hit by a retry storm.
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use reqwest::StatusCode;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const PLATFORM_API: &str = "platform_api";
+pub const UNDOC_API: &str = "undoc_api";
+pub const REST_API: &str = "rest_api";
+
+/// The delay after the first failure; doubled for each subsequent one
+const BASE_DELAY: Duration = Duration::from_secs(5);
+const MAX_DELAY: Duration = Duration::from_secs(300);
+/// The number of consecutive failures that open the circuit
+const OPEN_THRESHOLD: u32 = 5;
+const OPEN_DURATION: Duration = Duration::from_secs(900);
+/// Don't let an unreasonable Retry-After suspend requests for too long
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(3600);
+
+static BREAKERS: Lazy<Mutex<BTreeMap<String, Arc<CircuitBreaker>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Returns the name to use for the breaker of the upstream API
+/// `upstream` when it is used on behalf of `account`; the primary
+/// account is None
+pub fn upstream_for_account(upstream: &str, account: Option<&str>) -> String {
+    match account {
+        Some(account) => format!("{upstream}:{account}"),
+        None => upstream.to_string(),
+    }
+}
+
+/// Returns the breaker for requests to `host` made via `upstream`
+pub fn breaker(upstream: &str, host: &str) -> Arc<CircuitBreaker> {
+    let name = format!("{upstream}@{host}");
+    BREAKERS
+        .lock()
+        .entry(name.clone())
+        .or_insert_with(|| Arc::new(CircuitBreaker::new(name)))
+        .clone()
+}
+
+/// Returns the status of the breakers that have been used so far,
+/// keyed by `upstream[:account]@host`
+pub fn breaker_statuses() -> BTreeMap<String, BreakerStatus> {
+    BREAKERS
+        .lock()
+        .iter()
+        .map(|(name, breaker)| (name.clone(), breaker.status()))
+        .collect()
+}
+
+/// Sends `request` unless requests to its host via `upstream` are
+/// currently suspended, and records the outcome with its breaker
+pub async fn send_with_breaker(
+    upstream: &str,
+    request: reqwest::RequestBuilder,
+) -> anyhow::Result<reqwest::Response> {
+    send_with_breaker_after(upstream, request, || Ok(())).await
+}
+
+/// Like send_with_breaker, but calls `admit` once the breaker has
+/// allowed the request and before it is sent, so that accounting
+/// such as the daily quota isn't charged for requests that are held off
+pub async fn send_with_breaker_after<F: FnOnce() -> anyhow::Result<()>>(
+    upstream: &str,
+    request: reqwest::RequestBuilder,
+    admit: F,
+) -> anyhow::Result<reqwest::Response> {
+    let (client, request) = request.build_split();
+    let request = request?;
+    let breaker = breaker(upstream, request.url().host_str().unwrap_or_default());
+    breaker.check()?;
+    (admit)()?;
+
+    match client.execute(request).await {
+        Ok(response) => {
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after);
+            breaker.record(Some(response.status()), retry_after);
+            Ok(response)
+        }
+        Err(err) => {
+            breaker.record(None, None);
+            Err(err.into())
+        }
+    }
+}
+
+/// Parses the value of a Retry-After header, which is either
+/// a number of seconds or an HTTP date
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Requests are sent normally
+    Closed,
+    /// Requests are held off after a 429 or a failure
+    Backoff,
+    /// Requests are held off after repeated failures
+    Open,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct BreakerStatus {
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    /// How many seconds remain until requests are allowed again
+    pub retry_in_secs: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerInner {
+    consecutive_failures: u32,
+    blocked_until: Option<Instant>,
+    open: bool,
+    last_error: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: String,
+    inner: Mutex<BreakerInner>,
+}
+
+impl CircuitBreaker {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            inner: Mutex::new(BreakerInner::default()),
+        }
+    }
+
+    /// Returns an error if requests are currently suspended
+    pub fn check(&self) -> anyhow::Result<()> {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&self, now: Instant) -> anyhow::Result<()> {
+        let inner = self.inner.lock();
+        if let Some(until) = inner.blocked_until {
+            if until > now {
+                anyhow::bail!(
+                    "Requests to {} are suspended for another {}s after {}",
+                    self.name,
+                    (until - now).as_secs(),
+                    inner.last_error.as_deref().unwrap_or("earlier failures")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the outcome of a request; `status` is None if
+    /// the request failed without a response
+    pub fn record(&self, status: Option<StatusCode>, retry_after: Option<Duration>) {
+        self.record_at(status, retry_after, Instant::now())
+    }
+
+    fn record_at(&self, status: Option<StatusCode>, retry_after: Option<Duration>, now: Instant) {
+        let mut inner = self.inner.lock();
+
+        let delay = match status {
+            Some(StatusCode::TOO_MANY_REQUESTS) => {
+                inner.consecutive_failures += 1;
+                inner
+                    .last_error
+                    .replace("429 Too Many Requests".to_string());
+                retry_after
+                    .map(|d| d.min(MAX_RETRY_AFTER))
+                    .unwrap_or_else(|| backoff_delay(inner.consecutive_failures))
+            }
+            Some(status) if !status.is_server_error() => {
+                if inner.consecutive_failures > 0 {
+                    log::info!("{} has recovered", self.name);
+                }
+                *inner = BreakerInner::default();
+                return;
+            }
+            _ => {
+                inner.consecutive_failures += 1;
+                inner.last_error.replace(match status {
+                    Some(status) => format!("status {status}"),
+                    None => "a request failure".to_string(),
+                });
+                let delay = if inner.consecutive_failures >= OPEN_THRESHOLD {
+                    if !inner.open {
+                        log::warn!(
+                            "Opening the circuit for {} for {OPEN_DURATION:?} after {} failures",
+                            self.name,
+                            inner.consecutive_failures
+                        );
+                    }
+                    inner.open = true;
+                    OPEN_DURATION
+                } else {
+                    backoff_delay(inner.consecutive_failures)
+                };
+                // eg: a 503 may say how long the outage will last
+                retry_after
+                    .map(|d| d.min(MAX_RETRY_AFTER).max(delay))
+                    .unwrap_or(delay)
+            }
+        };
+
+        // Honour the longer of the existing and new suspension
+        let until = now + delay;
+        if inner
+            .blocked_until
+            .map(|prior| prior < until)
+            .unwrap_or(true)
+        {
+            inner.blocked_until.replace(until);
+        }
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        self.status_at(Instant::now())
+    }
+
+    fn status_at(&self, now: Instant) -> BreakerStatus {
+        let inner = self.inner.lock();
+        let retry_in = inner
+            .blocked_until
+            .map(|until| until.saturating_duration_since(now))
+            .unwrap_or_default();
+        let state = if retry_in.is_zero() {
+            BreakerState::Closed
+        } else if inner.open {
+            BreakerState::Open
+        } else {
+            BreakerState::Backoff
+        };
+        BreakerStatus {
+            state,
+            consecutive_failures: inner.consecutive_failures,
+            retry_in_secs: retry_in.as_secs(),
+            last_error: inner.last_error.clone(),
+        }
+    }
+}
+
+fn backoff_delay(failures: u32) -> Duration {
+    BASE_DELAY
+        .saturating_mul(
+            1u32.checked_shl(failures.saturating_sub(1))
+                .unwrap_or(u32::MAX),
+        )
+        .min(MAX_DELAY)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn breaker_states() {
+        let breaker = CircuitBreaker::new("test".to_string());
+        let now = Instant::now();
+
+        breaker.record_at(
+            Some(StatusCode::TOO_MANY_REQUESTS),
+            Some(Duration::from_secs(60)),
+            now,
+        );
+        assert!(breaker.check_at(now).is_err());
+        assert_eq!(breaker.status_at(now).state, BreakerState::Backoff);
+        assert!(breaker.check_at(now + Duration::from_secs(61)).is_ok());
+
+        breaker.record_at(Some(StatusCode::OK), None, now);
+        assert_eq!(breaker.status_at(now).state, BreakerState::Closed);
+        assert!(breaker.check_at(now).is_ok());
+
+        // Client errors are the caller's problem, not the server's
+        breaker.record_at(Some(StatusCode::NOT_FOUND), None, now);
+        assert!(breaker.check_at(now).is_ok());
+
+        for failure in 1..OPEN_THRESHOLD {
+            breaker.record_at(Some(StatusCode::BAD_GATEWAY), None, now);
+            assert_eq!(
+                breaker.status_at(now).retry_in_secs,
+                backoff_delay(failure).as_secs()
+            );
+        }
+        assert_eq!(breaker.status_at(now).retry_in_secs, 40);
+
+        breaker.record_at(None, None, now);
+        let status = breaker.status_at(now);
+        assert_eq!(status.state, BreakerState::Open);
+        assert_eq!(status.retry_in_secs, OPEN_DURATION.as_secs());
+        assert_eq!(status.last_error.as_deref(), Some("a request failure"));
+    }
+
+    #[test]
+    fn server_error_retry_after() {
+        let breaker = CircuitBreaker::new("test".to_string());
+        let now = Instant::now();
+
+        breaker.record_at(
+            Some(StatusCode::SERVICE_UNAVAILABLE),
+            Some(Duration::from_secs(120)),
+            now,
+        );
+        assert_eq!(breaker.status_at(now).retry_in_secs, 120);
+
+        // The exponential delay applies if it is the longer of the two
+        breaker.record_at(
+            Some(StatusCode::SERVICE_UNAVAILABLE),
+            Some(Duration::from_secs(1)),
+            now,
+        );
+        assert_eq!(
+            breaker.status_at(now).retry_in_secs,
+            backoff_delay(2).max(Duration::from_secs(120)).as_secs()
+        );
+    }
+
+    #[test]
+    fn breakers_per_account_and_host() {
+        let primary = upstream_for_account("test_api", None);
+        let cabin = upstream_for_account("test_api", Some("cabin"));
+        assert_eq!(cabin, "test_api:cabin");
+
+        breaker(&cabin, "a.example.com").record(Some(StatusCode::TOO_MANY_REQUESTS), None);
+        assert!(breaker(&cabin, "a.example.com").check().is_err());
+        assert!(breaker(&cabin, "b.example.com").check().is_ok());
+        assert!(breaker(&primary, "a.example.com").check().is_ok());
+        assert!(breaker_statuses().contains_key("test_api:cabin@a.example.com"));
+    }
+}
@@ -1,7 +1,7 @@
 use anyhow::anyhow;
 use once_cell::sync::Lazy;
 use parking_lot::{MappedMutexGuard, Mutex, MutexGuard};
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::any::{Any, TypeId};
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -17,9 +17,12 @@ impl std::fmt::Debug for HexBytes {
     }
 }
 
+type EncodeFn = Box<dyn Fn(&dyn Any) -> anyhow::Result<Vec<u8>> + Sync + Send>;
+type DecodeFn = Box<dyn Fn(&[u8]) -> anyhow::Result<GoveeBlePacket> + Sync + Send>;
+
 pub struct PacketCodec {
-    encode: Box<dyn Fn(&dyn Any) -> anyhow::Result<Vec<u8>> + Sync + Send>,
-    decode: Box<dyn Fn(&[u8]) -> anyhow::Result<GoveeBlePacket> + Sync + Send>,
+    encode: EncodeFn,
+    decode: DecodeFn,
     supported_skus: &'static [&'static str],
     type_id: TypeId,
 }
@@ -51,16 +54,16 @@ pub struct PacketManager {
 }
 
 impl PacketManager {
-    fn map_for_sku(&self, sku: &str) -> MappedMutexGuard<HashMap<TypeId, Arc<PacketCodec>>> {
+    fn map_for_sku(&self, sku: &str) -> MappedMutexGuard<'_, HashMap<TypeId, Arc<PacketCodec>>> {
         MutexGuard::map(self.codec_by_sku.lock(), |codecs| {
             codecs.entry(sku.to_string()).or_insert_with(|| {
                 let mut map = HashMap::new();
 
                 for codec in &self.all_codecs {
-                    if codec.supported_skus.iter().any(|s| *s == sku) {
-                        if map.insert(codec.type_id.clone(), codec.clone()).is_some() {
-                            eprintln!("Conflicting PacketCodecs for {sku} {:?}", codec.type_id);
-                        }
+                    if codec.supported_skus.contains(&sku)
+                        && map.insert(codec.type_id, codec.clone()).is_some()
+                    {
+                        eprintln!("Conflicting PacketCodecs for {sku} {:?}", codec.type_id);
                     }
                 }
 
@@ -232,6 +235,53 @@ impl PacketManager {
             0x04,
             code,
         ));
+        all_codecs.push(packet!(
+            &["Generic:Light"],
+            SetSegmentRgb,
+            SetSegmentRgb,
+            0x33,
+            0x05,
+            0x15,
+            0x01,
+            r,
+            g,
+            b,
+            // Color temperature and its RGB equivalent; unused
+            // when setting an RGB color
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            segments,
+        ));
+        all_codecs.push(packet!(
+            &["Generic:Light"],
+            SetSegmentBrightness,
+            SetSegmentBrightness,
+            0x33,
+            0x05,
+            0x15,
+            0x02,
+            brightness,
+            segments,
+        ));
+        all_codecs.push(packet!(
+            &["Generic:Light"],
+            SetMusicMode,
+            SetMusicMode,
+            0x33,
+            0x05,
+            0x13,
+            mode,
+            sensitivity,
+            // 0 selects the dynamic rather than calm variant
+            0x00,
+            auto_color,
+            r,
+            g,
+            b,
+        ));
         all_codecs.push(packet!(
             &["Generic:Light"],
             SetDevicePower,
@@ -255,7 +305,7 @@ pub trait DecodePacketParam {
 
 impl DecodePacketParam for u8 {
     fn decode_param<'a>(&mut self, data: &'a [u8]) -> anyhow::Result<&'a [u8]> {
-        *self = *data.get(0).ok_or_else(|| anyhow!("EOF"))?;
+        *self = *data.first().ok_or_else(|| anyhow!("EOF"))?;
         Ok(&data[1..])
     }
 
@@ -266,7 +316,7 @@ impl DecodePacketParam for u8 {
 
 impl DecodePacketParam for u16 {
     fn decode_param<'a>(&mut self, data: &'a [u8]) -> anyhow::Result<&'a [u8]> {
-        let lo = *data.get(0).ok_or_else(|| anyhow!("EOF"))?;
+        let lo = *data.first().ok_or_else(|| anyhow!("EOF"))?;
         let hi = *data.get(1).ok_or_else(|| anyhow!("EOF"))?;
         *self = ((hi as u16) << 8) | lo as u16;
         Ok(&data[2..])
@@ -289,19 +339,19 @@ pub struct SetHumidifierNightlightParams {
     pub brightness: u8,
 }
 
-impl Into<SetHumidifierNightlightParams> for NotifyHumidifierNightlightParams {
-    fn into(self) -> SetHumidifierNightlightParams {
+impl From<NotifyHumidifierNightlightParams> for SetHumidifierNightlightParams {
+    fn from(val: NotifyHumidifierNightlightParams) -> Self {
         SetHumidifierNightlightParams {
-            on: self.on,
-            r: self.r,
-            g: self.g,
-            b: self.b,
-            brightness: self.brightness,
+            on: val.on,
+            r: val.r,
+            g: val.g,
+            b: val.b,
+            brightness: val.brightness,
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub struct NotifyHumidifierNightlightParams {
     pub on: bool,
     pub r: u8,
@@ -315,9 +365,9 @@ pub struct NotifyHumidifierNightlightParams {
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TargetHumidity(u8);
 
-impl Into<u8> for TargetHumidity {
-    fn into(self) -> u8 {
-        self.0
+impl From<TargetHumidity> for u8 {
+    fn from(val: TargetHumidity) -> Self {
+        val.0
     }
 }
 
@@ -367,16 +417,118 @@ pub struct SetSceneCode {
     pub code: u16,
 }
 
+/// The number of payload bytes carried by each packet of a
+/// multi-packet upload; the remaining bytes hold the 0xa3
+/// prefix, the packet index and the checksum
+const MULTI_PACKET_CHUNK: usize = 17;
+
+/// Encodes `data` as a sequence of 0xa3-prefixed packets.
+/// The payload is preceded by a small header that holds the number
+/// of packets in the sequence. Each packet carries its index in
+/// the second byte, except for the last one, which uses 0xff.
+pub fn encode_multi_packet(data: &[u8]) -> anyhow::Result<Vec<Base64HexBytes>> {
+    let num_packets = (data.len() + 3).div_ceil(MULTI_PACKET_CHUNK);
+    let num_packets_byte = u8::try_from(num_packets)
+        .ok()
+        .filter(|&n| n < 0xff)
+        .ok_or_else(|| anyhow!("{} bytes is too large for a multi-packet", data.len()))?;
+
+    let mut payload = vec![0x01, num_packets_byte, 0x02];
+    payload.extend_from_slice(data);
+
+    Ok(payload
+        .chunks(MULTI_PACKET_CHUNK)
+        .enumerate()
+        .map(|(idx, chunk)| {
+            let index = if idx + 1 == num_packets {
+                0xff
+            } else {
+                idx as u8
+            };
+            let mut packet = vec![0xa3, index];
+            packet.extend_from_slice(chunk);
+            Base64HexBytes::with_bytes(packet)
+        })
+        .collect())
+}
+
+/// Returns the packets that activate a scene from the light
+/// effect library. Scenes with a `scence_param` need their
+/// parameters to be uploaded before the scene code is sent.
+pub fn encode_scene_activation(
+    scene_code: u16,
+    scence_param: &str,
+) -> anyhow::Result<Vec<Base64HexBytes>> {
+    let param = data_encoding::BASE64.decode(scence_param.as_bytes())?;
+    anyhow::ensure!(
+        scene_code != 0 || !param.is_empty(),
+        "scene has neither a code nor parameters"
+    );
+
+    let mut packets = if param.is_empty() {
+        vec![]
+    } else {
+        encode_multi_packet(&param)?
+    };
+    packets.push(Base64HexBytes::encode_for_sku(
+        "Generic:Light",
+        &SetSceneCode { code: scene_code },
+    )?);
+    Ok(packets)
+}
+
 #[derive(Clone, Default, Debug, PartialEq, Eq)]
 pub struct SetDevicePower {
     pub on: bool,
 }
 
+/// Activates a music mode. `mode` is the same value that the
+/// platform API uses to identify the mode.
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct SetMusicMode {
+    pub mode: u8,
+    /// The microphone sensitivity in percent
+    pub sensitivity: u8,
+    /// When true, the device picks the colors; otherwise
+    /// the r, g, b color is used
+    pub auto_color: bool,
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+/// Returns the bitmask that selects `segment` in the segment
+/// packets, which can address up to 16 segments
+pub fn segment_mask(segment: u32) -> anyhow::Result<u16> {
+    1u16.checked_shl(segment)
+        .ok_or_else(|| anyhow!("segment {segment} cannot be addressed via BLE packets"))
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct SetSegmentRgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    /// A bitmask of the segments to change
+    pub segments: u16,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct SetSegmentBrightness {
+    /// The brightness in percent
+    pub brightness: u8,
+    /// A bitmask of the segments to change
+    pub segments: u16,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum GoveeBlePacket {
     Generic(HexBytes),
     SetSceneCode(SetSceneCode),
     SetDevicePower(SetDevicePower),
+    SetSegmentRgb(SetSegmentRgb),
+    SetSegmentBrightness(SetSegmentBrightness),
+    SetMusicMode(SetMusicMode),
     SetHumidifierNightlight(SetHumidifierNightlightParams),
     NotifyHumidifierMode(NotifyHumidifierMode),
     SetHumidifierMode(SetHumidifierMode),
@@ -397,6 +549,11 @@ impl Base64HexBytes {
             .map(|bytes| Base64HexBytes(HexBytes(bytes)))
     }
 
+    pub fn from_base64(encoded: &str) -> anyhow::Result<Self> {
+        let decoded = data_encoding::BASE64.decode(encoded.as_bytes())?;
+        Ok(Self(HexBytes(decoded)))
+    }
+
     pub fn base64(&self) -> String {
         data_encoding::BASE64.encode(&self.0 .0)
     }
@@ -423,7 +580,7 @@ impl<'de> Deserialize<'de> for Base64HexBytes {
 fn calculate_checksum(data: &[u8]) -> u8 {
     let mut checksum: u8 = 0;
     for &b in data {
-        checksum = checksum ^ b;
+        checksum ^= b;
     }
     checksum
 }
@@ -466,6 +623,102 @@ impl GoveeBlePacket {}
 mod test {
     use super::*;
 
+    #[test]
+    fn multi_packet() {
+        let packets = encode_multi_packet(&(1..=20).collect::<Vec<u8>>()).unwrap();
+        let packets: Vec<&[u8]> = packets.iter().map(|p| p.0 .0.as_slice()).collect();
+        assert_eq!(
+            packets,
+            vec![
+                &[
+                    0xa3, 0x00, 0x01, 0x02, 0x02, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14,
+                    0xad
+                ][..],
+                &[0xa3, 0xff, 15, 16, 17, 18, 19, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x47][..],
+            ]
+        );
+    }
+
+    #[test]
+    fn light_effect_library_activation() {
+        let resp: crate::undoc_api::LightEffectLibraryResponse =
+            serde_json::from_str(include_str!("../test-data/light-effect-library-h6072.json"))
+                .unwrap();
+
+        let mut num_multi = 0;
+        for category in resp.data.categories {
+            for scene in category.scenes {
+                for effect in scene.light_effects {
+                    let packets =
+                        encode_scene_activation(effect.scene_code, &effect.scence_param).unwrap();
+                    let (code, params) = packets.split_last().unwrap();
+                    assert_eq!(
+                        code.decode_for_sku("Generic:Light"),
+                        GoveeBlePacket::SetSceneCode(SetSceneCode {
+                            code: effect.scene_code
+                        }),
+                        "{}",
+                        scene.scene_name
+                    );
+
+                    let param = data_encoding::BASE64
+                        .decode(effect.scence_param.as_bytes())
+                        .unwrap();
+                    if param.is_empty() {
+                        assert!(params.is_empty());
+                        continue;
+                    }
+                    num_multi += 1;
+
+                    let mut uploaded = vec![];
+                    for (idx, packet) in params.iter().enumerate() {
+                        let bytes = &packet.0 .0;
+                        assert_eq!(bytes.len(), 20);
+                        assert_eq!(calculate_checksum(bytes), 0);
+                        assert_eq!(bytes[0], 0xa3);
+                        let expect_index = if idx + 1 == params.len() {
+                            0xff
+                        } else {
+                            idx as u8
+                        };
+                        assert_eq!(bytes[1], expect_index, "{}", scene.scene_name);
+                        uploaded.extend_from_slice(&bytes[2..19]);
+                    }
+                    assert_eq!(uploaded[..3], [0x01, params.len() as u8, 0x02]);
+                    assert_eq!(
+                        uploaded[3..3 + param.len()],
+                        param[..],
+                        "{}",
+                        scene.scene_name
+                    );
+                    assert!(uploaded[3 + param.len()..].iter().all(|&b| b == 0));
+                }
+            }
+        }
+        assert_eq!(num_multi, 46);
+    }
+
+    #[test]
+    fn segment_packets() {
+        assert_eq!(
+            MGR.encode_for_sku(
+                "Generic:Light",
+                &SetSegmentRgb {
+                    r: 0xff,
+                    g: 0x00,
+                    b: 0x00,
+                    segments: 0x0101,
+                }
+            )
+            .unwrap(),
+            vec![
+                0x33, 0x05, 0x15, 0x01, 0xff, 0x00, 0x00, 0, 0, 0, 0, 0, 0x01, 0x01, 0, 0, 0, 0, 0,
+                0xdd
+            ]
+        );
+        assert!(segment_mask(16).is_err());
+    }
+
     #[test]
     fn packet_manager() {
         assert_eq!(
@@ -510,6 +763,49 @@ mod test {
             &SetDevicePower { on: true },
             GoveeBlePacket::SetDevicePower(SetDevicePower { on: true }),
         );
+        round_trip(
+            "Generic:Light",
+            &SetSegmentRgb {
+                r: 255,
+                g: 0,
+                b: 42,
+                segments: segment_mask(3).unwrap(),
+            },
+            GoveeBlePacket::SetSegmentRgb(SetSegmentRgb {
+                r: 255,
+                g: 0,
+                b: 42,
+                segments: 0x08,
+            }),
+        );
+        round_trip(
+            "Generic:Light",
+            &SetSegmentBrightness {
+                brightness: 50,
+                segments: segment_mask(14).unwrap(),
+            },
+            GoveeBlePacket::SetSegmentBrightness(SetSegmentBrightness {
+                brightness: 50,
+                segments: 0x4000,
+            }),
+        );
+        round_trip(
+            "Generic:Light",
+            &SetMusicMode {
+                mode: 3,
+                sensitivity: 80,
+                auto_color: true,
+                ..SetMusicMode::default()
+            },
+            GoveeBlePacket::SetMusicMode(SetMusicMode {
+                mode: 3,
+                sensitivity: 80,
+                auto_color: true,
+                r: 0,
+                g: 0,
+                b: 0,
+            }),
+        );
         round_trip(
             "H7160",
             &SetHumidifierNightlightParams {
@@ -1,3 +1,4 @@
+use crate::config::config;
 use anyhow::Context;
 use arc_swap::ArcSwap;
 use chrono::{DateTime, Utc};
@@ -17,16 +18,23 @@ fn cache_file_name() -> PathBuf {
     let cache_dir = std::env::var("GOVEE_CACHE_DIR")
         .ok()
         .map(PathBuf::from)
-        .or_else(|| dirs_next::cache_dir())
+        .or_else(|| config().general.cache_dir.clone())
+        .or_else(dirs_next::cache_dir)
         .expect("failed to resolve cache dir");
 
     cache_dir.join("govee2mqtt-cache.sqlite")
 }
 
 fn open_cache() -> anyhow::Result<Arc<Cache>> {
-    let cache_file = cache_file_name();
-    let conn = sqlite_cache::rusqlite::Connection::open(&cache_file)
-        .expect(&format!("failed to open {cache_file:?}"));
+    // Tests get a private cache, rather than sharing the user's
+    #[cfg(test)]
+    let conn = sqlite_cache::rusqlite::Connection::open_in_memory()?;
+    #[cfg(not(test))]
+    let conn = {
+        let cache_file = cache_file_name();
+        sqlite_cache::rusqlite::Connection::open(&cache_file)
+            .unwrap_or_else(|_| panic!("failed to open {cache_file:?}"))
+    };
     Ok(Arc::new(Cache::new(
         // We have low cardinality and can be pretty relaxed
         CacheConfig {
@@ -42,7 +50,7 @@ pub fn purge_cache() -> anyhow::Result<()> {
     let cache_file = cache_file_name();
     std::fs::remove_file(&cache_file)
         .with_context(|| format!("removing cache file {cache_file:?}"))?;
-    CACHE.store(open_cache()?.into());
+    CACHE.store(open_cache()?);
     Ok(())
 }
 
@@ -96,6 +104,27 @@ pub fn invalidate_key(topic: &str, key: &str) -> anyhow::Result<()> {
     Ok(topic.delete(key)?)
 }
 
+/// Store a value directly, replacing any prior value for `key`
+pub fn cache_put<T: Serialize>(
+    topic: &str,
+    key: &str,
+    value: &T,
+    ttl: Duration,
+) -> anyhow::Result<()> {
+    let topic = CACHE.load().topic(topic)?;
+    let data = serde_json::to_vec(value)?;
+    Ok(topic.set(key, &data, ttl)?)
+}
+
+/// Retrieve a value that was stored via cache_put
+pub fn cache_lookup<T: DeserializeOwned>(topic: &str, key: &str) -> anyhow::Result<Option<T>> {
+    let topic = CACHE.load().topic(topic)?;
+    match topic.get(key)? {
+        Some(value) => Ok(Some(serde_json::from_slice(&value.data)?)),
+        None => Ok(None),
+    }
+}
+
 /// Cache an item with a soft TTL; we'll retry the operation
 /// if the TTL has expired, but allow stale reads
 pub async fn cache_get<T, Fut>(options: CacheGetOptions<'_>, future: Fut) -> anyhow::Result<T>
@@ -114,6 +143,7 @@ where
             Ok(entry) => {
                 if now < entry.expires {
                     log::trace!("cache hit for {}", options.key);
+                    crate::metrics::cache_request(options.topic, "hit");
                     return entry.result.into_result();
                 }
 
@@ -129,6 +159,14 @@ where
     }
 
     log::trace!("cache miss for {}", options.key);
+    crate::metrics::cache_request(
+        options.topic,
+        if cache_entry.is_some() {
+            "stale"
+        } else {
+            "miss"
+        },
+    );
     let value: anyhow::Result<CacheComputeResult<T>> = future.await;
     match value {
         Ok(CacheComputeResult::WithTtl(value, ttl)) => {
@@ -0,0 +1,93 @@
+use crate::lan_api::{DeviceColor, DeviceStatus, LanPorts};
+use crate::lan_sim::{LanSimulator, SimulatedDevice};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use tokio::time::{sleep, Duration};
+
+/// Simulate one or more devices that speak the Govee LAN protocol,
+/// for testing without physical devices
+#[derive(clap::Parser, Debug)]
+pub struct LanSimCommand {
+    /// The SKU of the simulated device(s)
+    #[arg(long, default_value = "H6072")]
+    sku: String,
+
+    /// The device id of the first simulated device.
+    /// Subsequent devices will have the final byte incremented.
+    #[arg(long, default_value = "AA:BB:CC:DD:EE:FF:00:01")]
+    id: String,
+
+    /// The address of the first simulated device.
+    /// Subsequent devices will use the following addresses,
+    /// so the default of using the loopback network allows
+    /// simulating multiple devices on the local machine.
+    #[arg(long, default_value = "127.0.0.1")]
+    ip: Ipv4Addr,
+
+    /// How many devices to simulate
+    #[arg(long, default_value_t = 1)]
+    count: u8,
+
+    /// The address on which to listen for scan requests
+    #[arg(long, default_value = "0.0.0.0")]
+    scan_bind: IpAddr,
+
+    /// Don't respond to status requests, simulating
+    /// unresponsive devices
+    #[arg(long)]
+    ignore_status: bool,
+}
+
+impl LanSimCommand {
+    fn devices(&self) -> anyhow::Result<Vec<SimulatedDevice>> {
+        let (prefix, last) = self
+            .id
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow::anyhow!("invalid device id {}", self.id))?;
+        let last = u8::from_str_radix(last, 16)?;
+
+        let mut devices = vec![];
+        for n in 0..self.count {
+            let id = format!("{prefix}:{:02X}", last.wrapping_add(n));
+            let ip = Ipv4Addr::from(u32::from(self.ip) + n as u32);
+            let mut device = SimulatedDevice::new(&self.sku, id, ip.into());
+            device.respond_to_status = !self.ignore_status;
+            device.initial_status = DeviceStatus {
+                on: true,
+                brightness: 100,
+                color: DeviceColor {
+                    r: 255,
+                    g: 255,
+                    b: 255,
+                },
+                color_temperature_kelvin: 0,
+            };
+            devices.push(device);
+        }
+        Ok(devices)
+    }
+
+    pub async fn run(&self, _args: &crate::Args) -> anyhow::Result<()> {
+        let devices = self.devices()?;
+        for device in &devices {
+            println!("Simulating {} {} at {}", device.sku, device.id, device.ip);
+        }
+
+        let sim = LanSimulator::start(devices, self.scan_bind, LanPorts::default()).await?;
+
+        // Report state changes as they are made by clients
+        let mut last_state = HashMap::new();
+        loop {
+            for id in sim.device_ids() {
+                if let Some(state) = sim.state(&id) {
+                    let status = format!("{:?} scene={:?}", state.status, state.scene_code);
+                    if last_state.get(&id) != Some(&status) {
+                        println!("{id}: {status}");
+                        last_state.insert(id, status);
+                    }
+                }
+            }
+            sleep(Duration::from_millis(250)).await;
+        }
+    }
+}
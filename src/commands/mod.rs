@@ -1,7 +1,10 @@
 pub mod http_control;
 pub mod lan_control;
 pub mod lan_disco;
+pub mod lan_sim;
 pub mod list;
 pub mod list_http;
+pub mod platform_mock;
+pub mod quirk;
 pub mod serve;
 pub mod undoc;
@@ -0,0 +1,37 @@
+use crate::platform_mock::MockPlatformApi;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Serve a mock of the Govee Platform API, for testing without
+/// an API key or network access. Point govee2mqtt at it using
+/// `--api-base-url`.
+#[derive(clap::Parser, Debug)]
+pub struct PlatformMockCommand {
+    /// The directory from which to load the device list,
+    /// device state and scene data
+    #[arg(long, default_value = "test-data")]
+    data_dir: PathBuf,
+
+    /// The address on which to listen
+    #[arg(long, default_value = "127.0.0.1")]
+    bind: IpAddr,
+
+    /// The port on which to listen
+    #[arg(long, default_value_t = 8057)]
+    port: u16,
+}
+
+impl PlatformMockCommand {
+    pub async fn run(&self, _args: &crate::Args) -> anyhow::Result<()> {
+        let mock = Arc::new(MockPlatformApi::load(&self.data_dir)?);
+        for d in mock.devices() {
+            println!("{sku:<7} {id}", sku = d.sku, id = d.device);
+        }
+
+        let addr = mock.serve(SocketAddr::new(self.bind, self.port)).await?;
+        println!("Serving mock platform API on http://{addr}");
+        std::future::pending::<()>().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,26 @@
+use crate::service::quirks::{has_user_quirk, resolve_quirk};
+
+/// Show the effective quirk for a SKU, taking into account
+/// any entries from the quirks file
+#[derive(clap::Parser, Debug)]
+pub struct QuirkCommand {
+    sku: String,
+}
+
+impl QuirkCommand {
+    pub async fn run(&self, _args: &crate::Args) -> anyhow::Result<()> {
+        let sku = self.sku.to_ascii_uppercase();
+        match resolve_quirk(&sku) {
+            Some(quirk) => {
+                if has_user_quirk(&sku) {
+                    println!("{sku} has an entry in the quirks file");
+                }
+                println!("{quirk:#?}");
+            }
+            None => {
+                println!("There is no quirk defined for {sku}");
+            }
+        }
+        Ok(())
+    }
+}
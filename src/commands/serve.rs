@@ -1,24 +1,30 @@
+use crate::config::{config, AccountConfig};
 use crate::lan_api::Client as LanClient;
+use crate::platform_api::GoveeApiClient;
 use crate::service::device::Device;
 use crate::service::hass::spawn_hass_integration;
 use crate::service::http::run_http_server;
-use crate::service::iot::start_iot_client;
+use crate::service::iot::{start_iot_client, start_iot_client_for_account};
+use crate::service::schedule::run_scheduler;
 use crate::service::state::StateHandle;
+use crate::service::transport::TransportPolicy;
+use crate::undoc_api::GoveeUndocumentedApi;
 use crate::version_info::govee_version;
 use anyhow::Context;
 use chrono::Utc;
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, Duration, Instant};
 
-pub const POLL_INTERVAL: Lazy<chrono::Duration> = Lazy::new(|| chrono::Duration::seconds(900));
+pub static POLL_INTERVAL: Lazy<chrono::Duration> = Lazy::new(|| chrono::Duration::seconds(900));
 
 #[derive(clap::Parser, Debug)]
 pub struct ServeCommand {
-    /// The port on which the HTTP API will listen
-    #[arg(long, default_value_t = 8056)]
-    http_port: u16,
+    /// The port on which the HTTP API will listen.
+    /// If unspecified, uses 8056
+    #[arg(long)]
+    http_port: Option<u16>,
 }
 
 async fn poll_single_device(state: &StateHandle, device: &Device) -> anyhow::Result<()> {
@@ -29,7 +35,11 @@ async fn poll_single_device(state: &StateHandle, device: &Device) -> anyhow::Res
         return Ok(());
     }
 
-    let poll_interval = device.preferred_poll_interval();
+    let mut poll_interval = device.preferred_poll_interval();
+    if let Some(client) = state.platform_client_for(device).await {
+        // Slow down as the daily request budget runs low
+        poll_interval = poll_interval * client.quota().poll_interval_multiplier();
+    }
 
     let can_update = match &device.last_polled {
         None => true,
@@ -43,7 +53,7 @@ async fn poll_single_device(state: &StateHandle, device: &Device) -> anyhow::Res
     let device_state = device.device_state();
     let needs_update = match &device_state {
         None => true,
-        Some(state) => now - state.updated > poll_interval,
+        Some(state) => state.stale || now - state.updated > poll_interval,
     };
 
     if !needs_update {
@@ -56,18 +66,76 @@ async fn poll_single_device(state: &StateHandle, device: &Device) -> anyhow::Res
     // If we have LAN and the device is stale, it is likely
     // offline and there is little sense in burning up request
     // quota to the platform API for it
-    if device.lan_device.is_some() && !needs_platform {
+    if state.lan_device_for(device).await.is_some() && !needs_platform {
         log::trace!("LAN-available device {device} needs a status update; it's likely offline.");
         return Ok(());
     }
 
-    if !needs_platform {
-        if state.poll_iot_api(&device).await? {
-            return Ok(());
+    let started = Instant::now();
+    let iot_result = if needs_platform {
+        Ok(false)
+    } else {
+        state.poll_iot_api(device).await
+    };
+    let (transport, result) = match iot_result {
+        Ok(true) => (crate::metrics::IOT, Ok(true)),
+        Ok(false) => (
+            crate::metrics::PLATFORM,
+            state.poll_platform_api(device).await,
+        ),
+        Err(err) => (crate::metrics::IOT, Err(err)),
+    };
+    // Record failed polls too, as they are often the slow ones,
+    // but not those that didn't make a request at all
+    if !matches!(result, Ok(false)) {
+        crate::metrics::poll_duration(&device.id, transport, result.is_ok(), started.elapsed());
+    }
+
+    result.map(|_| ())
+}
+
+/// Query the device list for one of the additional accounts from
+/// the config file, and connect to its IoT service
+async fn setup_account(
+    args: &crate::Args,
+    state: &StateHandle,
+    account: &AccountConfig,
+) -> anyhow::Result<()> {
+    let name = &account.name;
+
+    if let Some(key) = &account.api_key {
+        log::info!("Querying platform API for device list of account {name}");
+        let client = GoveeApiClient::new(key)
+            .with_base_url(args.api_args.api_base_url()?)
+            .with_account(name);
+        for info in client.get_devices().await? {
+            let mut device = state.device_mut(&info.sku, &info.device).await;
+            device.account.replace(name.to_string());
+            device.set_http_device_info(info);
         }
+
+        state.set_account_platform_client(name, client).await;
     }
 
-    state.poll_platform_api(&device).await?;
+    if let (Some(email), Some(password)) = (&account.email, &account.password) {
+        log::info!("Querying undocumented API for device + room list of account {name}");
+        let client = GoveeUndocumentedApi::new(email, password).with_account(name);
+        let acct = client.login_account_cached().await?;
+        let info = client.get_device_list(&acct.token).await?;
+        let mut group_by_id = HashMap::new();
+        for group in info.groups {
+            group_by_id.insert(group.group_id, group.group_name);
+        }
+        for entry in info.devices {
+            let mut device = state.device_mut(&entry.sku, &entry.device).await;
+            let room_name = group_by_id.get(&entry.group_id).map(|name| name.as_str());
+            device.account.replace(name.to_string());
+            device.set_undoc_device_info(entry, room_name);
+        }
+
+        start_iot_client_for_account(args, state.clone(), &client, Some(name), Some(acct)).await?;
+        state.set_account_undoc_client(name, client).await;
+    }
 
     Ok(())
 }
@@ -81,11 +149,21 @@ async fn periodic_state_poll(state: StateHandle) -> anyhow::Result<()> {
             }
         }
 
+        if let Some(hass) = state.get_hass_client().await {
+            if let Err(err) = hass.advise_hass_of_platform_quota(&state).await {
+                log::error!("while reporting platform API quota: {err:#}");
+            }
+        }
+
         sleep(Duration::from_secs(60)).await;
     }
 }
 
 impl ServeCommand {
+    fn http_port(&self) -> u16 {
+        self.http_port.or(config().http.port).unwrap_or(8056)
+    }
+
     pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
         log::info!("Starting service. version {}", govee_version());
         let state = Arc::new(crate::service::state::State::new());
@@ -121,6 +199,12 @@ impl ServeCommand {
             state.set_undoc_client(client).await;
         }
 
+        for account in &config().account {
+            if let Err(err) = setup_account(args, &state, account).await {
+                log::error!("Failed to set up account {}: {err:#}", account.name);
+            }
+        }
+
         // Now start discovery
 
         let options = args.lan_disco_args.to_disco_options()?;
@@ -167,6 +251,13 @@ impl ServeCommand {
         log::info!("Devices returned from Govee's APIs");
         for device in state.devices().await {
             log::info!("{device}");
+            if let Some(account) = &device.account {
+                log::info!("  Account: {account}");
+            }
+            let policy = device.transport_policy();
+            if policy != TransportPolicy::Auto {
+                log::info!("  Transport policy: {policy:?}");
+            }
             if let Some(lan) = &device.lan_device {
                 log::info!("  LAN API: ip={:?}", lan.ip);
             }
@@ -234,11 +325,22 @@ impl ServeCommand {
             });
         }
 
+        // Run the schedules from the config file
+        {
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(err) = run_scheduler(state).await {
+                    log::error!("run_scheduler: {err:#}");
+                }
+            });
+        }
+
         // start advertising on local mqtt
         spawn_hass_integration(state.clone(), &args.hass_args).await?;
 
-        run_http_server(state.clone(), self.http_port)
+        let http_port = self.http_port();
+        run_http_server(state.clone(), http_port)
             .await
-            .with_context(|| format!("Starting HTTP service on port {}", self.http_port))
+            .with_context(|| format!("Starting HTTP service on port {http_port}"))
     }
 }
@@ -0,0 +1,713 @@
+//! Support for loading settings from a declarative TOML configuration file.
+//!
+//! Every option that can be set via the command line or the environment
+//! can also be set in the config file. The effective value of an option
+//! is resolved in this order, with the first match winning:
+//!
+//! 1. The command line
+//! 2. The environment
+//! 3. The config file
+//! 4. The built-in default
+use crate::service::hass::topic_safe_string;
+use crate::service::schedule::{Location, Trigger};
+use crate::service::state::DesiredState;
+use crate::service::transport::TransportPolicy;
+use crate::temperature::TemperatureScale;
+use anyhow::Context;
+use once_cell::sync::OnceCell;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+static CONFIG: OnceCell<ConfigFile> = OnceCell::new();
+
+/// Returns the loaded config file, or an empty config if
+/// none was specified.
+pub fn config() -> &'static ConfigFile {
+    CONFIG.get_or_init(ConfigFile::default)
+}
+
+/// Loads the config file from `path` and makes it available via `config()`.
+/// Must be called before any option is resolved.
+pub fn load_config(path: &Path) -> anyhow::Result<()> {
+    let config = ConfigFile::load(path)?;
+    CONFIG
+        .set(config)
+        .map_err(|_| anyhow::anyhow!("config file was already loaded"))
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub general: GeneralConfig,
+    pub govee: GoveeConfig,
+    pub lan: LanConfig,
+    pub mqtt: MqttConfig,
+    pub hass: HassConfig,
+    pub http: HttpConfig,
+    /// Per-device settings, keyed by device id or SKU
+    pub device: BTreeMap<String, DeviceConfig>,
+    /// Additional Govee accounts, beyond the one configured
+    /// via the `[govee]` section
+    pub account: Vec<AccountConfig>,
+    /// Virtual light groups, keyed by the group name
+    pub group: BTreeMap<String, GroupConfig>,
+    /// Schedules that control devices at particular times
+    pub schedule: Vec<ScheduleConfig>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeneralConfig {
+    pub cache_dir: Option<PathBuf>,
+    pub quirks_file: Option<PathBuf>,
+    pub log_sensitive_data: Option<bool>,
+    /// The transport policy for devices that don't specify their own
+    pub transport: Option<TransportPolicy>,
+    /// The location used to compute sunrise and sunset for schedules
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// The time zone in which schedules are evaluated
+    #[serde(deserialize_with = "opt_from_str")]
+    pub timezone: Option<chrono_tz::Tz>,
+}
+
+impl GeneralConfig {
+    pub fn location(&self) -> Option<Location> {
+        Some(Location {
+            latitude: self.latitude?,
+            longitude: self.longitude?,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct GoveeConfig {
+    pub api_key: Option<String>,
+    pub api_base_url: Option<String>,
+    /// The number of Platform API requests that may be made per day
+    pub api_daily_limit: Option<u32>,
+    pub email: Option<String>,
+    pub password: Option<String>,
+    pub iot_key: Option<PathBuf>,
+    pub iot_cert: Option<PathBuf>,
+    pub amazon_root_ca: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountConfig {
+    /// Identifies the account in the logs, and keeps its cached
+    /// data and IoT credentials separate from the other accounts
+    pub name: String,
+    pub api_key: Option<String>,
+    pub email: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupConfig {
+    /// Device ids or SKUs of the members of the group
+    pub devices: Vec<String>,
+    /// Room names; every device in these rooms is a member of the group.
+    /// The room of a device can be overridden by its `room` setting,
+    /// which takes precedence over the room from the Govee App.
+    pub rooms: Vec<String>,
+    pub icon: Option<String>,
+}
+
+impl GroupConfig {
+    /// Returns true if the device with the specified SKU, id and
+    /// room is a member of this group. `room` is the effective room
+    /// of the device, including any override from the config file.
+    pub fn matches(&self, sku: &str, id: &str, room: Option<&str>) -> bool {
+        self.devices
+            .iter()
+            .any(|d| d.eq_ignore_ascii_case(id) || d.eq_ignore_ascii_case(sku))
+            || room
+                .map(|room| self.rooms.iter().any(|r| r.eq_ignore_ascii_case(room)))
+                .unwrap_or(false)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    /// Identifies the schedule in the logs
+    pub name: Option<String>,
+    /// A cron expression, a time of day such as `07:30`, or a time
+    /// relative to sunrise or sunset, such as `sunset-30m`
+    #[serde(deserialize_with = "from_str")]
+    pub at: Trigger,
+    /// Restricts the schedule to these days of the week
+    #[serde(default)]
+    pub days: Vec<chrono::Weekday>,
+    /// Device names or ids
+    #[serde(default)]
+    pub devices: Vec<String>,
+    /// Names of light groups
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Turns the devices on or off
+    pub power: Option<bool>,
+    /// The brightness in percent
+    pub brightness: Option<u8>,
+    #[serde(default, deserialize_with = "opt_from_str")]
+    pub color: Option<csscolorparser::Color>,
+    /// The color temperature in kelvin
+    pub color_temp: Option<u32>,
+    pub scene: Option<String>,
+    /// The name of a One-Click shortcut to activate
+    pub oneclick: Option<String>,
+}
+
+impl ScheduleConfig {
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => name.to_string(),
+            None => self.at.to_string(),
+        }
+    }
+
+    /// Returns the state that this schedule applies to its devices
+    pub fn desired_state(&self) -> DesiredState {
+        DesiredState {
+            on: self.power,
+            brightness: self.brightness,
+            color: self.color.as_ref().map(|color| color.to_hex_string()),
+            kelvin: self.color_temp,
+            scene: self.scene.clone(),
+        }
+    }
+
+    fn has_device_action(&self) -> bool {
+        self.power.is_some()
+            || self.brightness.is_some()
+            || self.color.is_some()
+            || self.color_temp.is_some()
+            || self.scene.is_some()
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LanConfig {
+    pub no_multicast: Option<bool>,
+    pub broadcast_all: Option<bool>,
+    pub global_broadcast: Option<bool>,
+    pub scan: Option<Vec<IpAddr>>,
+    pub disco_timeout: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub bind_address: Option<String>,
+    pub base_topic: Option<String>,
+    pub instance_id: Option<String>,
+    pub tls: Option<bool>,
+    pub ca_file: Option<PathBuf>,
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HassConfig {
+    pub discovery_prefix: Option<String>,
+    #[serde(deserialize_with = "opt_from_str")]
+    pub temperature_scale: Option<TemperatureScale>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub port: Option<u16>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// A known LAN address for the device; it will be added
+    /// to the list of addresses that are scanned during discovery
+    pub ip: Option<IpAddr>,
+    /// Overrides the name defined in the Govee App
+    pub name: Option<String>,
+    /// Overrides the room defined in the Govee App; used as the
+    /// suggested area in Home Assistant
+    pub room: Option<String>,
+    /// Overrides the icon of the primary entity of the device
+    pub icon: Option<String>,
+    /// Don't expose the device to Home Assistant or the HTTP API
+    pub hidden: Option<bool>,
+    /// Which transports may be used to control and poll the device
+    pub transport: Option<TransportPolicy>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading config file {path:?}"))?;
+        Self::parse(&text).with_context(|| format!("loading config file {path:?}"))
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let config: Self = parse_toml(text)?;
+        config.validate_accounts()?;
+        config.validate_groups()?;
+        config.validate_schedules()?;
+        Ok(config)
+    }
+
+    fn validate_accounts(&self) -> anyhow::Result<()> {
+        for (idx, account) in self.account.iter().enumerate() {
+            let name = &account.name;
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                anyhow::bail!(
+                    "account[{idx}].name: '{name}' must be non-empty and \
+                    consist of only letters, digits, '-' and '_'"
+                );
+            }
+            if self.account[..idx].iter().any(|a| a.name == *name) {
+                anyhow::bail!("account[{idx}].name: '{name}' is used by more than one account");
+            }
+            if account.api_key.is_none() && account.email.is_none() {
+                anyhow::bail!("account[{idx}]: '{name}' needs an api_key and/or an email");
+            }
+            if account.email.is_some() != account.password.is_some() {
+                anyhow::bail!(
+                    "account[{idx}]: '{name}' needs both an email and password, or neither"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Each group is addressed via MQTT by the topic safe form of its
+    /// name, so no two groups may have the same topic safe name
+    fn validate_groups(&self) -> anyhow::Result<()> {
+        let mut topics: BTreeMap<String, &str> = BTreeMap::new();
+        for name in self.group.keys() {
+            let topic = topic_safe_string(name);
+            if let Some(other) = topics.insert(topic.clone(), name) {
+                anyhow::bail!(
+                    "group.'{name}': the name is too similar to that of group '{other}'; \
+                    both are addressed by the MQTT topic name '{topic}'"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_schedules(&self) -> anyhow::Result<()> {
+        if let Some(latitude) = self.general.latitude {
+            anyhow::ensure!(
+                (-90.0..=90.0).contains(&latitude),
+                "general.latitude: {latitude} must be between -90 and 90"
+            );
+        }
+        if let Some(longitude) = self.general.longitude {
+            anyhow::ensure!(
+                (-180.0..=180.0).contains(&longitude),
+                "general.longitude: {longitude} must be between -180 and 180"
+            );
+        }
+
+        for (idx, schedule) in self.schedule.iter().enumerate() {
+            let label = schedule.label();
+            if schedule.at.needs_location() && self.general.location().is_none() {
+                anyhow::bail!(
+                    "schedule[{idx}]: '{label}' uses sunrise or sunset, which requires \
+                    general.latitude and general.longitude to be set"
+                );
+            }
+            if !schedule.has_device_action() && schedule.oneclick.is_none() {
+                anyhow::bail!(
+                    "schedule[{idx}]: '{label}' needs at least one of power, brightness, \
+                    color, color_temp, scene or oneclick"
+                );
+            }
+            if schedule.has_device_action()
+                && schedule.devices.is_empty()
+                && schedule.groups.is_empty()
+            {
+                anyhow::bail!("schedule[{idx}]: '{label}' needs devices and/or groups");
+            }
+            schedule
+                .desired_state()
+                .validate()
+                .with_context(|| format!("schedule[{idx}]: '{label}'"))?;
+            for group in &schedule.groups {
+                anyhow::ensure!(
+                    self.group
+                        .keys()
+                        .any(|name| name.eq_ignore_ascii_case(group)),
+                    "schedule[{idx}].groups: there is no group named '{group}'"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the device sections that apply to the specified device,
+    /// with the section matching the device id ahead of the one matching
+    /// its SKU, so that settings for a specific device win over those
+    /// made for all devices of that model.
+    pub fn device_sections<'a>(
+        &'a self,
+        sku: &'a str,
+        id: &'a str,
+    ) -> impl Iterator<Item = &'a DeviceConfig> + 'a {
+        let by_id = self
+            .device
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(id));
+        let by_sku = self
+            .device
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(sku));
+        by_id.chain(by_sku).map(|(_, section)| section)
+    }
+
+    /// Returns the LAN addresses of any devices that have an
+    /// `ip` configured in their device section
+    pub fn device_addresses(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.device.values().filter_map(|d| d.ip)
+    }
+}
+
+/// Parses TOML text, reporting the path to the offending key
+/// in the case of an error
+pub fn parse_toml<T: DeserializeOwned>(text: &str) -> anyhow::Result<T> {
+    let de = toml::Deserializer::new(text);
+    serde_path_to_error::deserialize(de).map_err(|err| {
+        let path = err.path().to_string();
+        let inner = err.into_inner();
+        let message = inner.message();
+        if path == "." {
+            anyhow::anyhow!("{message}")
+        } else {
+            anyhow::anyhow!("{path}: {message}")
+        }
+    })
+}
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    <T as FromStr>::Err: std::fmt::Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+fn opt_from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    <T as FromStr>::Err: std::fmt::Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_full_config() {
+        let config = ConfigFile::parse(
+            r#"
+[general]
+cache_dir = "/var/cache/govee"
+
+[govee]
+email = "user@example.com"
+password = "secret"
+api_key = "abc"
+
+[lan]
+no_multicast = true
+scan = ["10.0.0.1", "10.0.0.255"]
+disco_timeout = 5
+
+[mqtt]
+host = "mqtt.local"
+port = 8883
+
+[hass]
+discovery_prefix = "ha"
+temperature_scale = "F"
+
+[http]
+port = 8080
+
+[device."AA:BB:CC:DD:EE:FF:42:2A"]
+ip = "10.0.0.42"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.govee.email.as_deref(), Some("user@example.com"));
+        assert_eq!(config.lan.disco_timeout, Some(5));
+        assert_eq!(config.lan.scan.as_ref().map(|s| s.len()), Some(2));
+        assert_eq!(config.mqtt.port, Some(8883));
+        assert_eq!(
+            config.hass.temperature_scale,
+            Some(TemperatureScale::Farenheit)
+        );
+        assert_eq!(config.http.port, Some(8080));
+        assert_eq!(
+            config.device_addresses().collect::<Vec<_>>(),
+            vec!["10.0.0.42".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn device_sections() {
+        let config = ConfigFile::parse(
+            r#"
+[device.H6072]
+icon = "mdi:floor-lamp"
+hidden = true
+
+[device."AA:BB:CC:DD:EE:FF:42:2A"]
+name = "Reading Lamp"
+hidden = false
+"#,
+        )
+        .unwrap();
+
+        let lookup = |sku, id| {
+            let sections: Vec<_> = config.device_sections(sku, id).collect();
+            (
+                sections.iter().find_map(|d| d.name.as_deref()),
+                sections.iter().find_map(|d| d.icon.as_deref()),
+                sections.iter().find_map(|d| d.hidden),
+            )
+        };
+
+        assert_eq!(
+            lookup("H6072", "aa:bb:cc:dd:ee:ff:42:2a"),
+            (Some("Reading Lamp"), Some("mdi:floor-lamp"), Some(false))
+        );
+        assert_eq!(
+            lookup("H6072", "11:22:33:44:55:66:77:88"),
+            (None, Some("mdi:floor-lamp"), Some(true))
+        );
+        assert_eq!(
+            lookup("H6000", "11:22:33:44:55:66:77:88"),
+            (None, None, None)
+        );
+    }
+
+    #[test]
+    fn accounts() {
+        let config = ConfigFile::parse(
+            r#"
+[[account]]
+name = "parents"
+email = "parents@example.com"
+password = "secret"
+
+[[account]]
+name = "cabin"
+api_key = "abc"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config
+                .account
+                .iter()
+                .map(|a| a.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["parents", "cabin"]
+        );
+
+        let err = ConfigFile::parse(
+            "[[account]]\nname = \"a\"\napi_key = \"x\"\n\
+            [[account]]\nname = \"a\"\napi_key = \"y\"\n",
+        )
+        .unwrap_err();
+        k9::snapshot!(
+            err.to_string(),
+            "account[1].name: 'a' is used by more than one account"
+        );
+
+        let err = ConfigFile::parse("[[account]]\nname = \"a\"\nemail = \"x\"\n").unwrap_err();
+        k9::snapshot!(
+            err.to_string(),
+            "account[0]: 'a' needs both an email and password, or neither"
+        );
+    }
+
+    #[test]
+    fn group_topic_collision() {
+        let err = ConfigFile::parse(
+            "[group.\"Living Room\"]\nrooms = [\"a\"]\n\
+            [group.\"living room\"]\nrooms = [\"b\"]\n",
+        )
+        .unwrap_err();
+        k9::snapshot!(
+            err.to_string(),
+            "group.'living room': the name is too similar to that of group 'Living Room'; both are addressed by the MQTT topic name 'living_room'"
+        );
+    }
+
+    #[test]
+    fn empty_config() {
+        let config = ConfigFile::parse("").unwrap();
+        assert!(config.mqtt.host.is_none());
+        assert!(config.device.is_empty());
+    }
+
+    #[test]
+    fn unknown_key_is_reported() {
+        let err = ConfigFile::parse("[mqtt]\nhots = \"mqtt.local\"\n").unwrap_err();
+        k9::snapshot!(
+            err.to_string(),
+            "mqtt.hots: unknown field `hots`, expected one of `host`, `port`, `username`, `password`, `bind_address`, `base_topic`, `instance_id`, `tls`, `ca_file`, `cert_file`, `key_file`"
+        );
+    }
+
+    #[test]
+    fn invalid_value_is_reported() {
+        let err = ConfigFile::parse("[hass]\ntemperature_scale = \"K\"\n").unwrap_err();
+        k9::snapshot!(
+            err.to_string(),
+            "hass.temperature_scale: Unknown temperature scale K"
+        );
+
+        let err = ConfigFile::parse("[lan]\ndisco_timeout = \"soon\"\n").unwrap_err();
+        k9::snapshot!(
+            err.to_string(),
+            r#"lan.disco_timeout: invalid type: string "soon", expected u64"#
+        );
+    }
+
+    #[test]
+    fn transport_policy() {
+        let config = ConfigFile::parse(
+            r#"
+[general]
+transport = "prefer-iot"
+
+[device.H6072]
+transport = "never-cloud"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.general.transport, Some(TransportPolicy::PreferIot));
+        assert_eq!(
+            config
+                .device_sections("H6072", "AA:BB")
+                .find_map(|d| d.transport),
+            Some(TransportPolicy::NeverCloud)
+        );
+
+        let err = ConfigFile::parse("[general]\ntransport = \"cloud\"\n").unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("general.transport: unknown variant `cloud`"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn schedules() {
+        let config = ConfigFile::parse(
+            r#"
+[general]
+latitude = 51.5
+longitude = -0.12
+timezone = "Europe/London"
+
+[group."Downstairs"]
+rooms = ["Living Room"]
+
+[[schedule]]
+name = "Porch at dusk"
+at = "sunset-15m"
+devices = ["Porch Light"]
+power = true
+brightness = 60
+color = "orange"
+
+[[schedule]]
+at = "30 22 * * sun-thu"
+groups = ["downstairs"]
+power = false
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.general.timezone, Some(chrono_tz::Europe::London));
+        assert_eq!(
+            config
+                .schedule
+                .iter()
+                .map(|s| s.label())
+                .collect::<Vec<_>>(),
+            vec!["Porch at dusk", "30 22 * * sun-thu"]
+        );
+        assert_eq!(
+            config.schedule[0].color.as_ref().map(|c| c.to_rgba8()),
+            Some([255, 165, 0, 255])
+        );
+
+        let err =
+            ConfigFile::parse("[[schedule]]\nat = \"sunrise\"\ndevices = [\"a\"]\npower = true\n")
+                .unwrap_err();
+        k9::snapshot!(
+            err.to_string(),
+            "schedule[0]: 'sunrise' uses sunrise or sunset, which requires general.latitude and general.longitude to be set"
+        );
+
+        let err = ConfigFile::parse("[[schedule]]\nat = \"25:00\"\npower = true\n").unwrap_err();
+        k9::snapshot!(
+            err.to_string(),
+            "schedule[0].at: expected 5 fields (minute, hour, day of month, month, day of week) in '25:00'"
+        );
+
+        let err =
+            ConfigFile::parse("[[schedule]]\nat = \"07:00\"\ngroups = [\"nope\"]\npower = true\n")
+                .unwrap_err();
+        k9::snapshot!(
+            err.to_string(),
+            "schedule[0].groups: there is no group named 'nope'"
+        );
+
+        let err = ConfigFile::parse(
+            "[[schedule]]\nat = \"07:00\"\ndevices = [\"a\"]\npower = false\nbrightness = 10\n",
+        )
+        .unwrap_err();
+        k9::snapshot!(
+            format!("{err:#}"),
+            "schedule[0]: '0 7 * * *': cannot change other properties while turning the device off"
+        );
+
+        let err = ConfigFile::parse(
+            "[[schedule]]\nat = \"07:00\"\ndevices = [\"a\"]\ncolor = \"red\"\ncolor_temp = 3000\n",
+        )
+        .unwrap_err();
+        k9::snapshot!(
+            format!("{err:#}"),
+            "schedule[0]: '0 7 * * *': color cannot be combined with kelvin"
+        );
+    }
+}
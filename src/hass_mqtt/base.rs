@@ -1,7 +1,8 @@
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::topic_safe_id;
+use crate::service::group::LightGroup;
+use crate::service::hass::{instance_unique_id, topic_safe_id};
 use crate::version_info::govee_version;
-use serde::Serialize;
+use serde::{Serialize, Serializer};
 
 const MODEL: &str = "gv2mqtt";
 const URL: &str = "https://github.com/wez/govee2mqtt";
@@ -14,6 +15,8 @@ pub struct EntityConfig {
     pub device_class: Option<&'static str>,
     pub origin: Origin,
     pub device: Device,
+    /// This is qualified with the instance id when serialized
+    #[serde(serialize_with = "serialize_unique_id")]
     pub unique_id: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub entity_category: Option<String>,
@@ -21,6 +24,10 @@ pub struct EntityConfig {
     pub icon: Option<String>,
 }
 
+fn serialize_unique_id<S: Serializer>(id: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&instance_unique_id(id))
+}
+
 #[derive(Serialize, Clone, Debug)]
 pub struct Origin {
     pub name: &'static str,
@@ -63,9 +70,9 @@ impl Device {
             model: device.sku.to_string(),
             sw_version: None,
             suggested_area: device.room_name().map(|s| s.to_string()),
-            via_device: Some("gv2mqtt".to_string()),
+            via_device: Some(instance_unique_id("gv2mqtt")),
             identifiers: vec![
-                format!("gv2mqtt-{}", topic_safe_id(device)),
+                instance_unique_id(&format!("gv2mqtt-{}", topic_safe_id(device))),
                 /*
                 device.computed_name(),
                 device.id.to_string(),
@@ -75,6 +82,22 @@ impl Device {
         }
     }
 
+    pub fn for_group(group: &LightGroup, room: Option<String>) -> Self {
+        Self {
+            name: group.name.to_string(),
+            manufacturer: "Govee".to_string(),
+            model: "Light Group".to_string(),
+            sw_version: None,
+            suggested_area: room,
+            via_device: Some(instance_unique_id("gv2mqtt")),
+            identifiers: vec![instance_unique_id(&format!(
+                "gv2mqtt-group-{}",
+                group.topic_safe_name()
+            ))],
+            connections: vec![],
+        }
+    }
+
     pub fn this_service() -> Self {
         Self {
             name: "Govee to MQTT".to_string(),
@@ -83,7 +106,7 @@ impl Device {
             sw_version: Some(govee_version().to_string()),
             suggested_area: None,
             via_device: None,
-            identifiers: vec!["gv2mqtt".to_string()],
+            identifiers: vec![instance_unique_id("gv2mqtt")],
             connections: vec![],
         }
     }
@@ -0,0 +1,140 @@
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::platform_api::DeviceCapability;
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{
+    availability_topic, base_topic, topic_safe_id, topic_safe_string, HassClient,
+};
+use crate::service::state::StateHandle;
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::json;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct BinarySensorConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    pub state_topic: String,
+    pub payload_on: &'static str,
+    pub payload_off: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<String>,
+}
+
+impl BinarySensorConfig {
+    pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("binary_sensor", state, client, &self.base, self).await
+    }
+
+    pub async fn notify_state(&self, client: &HassClient, on: bool) -> anyhow::Result<()> {
+        let value = if on {
+            self.payload_on
+        } else {
+            self.payload_off
+        };
+        client.publish(&self.state_topic, value).await
+    }
+}
+
+/// Returns the name and device class to use for the
+/// event capability `cap`
+fn event_name_and_class(cap: &DeviceCapability) -> (String, Option<&'static str>) {
+    match cap.instance.as_str() {
+        "lackWaterEvent" => ("Water Shortage".to_string(), Some("problem")),
+        "bodyAppearedEvent" => ("Presence".to_string(), Some("occupancy")),
+        instance => {
+            let name = cap
+                .event_options()
+                .into_iter()
+                .find_map(|option| option.message)
+                .unwrap_or_else(|| instance.to_string());
+            let lower = instance.to_ascii_lowercase();
+            let device_class = if ["lack", "full", "fault", "error"]
+                .iter()
+                .any(|word| lower.contains(word))
+            {
+                Some("problem")
+            } else {
+                None
+            };
+            (name, device_class)
+        }
+    }
+}
+
+/// Surfaces an event capability, such as lackWaterEvent, as a
+/// binary sensor that is on while the event is active
+#[derive(Clone)]
+pub struct CapabilityEventSensor {
+    sensor: BinarySensorConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl CapabilityEventSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, cap: &DeviceCapability) -> Self {
+        let unique_id = format!(
+            "binary_sensor-{id}-{inst}",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&cap.instance)
+        );
+        let (name, device_class) = event_name_and_class(cap);
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(name),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class,
+                    icon: None,
+                },
+                state_topic: format!("{}/binary_sensor/{unique_id}/state", base_topic()),
+                payload_on: "ON",
+                payload_off: "OFF",
+                json_attributes_topic: Some(format!(
+                    "{}/binary_sensor/{unique_id}/attributes",
+                    base_topic()
+                )),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: cap.instance.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for CapabilityEventSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        // Until the event is reported, assume that it isn't active
+        let event = device.event_states.get(&self.instance_name);
+        let active = event.and_then(|e| e.active.as_ref());
+
+        self.sensor.notify_state(client, active.is_some()).await?;
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            let attributes = json!({
+                "event": active.map(|option| &option.name),
+                "message": active.and_then(|option| option.message.as_ref()),
+                "updated": event.map(|e| e.updated),
+            });
+            client.publish_obj(topic, attributes).await?;
+        }
+        Ok(())
+    }
+}
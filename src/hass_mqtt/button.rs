@@ -3,7 +3,8 @@ use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::platform_api::DeviceCapability;
 use crate::service::device::Device as ServiceDevice;
 use crate::service::hass::{
-    availability_topic, camel_case_to_space_separated, topic_safe_id, topic_safe_string, HassClient,
+    availability_topic, base_topic, camel_case_to_space_separated, topic_safe_id,
+    topic_safe_string, HassClient,
 };
 use crate::service::state::StateHandle;
 use async_trait::async_trait;
@@ -26,7 +27,8 @@ impl ButtonConfig {
         instance: &DeviceCapability,
     ) -> anyhow::Result<Self> {
         let command_topic = format!(
-            "gv2mqtt/switch/{id}/command/{inst}",
+            "{}/switch/{id}/command/{inst}",
+            base_topic(),
             id = topic_safe_id(device),
             inst = instance.instance
         );
@@ -85,7 +87,8 @@ impl ButtonConfig {
             mode = topic_safe_string(mode_name),
         );
         let command_topic = format!(
-            "gv2mqtt/number/{id}/command/{mode}/{mode_num}",
+            "{}/number/{id}/command/{mode}/{mode_num}",
+            base_topic(),
             id = topic_safe_id(device),
             mode = topic_safe_string(mode_name),
         );
@@ -111,7 +114,8 @@ impl ButtonConfig {
             id = topic_safe_id(device)
         );
         let command_topic = format!(
-            "gv2mqtt/{id}/request-platform-data",
+            "{}/{id}/request-platform-data",
+            base_topic(),
             id = topic_safe_id(device)
         );
         Self {
@@ -3,7 +3,9 @@ use crate::hass_mqtt::instance::EntityInstance;
 use crate::hass_mqtt::number::NumberConfig;
 use crate::platform_api::{DeviceCapability, DeviceParameters};
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
+use crate::service::hass::{
+    availability_topic, base_topic, topic_safe_id, topic_safe_string, HassClient,
+};
 use crate::service::state::StateHandle;
 use crate::temperature::{
     TemperatureScale, TemperatureUnits, TemperatureValue, DEVICE_CLASS_TEMPERATURE,
@@ -92,12 +94,14 @@ impl TargetTemperatureEntity {
 
         let name = "Target Temperature".to_string();
         let command_topic = format!(
-            "gv2mqtt/{id}/set-temperature/{inst}/{units}",
+            "{}/{id}/set-temperature/{inst}/{units}",
+            base_topic(),
             id = topic_safe_id(device),
             inst = topic_safe_string(&instance.instance)
         );
         let state_topic = format!(
-            "gv2mqtt/{id}/advise-set-temperature",
+            "{}/{id}/advise-set-temperature",
+            base_topic(),
             id = topic_safe_id(device),
         );
 
@@ -130,7 +134,7 @@ impl TargetTemperatureEntity {
 #[async_trait]
 impl EntityInstance for TargetTemperatureEntity {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.number.publish(&state, &client).await
+        self.number.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -176,7 +180,7 @@ impl EntityInstance for TargetTemperatureEntity {
 
             log::debug!("setting value to {value}");
 
-            return self.number.notify_state(&client, &value).await;
+            return self.number.notify_state(client, &value).await;
         }
 
         Ok(())
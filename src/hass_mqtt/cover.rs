@@ -1,6 +1,7 @@
 use crate::hass_mqtt::base::EntityConfig;
 use serde::Serialize;
 
+#[allow(dead_code)]
 #[derive(Serialize, Clone, Debug)]
 pub struct CoverConfig {
     #[serde(flatten)]
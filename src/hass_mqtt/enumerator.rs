@@ -1,17 +1,22 @@
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::binary_sensor::CapabilityEventSensor;
 use crate::hass_mqtt::button::ButtonConfig;
 use crate::hass_mqtt::climate::TargetTemperatureEntity;
+use crate::hass_mqtt::fan::Fan;
 use crate::hass_mqtt::humidifier::Humidifier;
 use crate::hass_mqtt::instance::EntityList;
-use crate::hass_mqtt::light::DeviceLight;
-use crate::hass_mqtt::number::WorkModeNumber;
+use crate::hass_mqtt::light::{DeviceLight, GroupLight};
+use crate::hass_mqtt::number::{MusicSensitivityNumber, WorkModeNumber};
 use crate::hass_mqtt::scene::SceneConfig;
-use crate::hass_mqtt::select::{SceneModeSelect, WorkModeSelect};
-use crate::hass_mqtt::sensor::{CapabilitySensor, DeviceStatusDiagnostic, GlobalFixedDiagnostic};
-use crate::hass_mqtt::switch::CapabilitySwitch;
+use crate::hass_mqtt::select::{MusicModeSelect, SceneModeSelect, WorkModeSelect};
+use crate::hass_mqtt::sensor::{
+    CapabilitySensor, DeviceStatusDiagnostic, GlobalFixedDiagnostic, PlatformQuotaDiagnostic,
+};
+use crate::hass_mqtt::switch::{CapabilitySwitch, MusicAutoColorSwitch};
 use crate::hass_mqtt::work_mode::ParsedWorkMode;
 use crate::platform_api::{DeviceCapability, DeviceCapabilityKind, DeviceType};
 use crate::service::device::Device as ServiceDevice;
+use crate::service::group::light_groups;
 use crate::service::hass::{availability_topic, oneclick_topic, purge_cache_topic};
 use crate::service::state::StateHandle;
 use crate::version_info::govee_version;
@@ -36,50 +41,71 @@ pub async fn enumerate_all_entites(state: &StateHandle) -> anyhow::Result<Entity
     Ok(entities)
 }
 
+/// Builds the lights for the groups in the config file. These are
+/// relatively expensive to build, as the scenes of each member are
+/// consulted, so this is done once at registration.
+pub async fn enumerate_group_lights(state: &StateHandle) -> anyhow::Result<Vec<GroupLight>> {
+    let mut lights = vec![];
+    for group in light_groups() {
+        lights.push(
+            GroupLight::new(group, state)
+                .await
+                .with_context(|| format!("GroupLight::new({})", group.name))?,
+        );
+    }
+    Ok(lights)
+}
+
 async fn enumerate_global_entities(
-    _state: &StateHandle,
+    state: &StateHandle,
     entities: &mut EntityList,
 ) -> anyhow::Result<()> {
     entities.add(GlobalFixedDiagnostic::new("Version", govee_version()));
+    enumerate_platform_quotas(state, entities).await;
     entities.add(ButtonConfig::new("Purge Caches", purge_cache_topic()));
     Ok(())
 }
 
+pub async fn enumerate_platform_quotas(state: &StateHandle, entities: &mut EntityList) {
+    for client in state.platform_clients().await {
+        entities.add(PlatformQuotaDiagnostic::new(&client));
+    }
+}
+
 async fn enumerate_scenes(state: &StateHandle, entities: &mut EntityList) -> anyhow::Result<()> {
-    if let Some(undoc) = state.get_undoc_client().await {
-        match undoc.parse_one_clicks().await {
-            Ok(items) => {
-                for oc in items {
-                    let unique_id = format!(
-                        "gv2mqtt-one-click-{}",
-                        Uuid::new_v5(&Uuid::NAMESPACE_DNS, oc.name.as_bytes()).simple()
-                    );
-                    entities.add(SceneConfig {
-                        base: EntityConfig {
-                            availability_topic: availability_topic(),
-                            name: Some(oc.name.to_string()),
-                            entity_category: None,
-                            origin: Origin::default(),
-                            device: Device::this_service(),
-                            unique_id: unique_id.clone(),
-                            device_class: None,
-                            icon: None,
-                        },
-                        command_topic: oneclick_topic(),
-                        payload_on: oc.name,
-                    });
-                }
-            }
-            Err(err) => {
-                log::warn!("Failed to parse one-clicks: {err:#}");
+    match state.list_one_clicks().await {
+        Ok(items) => {
+            for oc in items {
+                let oc = oc.item;
+                let unique_id = format!(
+                    "gv2mqtt-one-click-{}",
+                    Uuid::new_v5(&Uuid::NAMESPACE_DNS, oc.name.as_bytes()).simple()
+                );
+                entities.add(SceneConfig {
+                    base: EntityConfig {
+                        availability_topic: availability_topic(),
+                        name: Some(oc.name.to_string()),
+                        entity_category: None,
+                        origin: Origin::default(),
+                        device: Device::this_service(),
+                        unique_id: unique_id.clone(),
+                        device_class: None,
+                        icon: None,
+                    },
+                    command_topic: oneclick_topic(),
+                    payload_on: oc.name,
+                });
             }
         }
+        Err(err) => {
+            log::warn!("Failed to parse one-clicks: {err:#}");
+        }
     }
 
     Ok(())
 }
 
-async fn entities_for_work_mode<'a>(
+async fn entities_for_work_mode(
     d: &ServiceDevice,
     state: &StateHandle,
     cap: &DeviceCapability,
@@ -144,12 +170,12 @@ async fn entities_for_work_mode<'a>(
     Ok(())
 }
 
-pub async fn enumerate_entities_for_device<'a>(
-    d: &'a ServiceDevice,
+pub async fn enumerate_entities_for_device(
+    d: &ServiceDevice,
     state: &StateHandle,
     entities: &mut EntityList,
 ) -> anyhow::Result<()> {
-    if !d.is_controllable() {
+    if !d.is_controllable() || d.is_hidden() {
         return Ok(());
     }
 
@@ -157,14 +183,21 @@ pub async fn enumerate_entities_for_device<'a>(
     entities.add(ButtonConfig::request_platform_data_for_device(d));
 
     if d.supports_rgb() || d.get_color_temperature_range().is_some() || d.supports_brightness() {
-        entities.add(DeviceLight::for_device(&d, state, None).await?);
+        entities.add(DeviceLight::for_device(d, state, None).await?);
     }
 
     if matches!(
         d.device_type(),
         DeviceType::Humidifier | DeviceType::Dehumidifier
     ) {
-        entities.add(Humidifier::new(&d, state).await?);
+        entities.add(Humidifier::new(d, state).await?);
+    }
+
+    // Fans and purifiers present their work modes as the
+    // speeds and presets of a fan entity
+    let is_fan = matches!(d.device_type(), DeviceType::Fan | DeviceType::AirPurifier);
+    if is_fan {
+        entities.add(Fan::new(d, state));
     }
 
     if d.device_type() != DeviceType::Light {
@@ -177,27 +210,38 @@ pub async fn enumerate_entities_for_device<'a>(
         for cap in &info.capabilities {
             match &cap.kind {
                 DeviceCapabilityKind::Toggle | DeviceCapabilityKind::OnOff => {
-                    entities.add(CapabilitySwitch::new(&d, state, cap).await?);
+                    entities.add(CapabilitySwitch::new(d, state, cap).await?);
+                }
+                DeviceCapabilityKind::MusicSetting if cap.instance == "musicMode" => {
+                    if let Some(select) = MusicModeSelect::new(d, state) {
+                        entities.add(select);
+                        entities.add(MusicSensitivityNumber::new(d, state));
+                        entities.add(MusicAutoColorSwitch::new(d, state));
+                    }
                 }
                 DeviceCapabilityKind::ColorSetting
                 | DeviceCapabilityKind::SegmentColorSetting
                 | DeviceCapabilityKind::MusicSetting
-                | DeviceCapabilityKind::Event
                 | DeviceCapabilityKind::Mode
                 | DeviceCapabilityKind::DynamicScene => {}
 
                 DeviceCapabilityKind::Range if cap.instance == "brightness" => {}
                 DeviceCapabilityKind::Range if cap.instance == "humidity" => {}
+                DeviceCapabilityKind::WorkMode if is_fan => {}
                 DeviceCapabilityKind::WorkMode => {
                     entities_for_work_mode(d, state, cap, entities).await?;
                 }
 
+                DeviceCapabilityKind::Event => {
+                    entities.add(CapabilityEventSensor::new(d, state, cap));
+                }
+
                 DeviceCapabilityKind::Property => {
-                    entities.add(CapabilitySensor::new(&d, state, cap).await?);
+                    entities.add(CapabilitySensor::new(d, state, cap).await?);
                 }
 
                 DeviceCapabilityKind::TemperatureSetting => {
-                    entities.add(TargetTemperatureEntity::new(&d, state, cap).await?);
+                    entities.add(TargetTemperatureEntity::new(d, state, cap).await?);
                 }
 
                 kind => {
@@ -211,7 +255,7 @@ pub async fn enumerate_entities_for_device<'a>(
 
         if let Some(segments) = info.supports_segmented_rgb() {
             for n in segments {
-                entities.add(DeviceLight::for_device(&d, state, Some(n)).await?);
+                entities.add(DeviceLight::for_device(d, state, Some(n)).await?);
             }
         }
     }
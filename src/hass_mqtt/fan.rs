@@ -0,0 +1,417 @@
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::hass_mqtt::work_mode::ParsedWorkMode;
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{
+    availability_topic, base_topic, topic_safe_id, HassClient, IdParameter,
+};
+use crate::service::state::StateHandle;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
+use serde::Serialize;
+
+/// Work modes that conventionally represent the speeds
+/// of a fan, slowest first
+const STANDARD_SPEED_MODES: &[&str] = &["Low", "Medium", "High"];
+
+/// <https://www.home-assistant.io/integrations/fan.mqtt>
+#[derive(Serialize, Clone, Debug)]
+pub struct FanConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    /// Controls the power state
+    pub command_topic: String,
+    pub state_topic: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percentage_command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percentage_state_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed_range_min: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed_range_max: Option<i64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset_mode_command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset_mode_state_topic: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub preset_modes: Vec<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oscillation_command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oscillation_state_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_oscillation_on: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_oscillation_off: Option<&'static str>,
+
+    pub optimistic: bool,
+}
+
+/// One of the speeds of a fan, expressed as a work mode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FanSpeed {
+    pub mode: String,
+    /// The value of the mode that selects this speed, or None
+    /// if the mode itself selects the speed
+    pub value: Option<i64>,
+}
+
+/// Maps the work modes of a fan or air purifier onto the speeds
+/// and preset modes of a Home Assistant fan.
+/// Some devices have a single mode whose value selects the speed,
+/// such as `FanSpeed` 1-8, while others have a separate mode for
+/// each speed, such as `Low`, `Medium` and `High`. The remaining
+/// modes are presented as presets.
+#[derive(Debug)]
+pub struct FanModes {
+    work_modes: ParsedWorkMode,
+    /// Slowest first
+    pub speeds: Vec<FanSpeed>,
+    pub presets: Vec<String>,
+}
+
+impl FanModes {
+    pub fn with_device(device: &ServiceDevice) -> anyhow::Result<Self> {
+        let work_modes = ParsedWorkMode::with_device(device)?;
+        let speed_modes = device.resolve_quirk().and_then(|q| q.fan_speed_modes);
+        Ok(Self::new(work_modes, speed_modes))
+    }
+
+    pub fn new(work_modes: ParsedWorkMode, speed_modes: Option<&[&str]>) -> Self {
+        let speeds_from_modes = |names: &[&str]| -> Vec<FanSpeed> {
+            names
+                .iter()
+                .filter(|name| work_modes.mode_by_name(name).is_some())
+                .map(|name| FanSpeed {
+                    mode: name.to_string(),
+                    value: None,
+                })
+                .collect()
+        };
+
+        let speeds = match speed_modes {
+            Some(names) => speeds_from_modes(names),
+            None => {
+                // Prefer a mode whose value ranges over the speeds
+                let ranged = work_modes
+                    .modes
+                    .values()
+                    .filter_map(|mode| Some((mode, mode.contiguous_value_range()?)))
+                    .filter(|(_, range)| range.end - range.start > 1)
+                    .max_by_key(|(mode, _)| {
+                        let name = mode.name.to_ascii_lowercase();
+                        name.contains("speed") || name.contains("gear")
+                    });
+                match ranged {
+                    Some((mode, range)) => range
+                        .map(|value| FanSpeed {
+                            mode: mode.name.to_string(),
+                            value: Some(value),
+                        })
+                        .collect(),
+                    None => speeds_from_modes(STANDARD_SPEED_MODES),
+                }
+            }
+        };
+
+        let presets = work_modes
+            .get_mode_names()
+            .into_iter()
+            .filter(|name| !speeds.iter().any(|speed| speed.mode == *name))
+            .collect();
+
+        Self {
+            work_modes,
+            speeds,
+            presets,
+        }
+    }
+
+    fn mode_num(&self, name: &str) -> anyhow::Result<i64> {
+        self.work_modes
+            .mode_by_name(name)
+            .ok_or_else(|| anyhow!("mode {name} not found"))?
+            .value
+            .as_i64()
+            .ok_or_else(|| anyhow!("expected workMode to be a number"))
+    }
+
+    /// Returns the work mode and value that select `speed`,
+    /// counting from 1 for the slowest speed
+    pub fn speed_command(&self, speed: i64) -> anyhow::Result<(i64, i64)> {
+        let fan_speed = usize::try_from(speed - 1)
+            .ok()
+            .and_then(|idx| self.speeds.get(idx))
+            .ok_or_else(|| anyhow!("speed {speed} is out of range"))?;
+        let mode_num = self.mode_num(&fan_speed.mode)?;
+        let value = match fan_speed.value {
+            Some(value) => value,
+            None => self
+                .work_modes
+                .mode_by_name(&fan_speed.mode)
+                .map(|mode| mode.default_value())
+                .unwrap_or(0),
+        };
+        Ok((mode_num, value))
+    }
+
+    /// Returns the work mode and value that select `preset`
+    pub fn preset_command(&self, preset: &str) -> anyhow::Result<(i64, i64)> {
+        anyhow::ensure!(
+            self.presets.iter().any(|p| p == preset),
+            "preset {preset} not found"
+        );
+        let mode = self
+            .work_modes
+            .mode_by_name(preset)
+            .ok_or_else(|| anyhow!("mode {preset} not found"))?;
+        Ok((self.mode_num(preset)?, mode.default_value()))
+    }
+
+    /// Returns the speed, counting from 1, that corresponds to the
+    /// work mode `mode_num` and its `value`
+    pub fn speed_for(&self, mode_num: i64, value: i64) -> Option<i64> {
+        self.speeds
+            .iter()
+            .position(|speed| {
+                self.mode_num(&speed.mode).ok() == Some(mode_num)
+                    && speed.value.map(|v| v == value).unwrap_or(true)
+            })
+            .map(|idx| idx as i64 + 1)
+    }
+
+    pub fn preset_for(&self, mode_num: i64) -> Option<&str> {
+        self.presets
+            .iter()
+            .find(|name| self.mode_num(name).ok() == Some(mode_num))
+            .map(|name| name.as_str())
+    }
+}
+
+/// Returns the current work mode and its value, preferring the
+/// state reported via IoT over that from the Platform API
+fn current_work_mode(device: &ServiceDevice) -> Option<(i64, i64)> {
+    if let Some(mode) = device.humidifier_work_mode {
+        let value = device
+            .humidifier_param_by_mode
+            .get(&mode)
+            .copied()
+            .unwrap_or(0);
+        return Some((mode.into(), value.into()));
+    }
+
+    let cap = device.get_state_capability_by_instance("workMode")?;
+    let mode = cap.state.pointer("/value/workMode")?.as_i64()?;
+    let value = cap
+        .state
+        .pointer("/value/modeValue")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+    Some((mode, value))
+}
+
+#[derive(Clone)]
+pub struct Fan {
+    fan: FanConfig,
+    state: StateHandle,
+    device_id: String,
+}
+
+impl Fan {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let id = topic_safe_id(device);
+        let modes = FanModes::with_device(device).ok();
+        let speeds = modes.as_ref().map(|m| m.speeds.len()).unwrap_or(0);
+        let preset_modes = modes.map(|m| m.presets).unwrap_or_default();
+
+        // Power and oscillation are routed to the general
+        // switch handler, as for the humidifier
+        let switch_topic =
+            |instance: &str| format!("{}/switch/{id}/command/{instance}", base_topic());
+        let oscillates = device
+            .get_capability_by_instance("oscillationToggle")
+            .is_some();
+
+        let unique_id = format!("gv2mqtt-{id}-fan");
+
+        Self {
+            fan: FanConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: None,
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: device.icon_override().map(|icon| icon.to_string()),
+                },
+                command_topic: switch_topic("powerSwitch"),
+                state_topic: format!("{}/fan/{id}/state", base_topic()),
+
+                percentage_command_topic: (speeds > 0)
+                    .then(|| format!("{}/fan/{id}/set-speed", base_topic())),
+                percentage_state_topic: (speeds > 0)
+                    .then(|| format!("{}/fan/{id}/notify-speed", base_topic())),
+                speed_range_min: (speeds > 0).then_some(1),
+                speed_range_max: (speeds > 0).then_some(speeds as i64),
+
+                preset_mode_command_topic: (!preset_modes.is_empty())
+                    .then(|| format!("{}/fan/{id}/set-preset", base_topic())),
+                preset_mode_state_topic: (!preset_modes.is_empty())
+                    .then(|| format!("{}/fan/{id}/notify-preset", base_topic())),
+                preset_modes,
+
+                oscillation_command_topic: oscillates.then(|| switch_topic("oscillationToggle")),
+                oscillation_state_topic: oscillates
+                    .then(|| format!("{}/fan/{id}/notify-oscillation", base_topic())),
+                payload_oscillation_on: oscillates.then_some("ON"),
+                payload_oscillation_off: oscillates.then_some("OFF"),
+
+                optimistic: false,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for Fan {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("fan", state, client, &self.fan.base, &self.fan).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let is_on = device.device_state().map(|s| s.on).unwrap_or(false);
+        client
+            .publish(&self.fan.state_topic, if is_on { "ON" } else { "OFF" })
+            .await?;
+
+        if let (Ok(modes), Some((mode_num, value))) =
+            (FanModes::with_device(&device), current_work_mode(&device))
+        {
+            if let Some(topic) = &self.fan.percentage_state_topic {
+                if let Some(speed) = modes.speed_for(mode_num, value) {
+                    client.publish(topic, speed.to_string()).await?;
+                }
+            }
+            if let Some(topic) = &self.fan.preset_mode_state_topic {
+                // "None" tells HASS that no preset is active
+                let preset = modes.preset_for(mode_num).unwrap_or("None");
+                client.publish(topic, preset).await?;
+            }
+        }
+
+        if let Some(topic) = &self.fan.oscillation_state_topic {
+            if let Some(cap) = device.get_state_capability_by_instance("oscillationToggle") {
+                let on = cap
+                    .state
+                    .pointer("/value")
+                    .and_then(|v| v.as_i64())
+                    .map(|v| v != 0);
+                if let Some(on) = on {
+                    client.publish(topic, if on { "ON" } else { "OFF" }).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub async fn mqtt_fan_set_speed(
+    Payload(speed): Payload<i64>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_fan_set_speed: {id}: {speed}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    if speed <= 0 {
+        return state.device_power_on(&device, false).await;
+    }
+
+    let (mode_num, value) = FanModes::with_device(&device)?.speed_command(speed)?;
+    state
+        .humidifier_set_parameter(&device, mode_num, value)
+        .await
+}
+
+pub async fn mqtt_fan_set_preset(
+    Payload(preset): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_fan_set_preset: {id}: {preset}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let (mode_num, value) = FanModes::with_device(&device)?.preset_command(&preset)?;
+    state
+        .humidifier_set_parameter(&device, mode_num, value)
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::{from_json, GetDevicesResponse};
+
+    fn fan_modes(sku: &str, speed_modes: Option<&[&str]>) -> FanModes {
+        let resp: GetDevicesResponse =
+            from_json(include_str!("../../test-data/list_devices_issue4.json")).unwrap();
+        let info = resp.data.iter().find(|d| d.sku == sku).unwrap();
+        let cap = info.capability_by_instance("workMode").unwrap();
+        FanModes::new(ParsedWorkMode::with_capability(cap).unwrap(), speed_modes)
+    }
+
+    #[test]
+    fn fan_speed_mode_value() {
+        let modes = fan_modes("H7111", None);
+        assert_eq!(modes.speeds.len(), 8);
+        assert_eq!(
+            modes.presets,
+            vec!["Auto", "Custom", "Nature", "Sleep", "Storm"]
+        );
+        assert_eq!(modes.speed_command(3).unwrap(), (1, 3));
+        assert!(modes.speed_command(9).is_err());
+        assert_eq!(modes.speed_for(1, 8), Some(8));
+        assert_eq!(modes.preset_command("Nature").unwrap(), (6, 0));
+        assert_eq!(modes.preset_for(6), Some("Nature"));
+        assert_eq!(modes.preset_for(1), None);
+    }
+
+    #[test]
+    fn purifier_speed_modes() {
+        let modes = fan_modes("H7121", None);
+        assert_eq!(
+            modes
+                .speeds
+                .iter()
+                .map(|s| s.mode.as_str())
+                .collect::<Vec<_>>(),
+            vec!["Low", "Medium", "High"]
+        );
+        assert_eq!(modes.presets, vec!["Sleep"]);
+        assert_eq!(modes.speed_command(3).unwrap(), (3, 0));
+        assert_eq!(modes.speed_for(2, 0), Some(2));
+        assert_eq!(modes.preset_for(16), Some("Sleep"));
+
+        // A quirk can say which modes are speeds
+        let modes = fan_modes("H7121", Some(&["Sleep", "Low", "High"]));
+        assert_eq!(modes.speeds.len(), 3);
+        assert_eq!(modes.presets, vec!["Medium"]);
+    }
+}
@@ -4,7 +4,9 @@ use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::hass_mqtt::work_mode::ParsedWorkMode;
 use crate::platform_api::{DeviceParameters, DeviceType, IntegerRange};
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, HassClient, IdParameter};
+use crate::service::hass::{
+    availability_topic, base_topic, topic_safe_id, HassClient, IdParameter,
+};
 use crate::service::state::StateHandle;
 use anyhow::anyhow;
 use async_trait::async_trait;
@@ -55,7 +57,7 @@ pub struct Humidifier {
 impl Humidifier {
     pub async fn new(device: &ServiceDevice, state: &StateHandle) -> anyhow::Result<Self> {
         let _quirk = device.resolve_quirk();
-        let use_iot = device.iot_api_supported() && state.get_iot_client().await.is_some();
+        let use_iot = device.iot_api_supported() && state.iot_client_for(device).await.is_some();
         let optimistic = !use_iot;
 
         let device_class = if device.device_type() == DeviceType::Humidifier {
@@ -67,26 +69,35 @@ impl Humidifier {
         // command_topic controls the power state; just route it to
         // the general power switch handler
         let command_topic = format!(
-            "gv2mqtt/switch/{id}/command/powerSwitch",
+            "{}/switch/{id}/command/powerSwitch",
+            base_topic(),
             id = topic_safe_id(device)
         );
 
         let target_humidity_command_topic = format!(
-            "gv2mqtt/humidifier/{id}/set-target",
+            "{}/humidifier/{id}/set-target",
+            base_topic(),
             id = topic_safe_id(device)
         );
         let target_humidity_state_topic = format!(
-            "gv2mqtt/humidifier/{id}/notify-target",
+            "{}/humidifier/{id}/notify-target",
+            base_topic(),
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "{}/humidifier/{id}/state",
+            base_topic(),
             id = topic_safe_id(device)
         );
-        let state_topic = format!("gv2mqtt/humidifier/{id}/state", id = topic_safe_id(device));
 
         let mode_command_topic = format!(
-            "gv2mqtt/humidifier/{id}/set-mode",
+            "{}/humidifier/{id}/set-mode",
+            base_topic(),
             id = topic_safe_id(device)
         );
         let mode_state_topic = format!(
-            "gv2mqtt/humidifier/{id}/notify-mode",
+            "{}/humidifier/{id}/notify-mode",
+            base_topic(),
             id = topic_safe_id(device)
         );
 
@@ -103,17 +114,15 @@ impl Humidifier {
 
         if let Some(info) = &device.http_device_info {
             if let Some(cap) = info.capability_by_instance("humidity") {
-                match &cap.parameters {
-                    Some(DeviceParameters::Integer {
-                        range: IntegerRange { min, max, .. },
-                        unit,
-                    }) => {
-                        if unit.as_deref() == Some("unit.percent") {
-                            min_humidity.replace(*min as u8);
-                            max_humidity.replace(*max as u8);
-                        }
+                if let Some(DeviceParameters::Integer {
+                    range: IntegerRange { min, max, .. },
+                    unit,
+                }) = &cap.parameters
+                {
+                    if unit.as_deref() == Some("unit.percent") {
+                        min_humidity.replace(*min as u8);
+                        max_humidity.replace(*max as u8);
                     }
-                    _ => {}
                 }
             }
         }
@@ -135,7 +144,7 @@ impl Humidifier {
                     device: Device::for_device(device),
                     unique_id,
                     entity_category: None,
-                    icon: None,
+                    icon: device.icon_override().map(|icon| icon.to_string()),
                 },
                 command_topic,
                 target_humidity_command_topic,
@@ -277,7 +286,7 @@ pub async fn mqtt_humidifier_set_target(
 
     let device = state.resolve_device_for_control(&id).await?;
 
-    let use_iot = device.pollable_via_iot() && state.get_iot_client().await.is_some();
+    let use_iot = device.pollable_via_iot() && state.iot_client_for(&device).await.is_some();
 
     if !use_iot {
         if let Some(info) = &device.http_device_info {
@@ -1,5 +1,5 @@
 use crate::hass_mqtt::base::EntityConfig;
-use crate::service::hass::HassClient;
+use crate::service::hass::{instance_unique_id, HassClient};
 use crate::service::state::StateHandle;
 use anyhow::Context;
 use async_trait::async_trait;
@@ -24,7 +24,7 @@ pub async fn publish_entity_config<T: Serialize>(
     let disco = state.get_hass_disco_prefix().await;
     let topic = format!(
         "{disco}/{integration}/{unique_id}/config",
-        unique_id = base.unique_id
+        unique_id = instance_unique_id(&base.unique_id)
     );
 
     client.publish_obj(topic, config).await
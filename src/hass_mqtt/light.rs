@@ -2,9 +2,10 @@ use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::platform_api::DeviceType;
 use crate::service::device::Device as ServiceDevice;
+use crate::service::group::{aggregate_state, LightGroup};
 use crate::service::hass::{
-    availability_topic, kelvin_to_mired, light_segment_state_topic, light_state_topic,
-    topic_safe_id, HassClient,
+    availability_topic, base_topic, group_light_command_topic, group_light_state_topic,
+    kelvin_to_mired, light_segment_state_topic, light_state_topic, topic_safe_id, HassClient,
 };
 use crate::service::state::StateHandle;
 use async_trait::async_trait;
@@ -64,7 +65,7 @@ pub struct DeviceLight {
 #[async_trait]
 impl EntityInstance for DeviceLight {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.light.publish(&state, &client).await
+        self.light.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -136,17 +137,27 @@ impl DeviceLight {
         let device_type = device.device_type();
 
         let command_topic = match segment {
-            None => format!("gv2mqtt/light/{id}/command", id = topic_safe_id(device)),
+            None => format!(
+                "{}/light/{id}/command",
+                base_topic(),
+                id = topic_safe_id(device)
+            ),
             Some(seg) => format!(
-                "gv2mqtt/light/{id}/command/{seg}",
+                "{}/light/{id}/command/{seg}",
+                base_topic(),
                 id = topic_safe_id(device)
             ),
         };
 
         let icon = match segment {
             Some(_) => None,
-            None if device_type == DeviceType::Light => quirk.as_ref().map(|q| q.icon.to_string()),
-            None => None,
+            None => match device.icon_override() {
+                Some(icon) => Some(icon.to_string()),
+                None if device_type == DeviceType::Light => {
+                    quirk.as_ref().map(|q| q.icon.to_string())
+                }
+                None => None,
+            },
         };
 
         let state_topic = match segment {
@@ -157,7 +168,7 @@ impl DeviceLight {
         let unique_id = format!(
             "gv2mqtt-{id}{seg}",
             id = topic_safe_id(device),
-            seg = segment.map(|n| format!("-{n}")).unwrap_or(String::new())
+            seg = segment.map(|n| format!("-{n}")).unwrap_or_default()
         );
 
         let effect_list = if segment.is_some() {
@@ -241,3 +252,137 @@ impl DeviceLight {
         })
     }
 }
+
+/// A virtual light that represents a group of devices
+#[derive(Clone)]
+pub struct GroupLight {
+    light: LightConfig,
+    group: LightGroup,
+    state: StateHandle,
+}
+
+#[async_trait]
+impl EntityInstance for GroupLight {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.light.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let states: Vec<_> = self
+            .group
+            .members(&self.state)
+            .await
+            .iter()
+            .filter_map(|d| d.device_state())
+            .collect();
+        let group_state = aggregate_state(&states);
+        log::trace!("GroupLight::notify_state: {group_state:?}");
+
+        let light_state = if !group_state.on {
+            json!({"state":"OFF"})
+        } else if group_state.kelvin == 0 {
+            json!({
+                "state": "ON",
+                "color_mode": "rgb",
+                "color": {
+                    "r": group_state.color.r,
+                    "g": group_state.color.g,
+                    "b": group_state.color.b,
+                },
+                "brightness": group_state.brightness,
+                "effect": group_state.scene,
+            })
+        } else {
+            json!({
+                "state": "ON",
+                "color_mode": "color_temp",
+                "brightness": group_state.brightness,
+                "color_temp": kelvin_to_mired(group_state.kelvin),
+                "effect": group_state.scene,
+            })
+        };
+
+        client
+            .publish_obj(&self.light.state_topic, &light_state)
+            .await
+    }
+}
+
+impl GroupLight {
+    pub fn group_name(&self) -> &'static str {
+        self.group.name
+    }
+
+    pub async fn new(group: LightGroup, state: &StateHandle) -> anyhow::Result<Self> {
+        let members = group.members(state).await;
+
+        let mut supported_color_modes = vec![];
+        if members.iter().any(|d| d.supports_rgb()) {
+            supported_color_modes.push("rgb".to_string());
+        }
+
+        // Offer the full range of color temperatures that
+        // any of the members can produce
+        let range = members
+            .iter()
+            .filter_map(|d| d.get_color_temperature_range())
+            .reduce(|(a_min, a_max), (b_min, b_max)| (a_min.min(b_min), a_max.max(b_max)));
+        let (min_mireds, max_mireds) = match range {
+            Some((min, max)) => {
+                supported_color_modes.push("color_temp".to_string());
+                // Note that min and max are swapped by the translation
+                // from kelvin to mired
+                (Some(kelvin_to_mired(max)), Some(kelvin_to_mired(min)))
+            }
+            None => (None, None),
+        };
+
+        // Only offer the scenes that all of the members have in common
+        let mut effect_list: Option<Vec<String>> = None;
+        for device in &members {
+            let scenes = match state.device_list_scenes(device).await {
+                Ok(scenes) => scenes,
+                Err(err) => {
+                    log::error!("Unable to list scenes for {device}: {err:#}");
+                    vec![]
+                }
+            };
+            effect_list = Some(match effect_list {
+                None => scenes,
+                Some(list) => list.into_iter().filter(|s| scenes.contains(s)).collect(),
+            });
+        }
+        let effect_list = effect_list.unwrap_or_default();
+
+        Ok(Self {
+            light: LightConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: None,
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_group(&group, LightGroup::common_room(&members)),
+                    unique_id: format!("gv2mqtt-group-{}", group.topic_safe_name()),
+                    entity_category: None,
+                    icon: None,
+                },
+                schema: "json".to_string(),
+                command_topic: group_light_command_topic(&group),
+                state_topic: group_light_state_topic(&group),
+                color_mode: !supported_color_modes.is_empty(),
+                supported_color_modes,
+                brightness: true,
+                brightness_scale: 100,
+                effect: !effect_list.is_empty(),
+                effect_list,
+                payload_available: "online".to_string(),
+                max_mireds,
+                min_mireds,
+                optimistic: false,
+                icon: group.config.icon.clone(),
+            },
+            group,
+            state: state.clone(),
+        })
+    }
+}
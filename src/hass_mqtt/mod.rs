@@ -1,8 +1,10 @@
 pub mod base;
+pub mod binary_sensor;
 pub mod button;
 pub mod climate;
 pub mod cover;
 pub mod enumerator;
+pub mod fan;
 pub mod humidifier;
 pub mod instance;
 pub mod light;
@@ -1,7 +1,9 @@
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
+use crate::service::hass::{
+    availability_topic, base_topic, topic_safe_id, topic_safe_string, HassClient, IdParameter,
+};
 use crate::service::state::StateHandle;
 use anyhow::anyhow;
 use async_trait::async_trait;
@@ -62,7 +64,8 @@ impl WorkModeNumber {
         range: Option<Range<i64>>,
     ) -> Self {
         let command_topic = format!(
-            "gv2mqtt/number/{id}/command/{mode}/{mode_num}",
+            "{}/number/{id}/command/{mode}/{mode_num}",
+            base_topic(),
             id = topic_safe_id(device),
             mode = topic_safe_string(mode_name),
             mode_num = work_mode
@@ -71,7 +74,8 @@ impl WorkModeNumber {
                 .unwrap_or_else(|| "work-mode-was-not-int".to_string()),
         );
         let state_topic = format!(
-            "gv2mqtt/number/{id}/state/{mode}",
+            "{}/number/{id}/state/{mode}",
+            base_topic(),
             id = topic_safe_id(device),
             mode = topic_safe_string(mode_name)
         );
@@ -116,7 +120,7 @@ impl WorkModeNumber {
 #[async_trait]
 impl EntityInstance for WorkModeNumber {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.number.publish(&state, &client).await
+        self.number.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -193,3 +197,81 @@ pub async fn mqtt_number_command(
 
     Ok(())
 }
+
+pub struct MusicSensitivityNumber {
+    number: NumberConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl MusicSensitivityNumber {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let command_topic = format!(
+            "{}/{id}/set-music-sensitivity",
+            base_topic(),
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "{}/{id}/notify-music-sensitivity",
+            base_topic(),
+            id = topic_safe_id(device)
+        );
+        let unique_id = format!("gv2mqtt-{id}-music-sensitivity", id = topic_safe_id(device));
+
+        Self {
+            number: NumberConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Music Sensitivity".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: Some("config".to_string()),
+                    icon: Some("mdi:microphone".to_string()),
+                },
+                command_topic,
+                state_topic: Some(state_topic),
+                min: Some(0.),
+                max: Some(100.),
+                step: 1f32,
+                unit_of_measurement: Some("%"),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for MusicSensitivityNumber {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.number.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        self.number
+            .notify_state(client, &device.music_settings.sensitivity.to_string())
+            .await
+    }
+}
+
+pub async fn mqtt_set_music_sensitivity(
+    Payload(value): Payload<f64>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("music sensitivity for {id}: {value}");
+    let device = state.resolve_device_for_control(&id).await?;
+    let sensitivity = value.round().clamp(0., 100.) as u8;
+
+    state
+        .device_set_music_settings(&device, |settings| settings.sensitivity = sensitivity)
+        .await
+}
@@ -2,7 +2,9 @@ use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::hass_mqtt::work_mode::ParsedWorkMode;
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, HassClient, IdParameter};
+use crate::service::hass::{
+    availability_topic, base_topic, topic_safe_id, HassClient, IdParameter,
+};
 use crate::service::state::StateHandle;
 use anyhow::Context;
 use axum::async_trait;
@@ -34,8 +36,16 @@ pub struct WorkModeSelect {
 
 impl WorkModeSelect {
     pub fn new(device: &ServiceDevice, work_modes: &ParsedWorkMode, state: &StateHandle) -> Self {
-        let command_topic = format!("gv2mqtt/{id}/set-work-mode", id = topic_safe_id(device),);
-        let state_topic = format!("gv2mqtt/{id}/notify-work-mode", id = topic_safe_id(device));
+        let command_topic = format!(
+            "{}/{id}/set-work-mode",
+            base_topic(),
+            id = topic_safe_id(device),
+        );
+        let state_topic = format!(
+            "{}/{id}/notify-work-mode",
+            base_topic(),
+            id = topic_safe_id(device)
+        );
         let availability_topic = availability_topic();
         let unique_id = format!("gv2mqtt-{id}-workMode", id = topic_safe_id(device),);
 
@@ -64,7 +74,7 @@ impl WorkModeSelect {
 #[async_trait]
 impl EntityInstance for WorkModeSelect {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.select.publish(&state, &client).await
+        self.select.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -113,8 +123,16 @@ impl SceneModeSelect {
             return Ok(None);
         }
 
-        let command_topic = format!("gv2mqtt/{id}/set-mode-scene", id = topic_safe_id(device));
-        let state_topic = format!("gv2mqtt/{id}/notify-mode-scene", id = topic_safe_id(device));
+        let command_topic = format!(
+            "{}/{id}/set-mode-scene",
+            base_topic(),
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "{}/{id}/notify-mode-scene",
+            base_topic(),
+            id = topic_safe_id(device)
+        );
         let availability_topic = availability_topic();
         let unique_id = format!("gv2mqtt-{id}-mode-scene", id = topic_safe_id(device));
 
@@ -143,7 +161,7 @@ impl SceneModeSelect {
 #[async_trait]
 impl EntityInstance for SceneModeSelect {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.select.publish(&state, &client).await
+        self.select.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -180,3 +198,95 @@ pub async fn mqtt_set_mode_scene(
 
     Ok(())
 }
+
+pub struct MusicModeSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl MusicModeSelect {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Option<Self> {
+        let modes = device.http_device_info.as_ref()?.music_mode_names();
+        if modes.is_empty() {
+            return None;
+        }
+
+        let command_topic = format!(
+            "{}/{id}/set-music-mode",
+            base_topic(),
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "{}/{id}/notify-music-mode",
+            base_topic(),
+            id = topic_safe_id(device)
+        );
+        let availability_topic = availability_topic();
+        let unique_id = format!("gv2mqtt-{id}-music-mode", id = topic_safe_id(device));
+
+        // The empty option represents "not in a music mode"
+        let mut options = vec!["".to_string()];
+        options.extend(modes);
+
+        Some(Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability_topic,
+                    name: Some("Music Mode".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: Some("mdi:music".to_string()),
+                },
+                command_topic,
+                state_topic,
+                options,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for MusicModeSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        client
+            .publish(
+                &self.select.state_topic,
+                device.active_music_mode().unwrap_or(""),
+            )
+            .await
+    }
+}
+
+pub async fn mqtt_set_music_mode(
+    Payload(mode): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    if mode.is_empty() {
+        anyhow::bail!("Cannot set music mode to no-mode");
+    }
+    let device = state.resolve_device_for_control(&id).await?;
+
+    state
+        .device_set_music_mode(&device, &mode)
+        .await
+        .context("mqtt_set_music_mode: state.device_set_music_mode")?;
+
+    Ok(())
+}
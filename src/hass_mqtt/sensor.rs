@@ -1,10 +1,14 @@
+use crate::backoff::{breaker_statuses, upstream_for_account, PLATFORM_API, UNDOC_API};
 use crate::commands::serve::POLL_INTERVAL;
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::humidifier::DEVICE_CLASS_HUMIDITY;
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
-use crate::platform_api::DeviceCapability;
+use crate::platform_api::{DeviceCapability, GoveeApiClient};
+use crate::platform_quota::PlatformQuota;
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
+use crate::service::hass::{
+    availability_topic, base_topic, topic_safe_id, topic_safe_string, HassClient,
+};
 use crate::service::quirks::HumidityUnits;
 use crate::service::state::StateHandle;
 use crate::temperature::{TemperatureUnits, TemperatureValue, DEVICE_CLASS_TEMPERATURE};
@@ -12,6 +16,8 @@ use async_trait::async_trait;
 use chrono::Utc;
 use serde::Serialize;
 use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::Arc;
 
 #[derive(Serialize, Clone, Debug)]
 pub struct SensorConfig {
@@ -57,11 +63,11 @@ pub struct GlobalFixedDiagnostic {
 #[async_trait]
 impl EntityInstance for GlobalFixedDiagnostic {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.sensor.publish(&state, &client).await
+        self.sensor.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
-        self.sensor.notify_state(&client, &self.value).await
+        self.sensor.notify_state(client, &self.value).await
     }
 }
 
@@ -82,7 +88,7 @@ impl GlobalFixedDiagnostic {
                     device_class: None,
                     icon: None,
                 },
-                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_topic: format!("{}/sensor/{unique_id}/state", base_topic()),
                 state_class: None,
                 unit_of_measurement: None,
                 json_attributes_topic: None,
@@ -149,8 +155,8 @@ impl CapabilitySensor {
                     device_class,
                     icon: None,
                 },
-                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
-                state_class: state_class,
+                state_topic: format!("{}/sensor/{unique_id}/state", base_topic()),
+                state_class,
                 unit_of_measurement,
                 json_attributes_topic: None,
             },
@@ -164,7 +170,7 @@ impl CapabilitySensor {
 #[async_trait]
 impl EntityInstance for CapabilitySensor {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.sensor.publish(&state, &client).await
+        self.sensor.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -215,7 +221,7 @@ impl EntityInstance for CapabilitySensor {
                 _ => cap.state.to_string(),
             };
 
-            return self.sensor.notify_state(&client, &value).await;
+            return self.sensor.notify_state(client, &value).await;
         }
         log::trace!(
             "CapabilitySensor::notify_state: didn't find state for {device} {instance}",
@@ -247,9 +253,12 @@ impl DeviceStatusDiagnostic {
                     device_class: None,
                     icon: None,
                 },
-                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_topic: format!("{}/sensor/{unique_id}/state", base_topic()),
                 state_class: None,
-                json_attributes_topic: Some(format!("gv2mqtt/sensor/{unique_id}/attributes")),
+                json_attributes_topic: Some(format!(
+                    "{}/sensor/{unique_id}/attributes",
+                    base_topic()
+                )),
                 unit_of_measurement: None,
             },
             device_id: device.id.to_string(),
@@ -261,7 +270,7 @@ impl DeviceStatusDiagnostic {
 #[async_trait]
 impl EntityInstance for DeviceStatusDiagnostic {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.sensor.publish(&state, &client).await
+        self.sensor.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -293,7 +302,22 @@ impl EntityInstance for DeviceStatusDiagnostic {
             None => "Unknown".to_string(),
         };
 
+        // Report the breakers of the cloud APIs that this device
+        // relies on, for the account that the device belongs to
+        let account = device.account.as_deref();
+        let platform = upstream_for_account(PLATFORM_API, account);
+        let undoc = upstream_for_account(UNDOC_API, account);
+        let circuit_breakers: BTreeMap<_, _> = breaker_statuses()
+            .into_iter()
+            .filter(|(name, _)| {
+                let upstream = name.split_once('@').map(|(u, _)| u).unwrap_or(name);
+                (upstream == platform && device.http_device_info.is_some())
+                    || (upstream == undoc && device.undoc_device_info.is_some())
+            })
+            .collect();
+
         let attributes = json!({
+            "circuit_breakers": circuit_breakers,
             "iot": iot_state,
             "lan": lan_state,
             "http": http_state,
@@ -302,10 +326,69 @@ impl EntityInstance for DeviceStatusDiagnostic {
             "overall": device_state,
         });
 
-        self.sensor.notify_state(&client, &summary).await?;
+        self.sensor.notify_state(client, &summary).await?;
         if let Some(topic) = &self.sensor.json_attributes_topic {
             client.publish_obj(topic, attributes).await?;
         }
         Ok(())
     }
 }
+
+/// Reports the remaining daily request budget of the Platform API
+/// client of an account
+pub struct PlatformQuotaDiagnostic {
+    sensor: SensorConfig,
+    quota: Arc<PlatformQuota>,
+}
+
+impl PlatformQuotaDiagnostic {
+    pub fn new(client: &GoveeApiClient) -> Self {
+        let quota = client.quota();
+        let name = match &quota.status().account {
+            Some(account) => format!("Platform API Quota ({account})"),
+            None => "Platform API Quota".to_string(),
+        };
+        let unique_id = format!("global-{}", topic_safe_string(&name));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(name),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::this_service(),
+                    unique_id: unique_id.clone(),
+                    device_class: None,
+                    icon: Some("mdi:api".to_string()),
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", base_topic()),
+                state_class: Some(StateClass::Measurement),
+                unit_of_measurement: Some("requests"),
+                json_attributes_topic: Some(format!(
+                    "{}/sensor/{unique_id}/attributes",
+                    base_topic()
+                )),
+            },
+            quota,
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for PlatformQuotaDiagnostic {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let status = self.quota.status();
+        self.sensor
+            .notify_state(client, &status.remaining.to_string())
+            .await?;
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            client.publish_obj(topic, status).await?;
+        }
+        Ok(())
+    }
+}
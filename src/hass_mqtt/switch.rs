@@ -3,11 +3,12 @@ use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::platform_api::DeviceCapability;
 use crate::service::device::Device as ServiceDevice;
 use crate::service::hass::{
-    availability_topic, camel_case_to_space_separated, switch_instance_state_topic, topic_safe_id,
-    HassClient,
+    availability_topic, base_topic, camel_case_to_space_separated, switch_instance_state_topic,
+    topic_safe_id, HassClient, IdParameter,
 };
 use crate::service::state::StateHandle;
 use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
 use serde::Serialize;
 use serde_json::json;
 
@@ -25,7 +26,8 @@ impl SwitchConfig {
         instance: &DeviceCapability,
     ) -> anyhow::Result<Self> {
         let command_topic = format!(
-            "gv2mqtt/switch/{id}/command/{inst}",
+            "{}/switch/{id}/command/{inst}",
+            base_topic(),
             id = topic_safe_id(device),
             inst = instance.instance
         );
@@ -84,7 +86,7 @@ impl CapabilitySwitch {
 #[async_trait]
 impl EntityInstance for CapabilitySwitch {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.switch.publish(&state, &client).await
+        self.switch.publish(state, client).await
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -142,3 +144,88 @@ impl EntityInstance for CapabilitySwitch {
         Ok(())
     }
 }
+
+pub struct MusicAutoColorSwitch {
+    switch: SwitchConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl MusicAutoColorSwitch {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let command_topic = format!(
+            "{}/{id}/set-music-auto-color",
+            base_topic(),
+            id = topic_safe_id(device)
+        );
+        let state_topic = format!(
+            "{}/{id}/notify-music-auto-color",
+            base_topic(),
+            id = topic_safe_id(device)
+        );
+        let unique_id = format!("gv2mqtt-{id}-music-auto-color", id = topic_safe_id(device));
+
+        Self {
+            switch: SwitchConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Music Auto Color".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: Some("config".to_string()),
+                    icon: Some("mdi:palette".to_string()),
+                },
+                command_topic,
+                state_topic,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for MusicAutoColorSwitch {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.switch.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        client
+            .publish(
+                &self.switch.state_topic,
+                if device.music_settings.auto_color {
+                    "ON"
+                } else {
+                    "OFF"
+                },
+            )
+            .await
+    }
+}
+
+pub async fn mqtt_set_music_auto_color(
+    Payload(command): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("music auto color for {id}: {command}");
+    let auto_color = match command.as_str() {
+        "ON" | "on" => true,
+        "OFF" | "off" => false,
+        _ => anyhow::bail!("invalid {command} for {id}"),
+    };
+    let device = state.resolve_device_for_control(&id).await?;
+
+    state
+        .device_set_music_settings(&device, |settings| settings.auto_color = auto_color)
+        .await
+}
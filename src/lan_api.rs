@@ -1,4 +1,5 @@
-use crate::ble::{Base64HexBytes, SetSceneCode};
+use crate::ble::encode_scene_activation;
+use crate::config::config;
 use crate::opt_env_var;
 use crate::platform_api::from_json;
 use crate::undoc_api::GoveeUndocumentedApi;
@@ -17,14 +18,39 @@ use tokio::time::Instant;
 // <https://app-h5.govee.com/user-manual/wlan-guide>
 
 /// The port on which govee devices listen for scan requests
-const SCAN_PORT: u16 = 4001;
+pub(crate) const SCAN_PORT: u16 = 4001;
 /// The port on which a client needs to listen to receive responses
 /// from govee devices
-const LISTEN_PORT: u16 = 4002;
+pub(crate) const LISTEN_PORT: u16 = 4002;
 /// The port on which govee devices listen for control requests
-const CMD_PORT: u16 = 4003;
+pub(crate) const CMD_PORT: u16 = 4003;
 /// The multicast group of which govee LAN-API enabled devices are members
-const MULTICAST: IpAddr = IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250));
+pub(crate) const MULTICAST: IpAddr = IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250));
+
+/// The UDP ports used by the LAN protocol.
+/// Real devices always use the default ports; other ports are
+/// useful when talking to the simulator, so that it can run
+/// alongside a running instance of the service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LanPorts {
+    pub scan: u16,
+    pub listen: u16,
+    pub cmd: u16,
+}
+
+impl Default for LanPorts {
+    fn default() -> Self {
+        Self {
+            scan: SCAN_PORT,
+            listen: LISTEN_PORT,
+            cmd: CMD_PORT,
+        }
+    }
+}
+
+fn default_cmd_port() -> u16 {
+    CMD_PORT
+}
 
 #[derive(clap::Parser, Debug)]
 pub struct LanDiscoArguments {
@@ -52,8 +78,9 @@ pub struct LanDiscoArguments {
 
     /// How long to wait for discovery to complete, in seconds
     /// You may also set GOVEE_LAN_DISCO_TIMEOUT via the environment.
-    #[arg(long, default_value_t = 3, global = true)]
-    disco_timeout: u64,
+    /// If unspecified, uses 3 seconds.
+    #[arg(long, global = true)]
+    disco_timeout: Option<u64>,
 }
 
 pub fn truthy(s: &str) -> anyhow::Result<bool> {
@@ -75,45 +102,76 @@ pub fn truthy(s: &str) -> anyhow::Result<bool> {
 }
 
 impl LanDiscoArguments {
-    pub fn to_disco_options(&self) -> anyhow::Result<DiscoOptions> {
-        let mut options = DiscoOptions {
-            enable_multicast: !self.no_multicast,
-            additional_addresses: self.scan.clone(),
-            broadcast_all_interfaces: self.broadcast_all,
-            global_broadcast: self.global_broadcast,
-        };
-
-        if let Some(v) = opt_env_var::<String>("GOVEE_LAN_NO_MULTICAST")? {
-            options.enable_multicast = truthy(&v)?;
+    /// Resolves a boolean flag; a flag passed on the command line
+    /// wins over the environment, which wins over the config file.
+    fn resolve_flag(flag: bool, env_name: &str, file: Option<bool>) -> anyhow::Result<bool> {
+        if flag {
+            return Ok(true);
         }
-
-        if let Some(v) = opt_env_var::<String>("GOVEE_LAN_BROADCAST_ALL")? {
-            options.broadcast_all_interfaces = truthy(&v)?;
-        }
-
-        if let Some(v) = opt_env_var::<String>("GOVEE_LAN_BROADCAST_GLOBAL")? {
-            options.global_broadcast = truthy(&v)?;
+        if let Some(v) = opt_env_var::<String>(env_name)? {
+            return truthy(&v);
         }
+        Ok(file.unwrap_or(false))
+    }
 
-        if let Some(v) = opt_env_var::<String>("GOVEE_LAN_SCAN")? {
+    pub fn to_disco_options(&self) -> anyhow::Result<DiscoOptions> {
+        let lan_config = &config().lan;
+
+        let no_multicast = Self::resolve_flag(
+            self.no_multicast,
+            "GOVEE_LAN_NO_MULTICAST",
+            lan_config.no_multicast,
+        )?;
+        let broadcast_all_interfaces = Self::resolve_flag(
+            self.broadcast_all,
+            "GOVEE_LAN_BROADCAST_ALL",
+            lan_config.broadcast_all,
+        )?;
+        let global_broadcast = Self::resolve_flag(
+            self.global_broadcast,
+            "GOVEE_LAN_BROADCAST_GLOBAL",
+            lan_config.global_broadcast,
+        )?;
+
+        let mut additional_addresses = if !self.scan.is_empty() {
+            self.scan.clone()
+        } else if let Some(v) = opt_env_var::<String>("GOVEE_LAN_SCAN")? {
+            let mut addresses = vec![];
             for addr in v.split(',') {
                 let ip = addr
                     .trim()
                     .parse()
                     .with_context(|| format!("parsing {v} as IpAddr"))?;
-                options.additional_addresses.push(ip);
+                addresses.push(ip);
+            }
+            addresses
+        } else {
+            lan_config.scan.clone().unwrap_or_default()
+        };
+
+        // Devices with a known address in the config file are always scanned
+        for ip in config().device_addresses() {
+            if !additional_addresses.contains(&ip) {
+                additional_addresses.push(ip);
             }
         }
 
-        Ok(options)
+        Ok(DiscoOptions {
+            enable_multicast: !no_multicast,
+            additional_addresses,
+            broadcast_all_interfaces,
+            global_broadcast,
+            ports: LanPorts::default(),
+        })
     }
 
     pub fn disco_timeout(&self) -> anyhow::Result<u64> {
-        if let Some(v) = opt_env_var("GOVEE_LAN_DISCO_TIMEOUT")? {
-            Ok(v)
-        } else {
-            Ok(self.disco_timeout)
+        if let Some(v) = self.disco_timeout {
+            return Ok(v);
         }
+        Ok(opt_env_var("GOVEE_LAN_DISCO_TIMEOUT")?
+            .or(config().lan.disco_timeout)
+            .unwrap_or(3))
     }
 }
 
@@ -128,6 +186,8 @@ pub struct DiscoOptions {
     pub broadcast_all_interfaces: bool,
     /// Broadcast to the global broadcast address
     pub global_broadcast: bool,
+    /// The ports to use to talk to the devices
+    pub ports: LanPorts,
 }
 
 impl DiscoOptions {
@@ -146,6 +206,7 @@ impl Default for DiscoOptions {
             additional_addresses: vec![],
             broadcast_all_interfaces: false,
             global_broadcast: false,
+            ports: LanPorts::default(),
         }
     }
 }
@@ -172,8 +233,8 @@ pub enum Request {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-struct RequestMessage {
-    msg: Request,
+pub(crate) struct RequestMessage {
+    pub msg: Request,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq)]
@@ -189,6 +250,11 @@ pub struct LanDevice {
     pub wifi_version_hard: String,
     #[serde(rename = "wifiVersionSoft")]
     pub wifi_version_soft: String,
+    /// The port on which the device accepts control requests.
+    /// This is not part of the protocol; it is filled in by
+    /// the client that discovered the device.
+    #[serde(skip, default = "default_cmd_port")]
+    pub cmd_port: u16,
 }
 
 impl LanDevice {
@@ -196,7 +262,9 @@ impl LanDevice {
         log::trace!("LanDevice::send_request to {:?} {msg:?}", self.ip);
         let client = udp_socket_for_target(self.ip).await?;
         let data = serde_json::to_string(&RequestMessage { msg })?;
-        client.send_to(data.as_bytes(), (self.ip, CMD_PORT)).await?;
+        client
+            .send_to(data.as_bytes(), (self.ip, self.cmd_port))
+            .await?;
 
         Ok(())
     }
@@ -240,21 +308,24 @@ impl LanDevice {
     pub async fn set_scene_by_name(&self, scene_name: &str) -> anyhow::Result<()> {
         for category in GoveeUndocumentedApi::get_scenes_for_device(&self.sku).await? {
             for scene in category.scenes {
+                if scene.scene_name != scene_name {
+                    continue;
+                }
                 for effect in scene.light_effects {
-                    if scene.scene_name == scene_name && effect.scene_code != 0 {
-                        let encoded = Base64HexBytes::encode_for_sku(
-                            "Generic:Light",
-                            &SetSceneCode {
-                                code: effect.scene_code,
-                            },
-                        )?
-                        .base64();
-                        log::info!(
-                            "sending scene packet {encoded:x?} for {scene_name}, code {}",
-                            effect.scene_code
-                        );
-                        return self.send_real(vec![encoded]).await;
+                    if effect.scene_code == 0 && effect.scence_param.is_empty() {
+                        continue;
                     }
+                    let encoded: Vec<String> =
+                        encode_scene_activation(effect.scene_code, &effect.scence_param)?
+                            .iter()
+                            .map(|packet| packet.base64())
+                            .collect();
+                    log::info!(
+                        "sending {} scene packets {encoded:x?} for {scene_name}, code {}",
+                        encoded.len(),
+                        effect.scene_code
+                    );
+                    return self.send_real(encoded).await;
                 }
             }
         }
@@ -305,8 +376,8 @@ pub enum Response {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-struct ResponseWrapper {
-    msg: Response,
+pub(crate) struct ResponseWrapper {
+    pub msg: Response,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -320,9 +391,9 @@ struct ClientListener {
     tx: Sender<Response>,
 }
 
-#[derive(Default)]
 struct ClientInner {
     mux: Mutex<Vec<ClientListener>>,
+    ports: LanPorts,
 }
 
 #[derive(Clone)]
@@ -333,6 +404,7 @@ pub struct Client {
 #[derive(Debug)]
 struct Broadcaster {
     addr: IpAddr,
+    port: u16,
     socket: UdpSocket,
 }
 
@@ -344,7 +416,7 @@ async fn udp_socket_for_target(addr: IpAddr) -> std::io::Result<UdpSocket> {
 }
 
 impl Broadcaster {
-    pub async fn new(addr: IpAddr) -> std::io::Result<Self> {
+    pub async fn new(addr: IpAddr, port: u16) -> std::io::Result<Self> {
         let socket = udp_socket_for_target(addr).await?;
 
         if addr.is_multicast() {
@@ -362,12 +434,12 @@ impl Broadcaster {
             socket.set_broadcast(true)?;
         }
 
-        Ok(Self { addr, socket })
+        Ok(Self { addr, port, socket })
     }
 
     pub async fn broadcast<B: AsRef<[u8]>>(&self, bytes: B) -> std::io::Result<()> {
         self.socket
-            .send_to(bytes.as_ref(), (self.addr, SCAN_PORT))
+            .send_to(bytes.as_ref(), (self.addr, self.port))
             .await?;
         Ok(())
     }
@@ -406,7 +478,7 @@ async fn send_scan(options: &DiscoOptions) -> anyhow::Result<()> {
 
     let mut broadcasters = vec![];
     for addr in addresses {
-        match Broadcaster::new(addr).await {
+        match Broadcaster::new(addr, options.ports.scan).await {
             Ok(b) => broadcasters.push(b),
             Err(err) => {
                 log::error!("{addr}: {err:#}");
@@ -433,15 +505,20 @@ async fn lan_disco(
     options: DiscoOptions,
     inner: Arc<ClientInner>,
 ) -> anyhow::Result<Receiver<LanDevice>> {
-    let listen = UdpSocket::bind(("0.0.0.0", LISTEN_PORT)).await.context(
-        "Cannot bind to UDP Port 4002, which is required \
-        for the Govee LAN API to function. Most likely cause is that you \
-        are running another integration (perhaps `Govee LAN Control`, or \
-        `homebridge-govee`) that is already bound to that port. \
-        Both cannot run on the same machine at the same time. \
-        Consider disabling `Govee LAN Control` or setting `lanDisable` in \
-        `homebridge-govee`.",
-    )?;
+    let listen_port = options.ports.listen;
+    let listen = UdpSocket::bind(("0.0.0.0", listen_port))
+        .await
+        .with_context(|| {
+            format!(
+                "Cannot bind to UDP Port {listen_port}, which is required \
+                for the Govee LAN API to function. Most likely cause is that you \
+                are running another integration (perhaps `Govee LAN Control`, or \
+                `homebridge-govee`) that is already bound to that port. \
+                Both cannot run on the same machine at the same time. \
+                Consider disabling `Govee LAN Control` or setting `lanDisable` in \
+                `homebridge-govee`."
+            )
+        })?;
     let (tx, rx) = channel(8);
 
     async fn process_packet(
@@ -455,8 +532,11 @@ async fn lan_disco(
             String::from_utf8_lossy(data)
         );
 
-        let response: ResponseWrapper = from_json(data)
+        let mut response: ResponseWrapper = from_json(data)
             .with_context(|| format!("Parsing: {}", String::from_utf8_lossy(data)))?;
+        if let Response::Scan(info) = &mut response.msg {
+            info.cmd_port = inner.ports.cmd;
+        }
 
         let mut mux = inner.mux.lock().await;
         mux.retain(|l| !l.tx.is_closed());
@@ -467,6 +547,7 @@ async fn lan_disco(
         }
 
         if let Response::Scan(info) = response.msg {
+            crate::metrics::lan_discovery_response();
             tx.send(info).await?;
         }
 
@@ -517,7 +598,10 @@ async fn lan_disco(
 
 impl Client {
     pub async fn new(options: DiscoOptions) -> anyhow::Result<(Self, Receiver<LanDevice>)> {
-        let inner = Arc::new(ClientInner::default());
+        let inner = Arc::new(ClientInner {
+            mux: Mutex::new(vec![]),
+            ports: options.ports,
+        });
         let rx = lan_disco(options, Arc::clone(&inner)).await?;
 
         Ok((Self { inner }, rx))
@@ -538,7 +622,7 @@ impl Client {
     pub async fn scan_ip(&self, addr: IpAddr) -> anyhow::Result<LanDevice> {
         let mut rx = self.add_listener(addr).await?;
 
-        let bcast = Broadcaster::new(addr).await?;
+        let bcast = Broadcaster::new(addr, self.inner.ports.scan).await?;
         let scan = serde_json::to_string(&RequestMessage {
             msg: Request::Scan {
                 account_topic: AccountTopic::Reserve,
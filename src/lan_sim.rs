@@ -0,0 +1,454 @@
+//! A simulator for devices that speak the Govee LAN protocol.
+//! It answers scan requests and control commands from in-memory
+//! state, which makes it possible to exercise discovery and control
+//! end to end without any physical devices.
+use crate::ble::{Base64HexBytes, GoveeBlePacket};
+use crate::lan_api::{
+    DeviceStatus, LanDevice, LanPorts, Request, RequestMessage, Response, ResponseWrapper,
+    MULTICAST,
+};
+use crate::platform_api::from_json;
+use anyhow::Context;
+use parking_lot::Mutex;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+#[derive(Clone, Debug)]
+pub struct SimulatedDevice {
+    pub sku: String,
+    pub id: String,
+    /// The address that the device will use. Each simulated
+    /// device must have a distinct address, as that is how
+    /// clients tell their responses apart.
+    pub ip: IpAddr,
+    /// When false, devStatus requests are ignored, which is
+    /// useful to simulate a device that has stopped responding
+    pub respond_to_status: bool,
+    /// The status that the device has when the simulator starts
+    pub initial_status: DeviceStatus,
+}
+
+impl SimulatedDevice {
+    pub fn new<S: Into<String>, I: Into<String>>(sku: S, id: I, ip: IpAddr) -> Self {
+        Self {
+            sku: sku.into(),
+            id: id.into(),
+            ip,
+            respond_to_status: true,
+            initial_status: DeviceStatus::default(),
+        }
+    }
+
+    fn lan_device(&self, ports: LanPorts) -> LanDevice {
+        LanDevice {
+            ip: self.ip,
+            device: self.id.to_string(),
+            sku: self.sku.to_string(),
+            ble_version_hard: "1.00.00".to_string(),
+            ble_version_soft: "1.00.00".to_string(),
+            wifi_version_hard: "1.00.00".to_string(),
+            wifi_version_soft: "1.00.00".to_string(),
+            cmd_port: ports.cmd,
+        }
+    }
+}
+
+/// The mutable state of a simulated device
+#[derive(Clone, Debug, Default)]
+pub struct SimulatedState {
+    pub status: DeviceStatus,
+    /// The most recently applied scene code
+    pub scene_code: Option<u16>,
+    /// The decoded ptReal packets received by the device, in order
+    pub pt_real: Vec<GoveeBlePacket>,
+}
+
+impl SimulatedState {
+    fn apply(&mut self, sku: &str, request: &Request) {
+        match request {
+            Request::Scan { .. } | Request::DevStatus {} => {}
+            Request::Turn { value } => {
+                self.status.on = *value != 0;
+            }
+            Request::Brightness { value } => {
+                self.status.brightness = *value;
+            }
+            Request::Color {
+                color,
+                color_temperature_kelvin,
+            } => {
+                if *color_temperature_kelvin != 0 {
+                    self.status.color_temperature_kelvin = *color_temperature_kelvin;
+                } else {
+                    self.status.color = *color;
+                    self.status.color_temperature_kelvin = 0;
+                }
+                self.scene_code.take();
+            }
+            Request::PtReal { command } => {
+                for encoded in command {
+                    let packet = match Base64HexBytes::from_base64(encoded) {
+                        // Scene packets are encoded using the generic light
+                        // codec, so fall back to that for unknown packets
+                        Ok(bytes) => match bytes.decode_for_sku(sku) {
+                            GoveeBlePacket::Generic(_) => bytes.decode_for_sku("Generic:Light"),
+                            packet => packet,
+                        },
+                        Err(err) => {
+                            log::error!("ptReal: invalid packet {encoded}: {err:#}");
+                            continue;
+                        }
+                    };
+                    match &packet {
+                        GoveeBlePacket::SetSceneCode(scene) => {
+                            self.scene_code.replace(scene.code);
+                        }
+                        GoveeBlePacket::SetDevicePower(power) => {
+                            self.status.on = power.on;
+                        }
+                        _ => {}
+                    }
+                    self.pt_real.push(packet);
+                }
+            }
+        }
+    }
+}
+
+struct SimDeviceInner {
+    info: SimulatedDevice,
+    ports: LanPorts,
+    socket: UdpSocket,
+    state: Mutex<SimulatedState>,
+}
+
+impl SimDeviceInner {
+    async fn respond(&self, addr: SocketAddr, msg: Response) -> anyhow::Result<()> {
+        let data = serde_json::to_string(&ResponseWrapper { msg })?;
+        self.socket
+            .send_to(data.as_bytes(), (addr.ip(), self.ports.listen))
+            .await?;
+        Ok(())
+    }
+
+    async fn process_packet(&self, addr: SocketAddr, data: &[u8]) -> anyhow::Result<()> {
+        let request: RequestMessage = from_json(data)
+            .with_context(|| format!("Parsing: {}", String::from_utf8_lossy(data)))?;
+        log::info!(
+            "{} {} received {:?}",
+            self.info.sku,
+            self.info.id,
+            request.msg
+        );
+
+        match &request.msg {
+            Request::Scan { .. } => {
+                self.respond(addr, Response::Scan(self.info.lan_device(self.ports)))
+                    .await?;
+            }
+            Request::DevStatus {} => {
+                if self.info.respond_to_status {
+                    let status = self.state.lock().status.clone();
+                    self.respond(addr, Response::DevStatus(status)).await?;
+                }
+            }
+            request => {
+                self.state.lock().apply(&self.info.sku, request);
+            }
+        }
+        Ok(())
+    }
+
+    async fn run(self: Arc<Self>) {
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.socket.recv_from(&mut buf).await {
+                Ok((len, addr)) => {
+                    if let Err(err) = self.process_packet(addr, &buf[0..len]).await {
+                        log::error!("{}: {err:#}", self.info.id);
+                    }
+                }
+                Err(err) => {
+                    log::error!("{}: recv_from: {err:#}", self.info.id);
+                }
+            }
+        }
+    }
+}
+
+pub struct LanSimulator {
+    devices: Vec<Arc<SimDeviceInner>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl LanSimulator {
+    /// Start simulating `devices`.
+    /// Scan requests are received on `scan_bind`; if that is the
+    /// unspecified address, the simulator will also join the
+    /// Govee multicast group so that regular discovery will find it.
+    /// `ports` must match those used by the client.
+    pub async fn start(
+        devices: Vec<SimulatedDevice>,
+        scan_bind: IpAddr,
+        ports: LanPorts,
+    ) -> anyhow::Result<Self> {
+        let mut sim = Self {
+            devices: vec![],
+            tasks: vec![],
+        };
+
+        for info in devices {
+            let socket = UdpSocket::bind((info.ip, ports.cmd))
+                .await
+                .with_context(|| format!("binding to {}:{}", info.ip, ports.cmd))?;
+            let state = SimulatedState {
+                status: info.initial_status.clone(),
+                ..SimulatedState::default()
+            };
+            let device = Arc::new(SimDeviceInner {
+                info,
+                ports,
+                socket,
+                state: Mutex::new(state),
+            });
+            sim.tasks.push(tokio::spawn(device.clone().run()));
+            sim.devices.push(device);
+        }
+
+        let scan = UdpSocket::bind((scan_bind, ports.scan))
+            .await
+            .with_context(|| format!("binding to {scan_bind}:{}", ports.scan))?;
+        if scan_bind.is_unspecified() {
+            if let IpAddr::V4(group) = MULTICAST {
+                if let Err(err) = scan.join_multicast_v4(group, Ipv4Addr::UNSPECIFIED) {
+                    log::warn!("Unable to join multicast group {group}: {err:#}");
+                }
+            }
+        }
+
+        let devices = sim.devices.clone();
+        sim.tasks.push(tokio::spawn(async move {
+            let mut buf = [0u8; 4096];
+            loop {
+                match scan.recv_from(&mut buf).await {
+                    Ok((len, addr)) => {
+                        // We cannot tell which address the scan was sent to,
+                        // so every device responds to it
+                        for device in &devices {
+                            if let Err(err) = device.process_packet(addr, &buf[0..len]).await {
+                                log::error!("{}: {err:#}", device.info.id);
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        log::error!("scan recv_from: {err:#}");
+                    }
+                }
+            }
+        }));
+
+        Ok(sim)
+    }
+
+    /// Returns the ids of the simulated devices
+    pub fn device_ids(&self) -> Vec<String> {
+        self.devices.iter().map(|d| d.info.id.to_string()).collect()
+    }
+
+    /// Returns a snapshot of the state of the device with the specified id
+    pub fn state(&self, id: &str) -> Option<SimulatedState> {
+        self.devices
+            .iter()
+            .find(|d| d.info.id == id)
+            .map(|d| d.state.lock().clone())
+    }
+}
+
+impl Drop for LanSimulator {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ble::SetSceneCode;
+    use crate::lan_api::{Client, DeviceColor, DiscoOptions};
+    use crate::undoc_api::{GoveeUndocumentedApi, LightEffectLibraryResponse};
+    use std::time::Duration;
+
+    /// Returns ports that are not currently in use, so that the tests
+    /// can run alongside each other and alongside a running govee2mqtt
+    fn unused_ports() -> LanPorts {
+        let sockets: Vec<_> = (0..3)
+            .map(|_| std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap())
+            .collect();
+        let port = |idx: usize| sockets[idx].local_addr().unwrap().port();
+        LanPorts {
+            scan: port(0),
+            listen: port(1),
+            cmd: port(2),
+        }
+    }
+
+    async fn client_for(
+        ip: IpAddr,
+        ports: LanPorts,
+    ) -> (Client, tokio::sync::mpsc::Receiver<LanDevice>) {
+        Client::new(DiscoOptions {
+            enable_multicast: false,
+            additional_addresses: vec![ip],
+            broadcast_all_interfaces: false,
+            global_broadcast: false,
+            ports,
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn discover(scan: &mut tokio::sync::mpsc::Receiver<LanDevice>) -> LanDevice {
+        tokio::time::timeout(Duration::from_secs(5), scan.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    /// Waits for the simulated device `id` to satisfy `ready`
+    async fn wait_for_state(
+        sim: &LanSimulator,
+        id: &str,
+        ready: impl Fn(&SimulatedState) -> bool,
+    ) -> SimulatedState {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let state = sim.state(id).unwrap();
+                if ready(&state) {
+                    break state;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn simulated_discovery_and_control() {
+        let ip: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let ports = unused_ports();
+        let id = "AA:BB:CC:DD:EE:FF:00:01";
+        let mut sim_device = SimulatedDevice::new("H6072", id, ip);
+        sim_device.initial_status.brightness = 100;
+        let sim = LanSimulator::start(vec![sim_device], ip, ports)
+            .await
+            .unwrap();
+
+        let (client, mut scan) = client_for(ip, ports).await;
+
+        let device = discover(&mut scan).await;
+        assert_eq!(device.sku, "H6072");
+        assert_eq!(device.device, id);
+        assert_eq!(device.ip, ip);
+        assert_eq!(device.cmd_port, ports.cmd);
+
+        let status = client.query_status(&device).await.unwrap();
+        assert!(!status.on);
+        assert_eq!(status.brightness, 100);
+
+        device.send_turn(true).await.unwrap();
+        device.send_brightness(42).await.unwrap();
+        device
+            .send_color_rgb(DeviceColor { r: 255, g: 0, b: 0 })
+            .await
+            .unwrap();
+        let scene =
+            Base64HexBytes::encode_for_sku("Generic:Light", &SetSceneCode { code: 1234 }).unwrap();
+        device.send_real(vec![scene.base64()]).await.unwrap();
+
+        let expected = DeviceStatus {
+            on: true,
+            brightness: 42,
+            color: DeviceColor { r: 255, g: 0, b: 0 },
+            color_temperature_kelvin: 0,
+        };
+        let status = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let status = client.query_status(&device).await.unwrap();
+                if status == expected {
+                    break status;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(status, expected);
+
+        let state = wait_for_state(&sim, id, |state| state.scene_code.is_some()).await;
+        assert_eq!(state.scene_code, Some(1234));
+        assert_eq!(
+            state.pt_real,
+            vec![GoveeBlePacket::SetSceneCode(SetSceneCode { code: 1234 })]
+        );
+    }
+
+    // Time is paused so that the status request times out
+    // without waiting for it in real time
+    #[tokio::test(start_paused = true)]
+    async fn status_timeout() {
+        let ip: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let ports = unused_ports();
+        let mut sim_device = SimulatedDevice::new("H6072", "AA:BB:CC:DD:EE:FF:00:02", ip);
+        sim_device.respond_to_status = false;
+        let device = sim_device.lan_device(ports);
+        let _sim = LanSimulator::start(vec![sim_device], ip, ports)
+            .await
+            .unwrap();
+
+        let (client, _scan) = client_for(ip, ports).await;
+        let err = client.query_status(&device).await.unwrap_err();
+        k9::snapshot!(err.to_string(), "timed out waiting for status");
+    }
+
+    #[tokio::test]
+    async fn set_scene_by_name() {
+        let ip: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let ports = unused_ports();
+        let id = "AA:BB:CC:DD:EE:FF:00:03";
+        let sim = LanSimulator::start(vec![SimulatedDevice::new("H6072", id, ip)], ip, ports)
+            .await
+            .unwrap();
+
+        let library: LightEffectLibraryResponse =
+            serde_json::from_str(include_str!("../test-data/light-effect-library-h6072.json"))
+                .unwrap();
+        GoveeUndocumentedApi::set_scenes_for_device("H6072", library.data.categories)
+            .await
+            .unwrap();
+
+        let (_client, mut scan) = client_for(ip, ports).await;
+        let device = discover(&mut scan).await;
+
+        // Sunset has parameters that must be uploaded using several
+        // packets before its scene code is sent
+        device.set_scene_by_name("Sunset").await.unwrap();
+        let state = wait_for_state(&sim, id, |state| state.scene_code.is_some()).await;
+        assert_eq!(state.scene_code, Some(2100));
+        assert_eq!(state.pt_real.len(), 8);
+        assert_eq!(
+            state.pt_real.last(),
+            Some(&GoveeBlePacket::SetSceneCode(SetSceneCode { code: 2100 }))
+        );
+
+        let err = device.set_scene_by_name("No Such Scene").await.unwrap_err();
+        k9::snapshot!(
+            err.to_string(),
+            "unable to set scene No Such Scene for AA:BB:CC:DD:EE:FF:00:03"
+        );
+    }
+}
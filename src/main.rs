@@ -3,15 +3,22 @@ use crate::platform_api::GoveeApiArguments;
 use crate::service::hass::HassArguments;
 use crate::undoc_api::UndocApiArguments;
 use clap::Parser;
+use std::path::PathBuf;
 use std::str::FromStr;
 
+mod backoff;
 mod ble;
 mod cache;
 mod commands;
+mod config;
 mod hass_mqtt;
 mod lan_api;
+mod lan_sim;
+mod metrics;
 #[macro_use]
 mod platform_api;
+mod platform_mock;
+mod platform_quota;
 mod rest_api;
 mod service;
 mod temperature;
@@ -30,6 +37,18 @@ pub struct Args {
     #[command(flatten)]
     hass_args: HassArguments,
 
+    /// Load settings from the specified TOML config file.
+    /// Options passed on the command line or via the environment
+    /// take precedence over those in the config file.
+    /// You may also set GOVEE_CONFIG via the environment.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Load additional device quirks from the specified TOML file.
+    /// You may also set GOVEE_QUIRKS_FILE via the environment.
+    #[arg(long, global = true)]
+    quirks_file: Option<PathBuf>,
+
     #[command(subcommand)]
     cmd: SubCommand,
 }
@@ -38,21 +57,49 @@ pub struct Args {
 pub enum SubCommand {
     LanControl(commands::lan_control::LanControlCommand),
     LanDisco(commands::lan_disco::LanDiscoCommand),
+    LanSim(commands::lan_sim::LanSimCommand),
     ListHttp(commands::list_http::ListHttpCommand),
     List(commands::list::ListCommand),
+    PlatformMock(commands::platform_mock::PlatformMockCommand),
+    Quirk(commands::quirk::QuirkCommand),
     HttpControl(commands::http_control::HttpControlCommand),
     Serve(commands::serve::ServeCommand),
     Undoc(commands::undoc::UndocCommand),
 }
 
 impl Args {
+    pub fn load_config(&self) -> anyhow::Result<()> {
+        let path = match &self.config {
+            Some(path) => Some(path.clone()),
+            None => opt_env_var("GOVEE_CONFIG")?,
+        };
+        if let Some(path) = path {
+            log::info!("Loading config file {path:?}");
+            config::load_config(&path)?;
+        }
+
+        let quirks_file = match &self.quirks_file {
+            Some(path) => Some(path.clone()),
+            None => opt_env_var("GOVEE_QUIRKS_FILE")?
+                .or_else(|| config::config().general.quirks_file.clone()),
+        };
+        if let Some(path) = quirks_file {
+            log::info!("Loading quirks file {path:?}");
+            service::quirks::load_user_quirks(&path)?;
+        }
+        Ok(())
+    }
+
     pub async fn run(&self) -> anyhow::Result<()> {
         match &self.cmd {
             SubCommand::LanControl(cmd) => cmd.run(self).await,
             SubCommand::LanDisco(cmd) => cmd.run(self).await,
+            SubCommand::LanSim(cmd) => cmd.run(self).await,
             SubCommand::ListHttp(cmd) => cmd.run(self).await,
             SubCommand::HttpControl(cmd) => cmd.run(self).await,
             SubCommand::List(cmd) => cmd.run(self).await,
+            SubCommand::PlatformMock(cmd) => cmd.run(self).await,
+            SubCommand::Quirk(cmd) => cmd.run(self).await,
             SubCommand::Serve(cmd) => cmd.run(self).await,
             SubCommand::Undoc(cmd) => cmd.run(self).await,
         }
@@ -74,16 +121,18 @@ where
     }
 }
 
-fn setup_logger() {
-    fn resolve_timezone() -> chrono_tz::Tz {
-        std::env::var("TZ")
-            .or_else(|_| iana_time_zone::get_timezone())
-            .ok()
-            .and_then(|name| name.parse().ok())
-            .unwrap_or(chrono_tz::UTC)
-    }
+/// Returns the local time zone, as specified by $TZ or
+/// the system configuration, falling back to UTC
+pub fn local_timezone() -> chrono_tz::Tz {
+    std::env::var("TZ")
+        .or_else(|_| iana_time_zone::get_timezone())
+        .ok()
+        .and_then(|name| name.parse().ok())
+        .unwrap_or(chrono_tz::UTC)
+}
 
-    let tz = resolve_timezone();
+fn setup_logger() {
+    let tz = local_timezone();
     let utc_suffix = if tz == chrono_tz::UTC { "Z" } else { "" };
 
     env_logger::builder()
@@ -120,5 +169,6 @@ async fn main() -> anyhow::Result<()> {
     setup_logger();
 
     let args = Args::parse();
+    args.load_config()?;
     args.run().await
 }
@@ -0,0 +1,200 @@
+//! Prometheus metrics that describe the health of the service.
+//! They are rendered in the text exposition format by the
+//! `/metrics` endpoint of the HTTP server.
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, TextEncoder,
+};
+use std::time::Duration;
+
+pub const LAN: &str = "lan";
+pub const IOT: &str = "iot";
+pub const PLATFORM: &str = "platform";
+
+static COMMANDS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "govee_commands_total",
+        "Number of control commands sent to devices, by transport",
+        &["transport", "command"]
+    )
+    .unwrap()
+});
+
+static COMMAND_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "govee_command_failures_total",
+        "Number of control commands that failed, by transport",
+        &["transport", "command"]
+    )
+    .unwrap()
+});
+
+static PLATFORM_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "govee_platform_api_requests_total",
+        "Number of requests made to the Govee Platform API, by HTTP status",
+        &["status"]
+    )
+    .unwrap()
+});
+
+static PLATFORM_RATE_LIMITED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "govee_platform_api_rate_limited_total",
+        "Number of Platform API requests that were rejected with 429 Too Many Requests"
+    )
+    .unwrap()
+});
+
+static POLL_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "govee_poll_duration_seconds",
+        "Time taken to poll the state of a device, by transport and result",
+        &["device", "transport", "result"],
+        vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]
+    )
+    .unwrap()
+});
+
+static IOT_CONNECTED: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "govee_iot_connected",
+        "1 if the AWS IoT connection of the account is currently established",
+        &["account"]
+    )
+    .unwrap()
+});
+
+static MQTT_CONNECTED: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "govee_mqtt_connected",
+        "1 if the Home Assistant MQTT connection is currently established"
+    )
+    .unwrap()
+});
+
+static LAN_DISCOVERY_RESPONSES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "govee_lan_discovery_responses_total",
+        "Number of scan responses received from LAN devices"
+    )
+    .unwrap()
+});
+
+static CACHE_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "govee_cache_requests_total",
+        "Number of cache lookups, by topic and result (hit, miss or stale)",
+        &["topic", "result"]
+    )
+    .unwrap()
+});
+
+/// Records that `command` was sent using `transport`, counting
+/// it as a failure if `result` is an error. The result is passed
+/// through so that this can wrap the call site.
+pub fn command<T>(transport: &str, command: &str, result: anyhow::Result<T>) -> anyhow::Result<T> {
+    COMMANDS.with_label_values(&[transport, command]).inc();
+    if result.is_err() {
+        COMMAND_FAILURES
+            .with_label_values(&[transport, command])
+            .inc();
+    }
+    result
+}
+
+pub fn platform_request(status: reqwest::StatusCode) {
+    PLATFORM_REQUESTS
+        .with_label_values(&[status.as_str()])
+        .inc();
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        PLATFORM_RATE_LIMITED.inc();
+    }
+}
+
+/// Records the time taken to poll `device` using `transport`
+pub fn poll_duration(device: &str, transport: &str, success: bool, elapsed: Duration) {
+    let result = if success { "ok" } else { "error" };
+    POLL_DURATION
+        .with_label_values(&[device, transport, result])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Records the state of the IoT connection of `account`;
+/// None for the primary account
+pub fn iot_connected(account: Option<&str>, connected: bool) {
+    IOT_CONNECTED
+        .with_label_values(&[account.unwrap_or("primary")])
+        .set(connected as i64);
+}
+
+pub fn mqtt_connected(connected: bool) {
+    MQTT_CONNECTED.set(connected as i64);
+}
+
+pub fn lan_discovery_response() {
+    LAN_DISCOVERY_RESPONSES.inc();
+}
+
+pub fn cache_request(topic: &str, result: &str) {
+    CACHE_REQUESTS.with_label_values(&[topic, result]).inc();
+}
+
+/// Renders all registered metrics in the Prometheus text format
+pub fn render() -> anyhow::Result<String> {
+    // Metrics are registered on first use; make sure that the
+    // scalar ones are always present, even if they are still zero
+    Lazy::force(&PLATFORM_RATE_LIMITED);
+    Lazy::force(&MQTT_CONNECTED);
+    Lazy::force(&LAN_DISCOVERY_RESPONSES);
+
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_metrics() {
+        let _ = command(LAN, "test_power", anyhow::Ok(()));
+        let _ = command(LAN, "test_power", Err::<(), _>(anyhow::anyhow!("failed")));
+        platform_request(reqwest::StatusCode::TOO_MANY_REQUESTS);
+        poll_duration("test-device", PLATFORM, false, Duration::from_millis(100));
+        iot_connected(Some("test-account"), true);
+
+        let text = render().unwrap();
+        assert!(
+            text.contains(r#"govee_commands_total{command="test_power",transport="lan"} 2"#),
+            "{text}"
+        );
+        assert!(
+            text.contains(
+                r#"govee_command_failures_total{command="test_power",transport="lan"} 1"#
+            ),
+            "{text}"
+        );
+        assert!(
+            text.contains(r#"govee_platform_api_requests_total{status="429"}"#),
+            "{text}"
+        );
+        assert!(
+            text.contains("govee_platform_api_rate_limited_total"),
+            "{text}"
+        );
+        assert!(
+            text.contains(
+                r#"govee_poll_duration_seconds_count{device="test-device",result="error",transport="platform"} 1"#
+            ),
+            "{text}"
+        );
+        assert!(
+            text.contains(r#"govee_iot_connected{account="test-account"} 1"#),
+            "{text}"
+        );
+    }
+}
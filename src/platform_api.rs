@@ -1,6 +1,9 @@
+use crate::backoff::{send_with_breaker_after, upstream_for_account, PLATFORM_API};
 use crate::cache::{cache_get, CacheComputeResult, CacheGetOptions};
+use crate::config::config;
 use crate::hass_mqtt::climate::parse_temperature_constraints;
 use crate::opt_env_var;
+use crate::platform_quota::{quota_for, PlatformQuota, RequestPriority};
 use crate::service::state::sort_and_dedup_scenes;
 use crate::temperature::{TemperatureUnits, TemperatureValue};
 use crate::undoc_api::GoveeUndocumentedApi;
@@ -9,6 +12,7 @@ use reqwest::Method;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::{json, Value as JsonValue};
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 use thiserror::Error;
 
@@ -23,23 +27,25 @@ const SERVER: &str = "https://openapi.api.govee.com";
 pub const ONE_WEEK: Duration = Duration::from_secs(86400 * 7);
 pub const FIVE_MINUTES: Duration = Duration::from_secs(5 * 60);
 
-fn endpoint(url: &str) -> String {
-    format!("{SERVER}{url}")
-}
-
 #[derive(clap::Parser, Debug)]
 pub struct GoveeApiArguments {
     /// The Govee API Key. If not passed here, it will be read from
     /// the GOVEE_API_KEY environment variable.
     #[arg(long, global = true)]
     pub api_key: Option<String>,
+
+    /// The base URL of the Govee Platform API.
+    /// You may also set this via the GOVEE_API_BASE_URL environment variable.
+    /// If unspecified, uses https://openapi.api.govee.com
+    #[arg(long, global = true)]
+    pub api_base_url: Option<String>,
 }
 
 impl GoveeApiArguments {
     pub fn opt_api_key(&self) -> anyhow::Result<Option<String>> {
         match &self.api_key {
             Some(key) => Ok(Some(key.to_string())),
-            None => opt_env_var("GOVEE_API_KEY"),
+            None => Ok(opt_env_var("GOVEE_API_KEY")?.or_else(|| config().govee.api_key.clone())),
         }
     }
 
@@ -47,40 +53,100 @@ impl GoveeApiArguments {
         self.opt_api_key()?.ok_or_else(|| {
             anyhow::anyhow!(
                 "Please specify the api key either via the \
-                --api-key parameter or by setting $GOVEE_API_KEY"
+                --api-key parameter, by setting $GOVEE_API_KEY \
+                or via govee.api_key in the config file"
             )
         })
     }
 
+    pub fn api_base_url(&self) -> anyhow::Result<String> {
+        match &self.api_base_url {
+            Some(url) => Ok(url.to_string()),
+            None => Ok(opt_env_var("GOVEE_API_BASE_URL")?
+                .or_else(|| config().govee.api_base_url.clone())
+                .unwrap_or_else(|| SERVER.to_string())),
+        }
+    }
+
     pub fn api_client(&self) -> anyhow::Result<GoveeApiClient> {
         let key = self.api_key()?;
-        Ok(GoveeApiClient::new(key))
+        Ok(GoveeApiClient::new(key).with_base_url(self.api_base_url()?))
     }
 }
 
 #[derive(Clone)]
 pub struct GoveeApiClient {
     key: String,
+    base_url: String,
+    account: Option<String>,
 }
 
 impl GoveeApiClient {
     pub fn new<K: Into<String>>(key: K) -> Self {
-        Self { key: key.into() }
+        Self {
+            key: key.into(),
+            base_url: SERVER.to_string(),
+            account: None,
+        }
+    }
+
+    /// Associates this client with one of the additional accounts
+    /// from the config file, so that its cached data is kept
+    /// separate from that of the primary account
+    pub fn with_account<A: Into<String>>(mut self, account: A) -> Self {
+        self.account.replace(account.into());
+        self
+    }
+
+    pub fn with_base_url<U: Into<String>>(mut self, base_url: U) -> Self {
+        self.base_url = base_url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    fn endpoint(&self, url: &str) -> String {
+        format!("{}{url}", self.base_url)
+    }
+
+    /// Returns the cache key to use for `key`; responses from a
+    /// non-default server, or for an additional account, are cached
+    /// separately so that they don't get mixed up with those from
+    /// the real service or the primary account
+    fn cache_key(&self, key: &str) -> String {
+        let mut key = key.to_string();
+        if let Some(account) = &self.account {
+            key = format!("{account}:{key}");
+        }
+        if self.base_url != SERVER {
+            key = format!("{key}@{}", self.base_url);
+        }
+        key
+    }
+
+    /// Returns the name of the circuit breaker upstream for this client
+    fn upstream(&self) -> String {
+        upstream_for_account(PLATFORM_API, self.account.as_deref())
+    }
+
+    /// Returns the tracker for the daily request limit of this client
+    pub fn quota(&self) -> Arc<PlatformQuota> {
+        quota_for(self.account.as_deref(), &self.cache_key("platform-quota"))
     }
 
     pub async fn get_devices(&self) -> anyhow::Result<Vec<HttpDeviceInfo>> {
         cache_get(
             CacheGetOptions {
                 topic: "http-api",
-                key: "device-list",
+                key: &self.cache_key("device-list"),
                 soft_ttl: Duration::from_secs(900),
                 hard_ttl: ONE_WEEK,
                 negative_ttl: Duration::from_secs(60),
                 allow_stale: true,
             },
             async {
-                let url = endpoint("/router/api/v1/user/devices");
-                let resp: GetDevicesResponse = self.get_request_with_json_response(url).await?;
+                let url = self.endpoint("/router/api/v1/user/devices");
+                let resp: GetDevicesResponse = self
+                    .get_request_with_json_response(url, RequestPriority::Background)
+                    .await?;
                 Ok(CacheComputeResult::Value(resp.data))
             },
         )
@@ -104,7 +170,7 @@ impl GoveeApiClient {
         capability: &DeviceCapability,
         value: V,
     ) -> anyhow::Result<ControlDeviceResponseCapability> {
-        let url = endpoint("/router/api/v1/device/control");
+        let url = self.endpoint("/router/api/v1/device/control");
         let request = ControlDeviceRequest {
             request_id: "uuid".to_string(),
             payload: ControlDevicePayload {
@@ -119,7 +185,7 @@ impl GoveeApiClient {
         };
 
         let resp: ControlDeviceResponse = self
-            .request_with_json_response(Method::POST, url, &request)
+            .request_with_json_response(Method::POST, url, &request, RequestPriority::Control)
             .await?;
 
         log::info!("control_device result: {resp:?}");
@@ -131,7 +197,7 @@ impl GoveeApiClient {
         &self,
         device: &HttpDeviceInfo,
     ) -> anyhow::Result<HttpDeviceState> {
-        let url = endpoint("/router/api/v1/device/state");
+        let url = self.endpoint("/router/api/v1/device/state");
         let request = GetDeviceStateRequest {
             request_id: "uuid".to_string(),
             payload: GetDeviceStateRequestPayload {
@@ -141,7 +207,7 @@ impl GoveeApiClient {
         };
 
         let resp: GetDeviceStateResponse = self
-            .request_with_json_response(Method::POST, url, &request)
+            .request_with_json_response(Method::POST, url, &request, RequestPriority::Background)
             .await?;
 
         Ok(resp.payload)
@@ -155,7 +221,7 @@ impl GoveeApiClient {
             return Ok(vec![]);
         }
 
-        let key = format!("scene-list-diy-{}-{}", device.sku, device.device);
+        let key = self.cache_key(&format!("scene-list-diy-{}-{}", device.sku, device.device));
         cache_get(
             CacheGetOptions {
                 topic: "http-api",
@@ -166,7 +232,7 @@ impl GoveeApiClient {
                 allow_stale: true,
             },
             async {
-                let url = endpoint("/router/api/v1/device/diy-scenes");
+                let url = self.endpoint("/router/api/v1/device/diy-scenes");
                 let request = GetDeviceScenesRequest {
                     request_id: "uuid".to_string(),
                     payload: GetDeviceScenesPayload {
@@ -176,7 +242,12 @@ impl GoveeApiClient {
                 };
 
                 let resp: GetDeviceScenesResponse = self
-                    .request_with_json_response(Method::POST, url, &request)
+                    .request_with_json_response(
+                        Method::POST,
+                        url,
+                        &request,
+                        RequestPriority::Background,
+                    )
                     .await?;
 
                 Ok(CacheComputeResult::Value(resp.payload.capabilities))
@@ -193,7 +264,7 @@ impl GoveeApiClient {
             return Ok(vec![]);
         }
 
-        let key = format!("scene-list-{}-{}", device.sku, device.device);
+        let key = self.cache_key(&format!("scene-list-{}-{}", device.sku, device.device));
         cache_get(
             CacheGetOptions {
                 topic: "http-api",
@@ -204,7 +275,7 @@ impl GoveeApiClient {
                 allow_stale: true,
             },
             async {
-                let url = endpoint("/router/api/v1/device/scenes");
+                let url = self.endpoint("/router/api/v1/device/scenes");
                 let request = GetDeviceScenesRequest {
                     request_id: "uuid".to_string(),
                     payload: GetDeviceScenesPayload {
@@ -214,7 +285,12 @@ impl GoveeApiClient {
                 };
 
                 let resp: GetDeviceScenesResponse = self
-                    .request_with_json_response(Method::POST, url, &request)
+                    .request_with_json_response(
+                        Method::POST,
+                        url,
+                        &request,
+                        RequestPriority::Background,
+                    )
                     .await?;
 
                 Ok(CacheComputeResult::Value(resp.payload.capabilities))
@@ -229,8 +305,8 @@ impl GoveeApiClient {
     ) -> anyhow::Result<Vec<DeviceCapability>> {
         let mut result = vec![];
 
-        let scene_caps = self.get_device_scenes(&device).await?;
-        let diy_caps = self.get_device_diy_scenes(&device).await?;
+        let scene_caps = self.get_device_scenes(device).await?;
+        let diy_caps = self.get_device_diy_scenes(device).await?;
         let undoc_caps =
             match GoveeUndocumentedApi::synthesize_platform_api_scene_list(&device.sku).await {
                 Ok(caps) => caps,
@@ -281,39 +357,15 @@ impl GoveeApiClient {
     }
 
     pub async fn list_scene_names(&self, device: &HttpDeviceInfo) -> anyhow::Result<Vec<String>> {
-        let mut result = vec![];
-
         let caps = self
             .get_scene_caps(device)
             .await
             .context("list_scene_names: get_scene_caps")?;
-        for cap in caps {
-            match &cap.parameters {
-                Some(DeviceParameters::Enum { options }) => {
-                    for opt in options {
-                        result.push(opt.name.to_string());
-                    }
-                }
-                _ => anyhow::bail!("list_scene_names: unexpected type {cap:#?}"),
-            }
-        }
+        let mut result = scene_names_from_caps(&caps)?;
 
         // Add in music modes
-        if let Some(cap) = device.capability_by_instance("musicMode") {
-            if let Some(DeviceParameters::Struct { fields }) = &cap.parameters {
-                for f in fields {
-                    if f.field_name == "musicMode" {
-                        match &f.field_type {
-                            DeviceParameters::Enum { options } => {
-                                for opt in options {
-                                    result.push(format!("Music: {}", opt.name));
-                                }
-                            }
-                            _ => {}
-                        }
-                    }
-                }
-            }
+        for name in device.music_mode_names() {
+            result.push(format!("Music: {name}"));
         }
 
         if !result.is_empty() {
@@ -323,44 +375,53 @@ impl GoveeApiClient {
         Ok(sort_and_dedup_scenes(result))
     }
 
+    /// Activates `scene`, returning its name as it appears
+    /// in the list returned by list_scene_names
     pub async fn set_scene_by_name(
         &self,
         device: &HttpDeviceInfo,
         scene: &str,
-    ) -> anyhow::Result<ControlDeviceResponseCapability> {
-        if scene == "" {
+    ) -> anyhow::Result<String> {
+        if scene.is_empty() {
             // Can't set no scene
             anyhow::bail!("Cannot set scene to no-scene");
         }
 
         if let Some(music_mode) = scene.strip_prefix("Music: ") {
-            if let Some(cap) = device.capability_by_instance("musicMode") {
-                if let Some(field) = cap.struct_field_by_name("musicMode") {
-                    if let Some(value) = field.field_type.enum_parameter_by_name(music_mode) {
-                        let value = serde_json::json!({
-                            "musicMode": value,
-                            "sensitivity": 100,
-                            "autoColor": 1,
-                        });
-                        return self.control_device(&device, &cap, value).await;
-                    }
-                }
+            if let Some(value) = device.music_mode_value(music_mode) {
+                self.set_music_mode(device, value, 100, true).await?;
+                return Ok(scene.to_string());
             }
         }
 
         let caps = self.get_scene_caps(device).await?;
-        for cap in caps {
+        for cap in &caps {
             match &cap.parameters {
                 Some(DeviceParameters::Enum { options }) => {
                     for opt in options {
-                        if scene.eq_ignore_ascii_case(&opt.name) {
-                            return self.control_device(&device, &cap, opt.value.clone()).await;
+                        let label = scene_option_label(cap, &opt.name);
+                        if scene.eq_ignore_ascii_case(&label) {
+                            self.control_device(device, cap, opt.value.clone()).await?;
+                            return Ok(label);
                         }
                     }
                 }
                 _ => anyhow::bail!("set_scene_by_name: unexpected type {cap:#?}"),
             }
         }
+
+        // Allow DIY scenes to be activated by their unlabelled name too,
+        // for compatibility with automations that predate the label
+        for cap in caps.iter().filter(|cap| cap.instance == "diyScene") {
+            if let Some(DeviceParameters::Enum { options }) = &cap.parameters {
+                for opt in options {
+                    if scene.eq_ignore_ascii_case(&opt.name) {
+                        self.control_device(device, cap, opt.value.clone()).await?;
+                        return Ok(scene_option_label(cap, &opt.name));
+                    }
+                }
+            }
+        }
         anyhow::bail!("Scene '{scene}' is not available for this device");
     }
 
@@ -392,7 +453,7 @@ impl GoveeApiClient {
             "unit": "Celsius",
         });
 
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value).await
     }
 
     pub async fn set_work_mode(
@@ -410,7 +471,7 @@ impl GoveeApiClient {
             "modeValue": value
         });
 
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value).await
     }
 
     pub async fn set_toggle_state(
@@ -427,7 +488,7 @@ impl GoveeApiClient {
             .enum_parameter_by_name(if on { "on" } else { "off" })
             .ok_or_else(|| anyhow::anyhow!("{instance} has no on/off!?"))?;
 
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value).await
     }
 
     pub async fn set_power_state(
@@ -453,7 +514,7 @@ impl GoveeApiClient {
             }) => (percent as u32).max(*min).min(*max),
             _ => anyhow::bail!("unexpected parameter type for brightness"),
         };
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value).await
     }
 
     pub async fn set_color_temperature(
@@ -471,7 +532,7 @@ impl GoveeApiClient {
             }) => (kelvin).max(*min).min(*max),
             _ => anyhow::bail!("unexpected parameter type for colorTemperatureK"),
         };
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value).await
     }
 
     pub async fn set_color_rgb(
@@ -485,7 +546,29 @@ impl GoveeApiClient {
             .capability_by_instance("colorRgb")
             .ok_or_else(|| anyhow::anyhow!("device has no colorRgb"))?;
         let value = ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value).await
+    }
+
+    pub async fn set_music_mode(
+        &self,
+        device: &HttpDeviceInfo,
+        music_mode: u32,
+        sensitivity: u8,
+        auto_color: bool,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        let cap = device
+            .capability_by_instance("musicMode")
+            .ok_or_else(|| anyhow::anyhow!("device has no musicMode"))?;
+        self.control_device(
+            device,
+            cap,
+            json!({
+                "musicMode": music_mode,
+                "sensitivity": sensitivity,
+                "autoColor": if auto_color { 1 } else { 0 },
+            }),
+        )
+        .await
     }
 
     pub async fn set_segment_rgb(
@@ -501,8 +584,8 @@ impl GoveeApiClient {
             .ok_or_else(|| anyhow::anyhow!("device has no segmentedColorRgb"))?;
         let value = ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
         self.control_device(
-            &device,
-            &cap,
+            device,
+            cap,
             json!({
                 "segment": vec![segment],
                 "rgb": value,
@@ -528,8 +611,8 @@ impl GoveeApiClient {
         let value = (percent as u32).max(min).min(max);
 
         self.control_device(
-            &device,
-            &cap,
+            device,
+            cap,
             json!({
                 "segment": vec![segment],
                 "brightness": value,
@@ -541,7 +624,7 @@ impl GoveeApiClient {
 
 #[derive(Deserialize, Serialize, Debug)]
 #[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-struct GetDeviceScenesResponse {
+pub(crate) struct GetDeviceScenesResponse {
     #[serde(rename = "requestId")]
     pub request_id: String,
     pub code: u32,
@@ -552,50 +635,50 @@ struct GetDeviceScenesResponse {
 
 #[derive(Deserialize, Serialize, Debug)]
 #[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-struct GetDeviceScenesResponsePayload {
+pub(crate) struct GetDeviceScenesResponsePayload {
     pub sku: String,
     pub device: String,
     pub capabilities: Vec<DeviceCapability>,
 }
 
-#[derive(Serialize, Debug)]
-struct GetDeviceScenesRequest {
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct GetDeviceScenesRequest {
     #[serde(rename = "requestId")]
     pub request_id: String,
     pub payload: GetDeviceScenesPayload,
 }
 
-#[derive(Serialize, Debug)]
-struct GetDeviceScenesPayload {
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct GetDeviceScenesPayload {
     pub sku: String,
     pub device: String,
 }
 
-#[derive(Serialize, Debug)]
-struct ControlDeviceRequest {
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct ControlDeviceRequest {
     #[serde(rename = "requestId")]
     pub request_id: String,
     pub payload: ControlDevicePayload,
 }
 
-#[derive(Serialize, Debug)]
-struct ControlDevicePayload {
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct ControlDevicePayload {
     pub sku: String,
     pub device: String,
     pub capability: ControlDeviceCapability,
 }
 
-#[derive(Serialize, Debug)]
-struct ControlDeviceCapability {
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct ControlDeviceCapability {
     #[serde(rename = "type")]
     pub kind: DeviceCapabilityKind,
     pub instance: String,
     pub value: JsonValue,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 #[allow(dead_code)]
-struct ControlDeviceResponse {
+pub(crate) struct ControlDeviceResponse {
     #[serde(rename = "requestId")]
     pub request_id: String,
     pub code: u32,
@@ -605,7 +688,7 @@ struct ControlDeviceResponse {
     pub capability: ControlDeviceResponseCapability,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 #[allow(unused)]
 pub struct ControlDeviceResponseCapability {
     #[serde(rename = "type")]
@@ -615,22 +698,22 @@ pub struct ControlDeviceResponseCapability {
     pub state: JsonValue,
 }
 
-#[derive(Serialize, Debug)]
-struct GetDeviceStateRequest {
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct GetDeviceStateRequest {
     #[serde(rename = "requestId")]
     pub request_id: String,
     pub payload: GetDeviceStateRequestPayload,
 }
 
-#[derive(Serialize, Debug)]
-struct GetDeviceStateRequestPayload {
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct GetDeviceStateRequestPayload {
     pub sku: String,
     pub device: String,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 #[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-struct GetDeviceStateResponse {
+pub(crate) struct GetDeviceStateResponse {
     #[serde(rename = "requestId")]
     pub request_id: String,
     pub code: u32,
@@ -656,7 +739,6 @@ impl HttpDeviceState {
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(tag = "type")]
 #[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 pub struct DeviceCapabilityState {
     #[serde(rename = "type")]
@@ -667,7 +749,7 @@ pub struct DeviceCapabilityState {
 
 #[derive(Deserialize, Serialize, Debug)]
 #[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-struct GetDevicesResponse {
+pub(crate) struct GetDevicesResponse {
     pub code: u32,
     pub message: String,
     pub data: Vec<HttpDeviceInfo>,
@@ -706,6 +788,28 @@ impl HttpDeviceInfo {
             .any(|cap| cap.kind == DeviceCapabilityKind::DynamicScene)
     }
 
+    /// Returns the names of the music modes supported by the device
+    pub fn music_mode_names(&self) -> Vec<String> {
+        match self
+            .capability_by_instance("musicMode")
+            .and_then(|cap| cap.struct_field_by_name("musicMode"))
+            .map(|field| &field.field_type)
+        {
+            Some(DeviceParameters::Enum { options }) => {
+                options.iter().map(|opt| opt.name.to_string()).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Returns the value that identifies the named music mode
+    pub fn music_mode_value(&self, name: &str) -> Option<u32> {
+        self.capability_by_instance("musicMode")?
+            .struct_field_by_name("musicMode")?
+            .field_type
+            .enum_parameter_by_name(name)
+    }
+
     /// If supported, returns the number of segments
     pub fn supports_segmented_rgb(&self) -> Option<std::ops::Range<u32>> {
         let cap = self.capability_by_instance("segmentedColorRgb")?;
@@ -879,6 +983,55 @@ impl DeviceCapability {
             _ => None,
         }
     }
+
+    /// Returns the states that an event capability can report
+    pub fn event_options(&self) -> Vec<EventOption> {
+        self.event_state
+            .as_ref()
+            .and_then(|state| state.get("options"))
+            .and_then(|options| serde_json::from_value(options.clone()).ok())
+            .unwrap_or_default()
+    }
+
+    /// Interprets the state of an event capability, as reported either
+    /// by polling or by a push message, returning the matching option
+    /// if the event is currently active
+    pub fn active_event_option(&self, state: &JsonValue) -> Option<EventOption> {
+        fn event_value(state: &JsonValue) -> Option<&JsonValue> {
+            match state {
+                JsonValue::Null => None,
+                JsonValue::String(s) if s.is_empty() => None,
+                JsonValue::Object(map) => event_value(map.get("value")?),
+                // Push messages list the options that are active
+                JsonValue::Array(items) => items.iter().find_map(event_value),
+                value => Some(value),
+            }
+        }
+
+        let value = event_value(state)?;
+        let options = self.event_options();
+        if options.is_empty() {
+            // Without a list of options, treat any non-zero value as active
+            let active =
+                value.as_bool().unwrap_or(false) || value.as_i64().map(|v| v != 0).unwrap_or(false);
+            return active.then(|| EventOption {
+                name: self.instance.to_string(),
+                value: value.clone(),
+                message: None,
+            });
+        }
+        options.into_iter().find(|option| option.value == *value)
+    }
+}
+
+/// One of the states reported by an event capability, such as
+/// the `lack` state of `lackWaterEvent`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct EventOption {
+    pub name: String,
+    pub value: JsonValue,
+    #[serde(default)]
+    pub message: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -1071,17 +1224,29 @@ pub async fn http_response_body<R: serde::de::DeserializeOwned>(
 }
 
 impl GoveeApiClient {
+    /// Accounts for a request against the daily limit. Requests
+    /// to a non-default server, such as the mock, are not limited.
+    fn acquire_quota(&self, priority: RequestPriority) -> anyhow::Result<()> {
+        if self.base_url != SERVER {
+            return Ok(());
+        }
+        self.quota().acquire(priority)
+    }
+
     async fn get_request_with_json_response<T: reqwest::IntoUrl, R: serde::de::DeserializeOwned>(
         &self,
         url: T,
+        priority: RequestPriority,
     ) -> anyhow::Result<R> {
-        let response = reqwest::Client::builder()
+        let request = reqwest::Client::builder()
             .timeout(Duration::from_secs(60))
             .build()?
             .request(Method::GET, url)
-            .header("Govee-API-Key", &self.key)
-            .send()
-            .await?;
+            .header("Govee-API-Key", &self.key);
+        let response =
+            send_with_breaker_after(&self.upstream(), request, || self.acquire_quota(priority))
+                .await?;
+        crate::metrics::platform_request(response.status());
 
         http_response_body(response).await
     }
@@ -1095,29 +1260,89 @@ impl GoveeApiClient {
         method: Method,
         url: T,
         body: &B,
+        priority: RequestPriority,
     ) -> anyhow::Result<R> {
-        let response = reqwest::Client::builder()
+        let request = reqwest::Client::builder()
             .timeout(Duration::from_secs(60))
             .build()?
             .request(method, url)
             .header("Govee-API-Key", &self.key)
-            .json(body)
-            .send()
-            .await?;
+            .json(body);
+        let response =
+            send_with_breaker_after(&self.upstream(), request, || self.acquire_quota(priority))
+                .await?;
+        crate::metrics::platform_request(response.status());
 
         http_response_body(response).await
     }
 }
 
+/// Returns the name by which a scene option is presented to the user.
+/// DIY scenes are created by the user in the Govee app and can share
+/// names with the built-in scenes, so they are labelled to tell them apart.
+pub fn scene_option_label(cap: &DeviceCapability, name: &str) -> String {
+    if cap.instance == "diyScene" {
+        format!("DIY: {name}")
+    } else {
+        name.to_string()
+    }
+}
+
+fn scene_names_from_caps(caps: &[DeviceCapability]) -> anyhow::Result<Vec<String>> {
+    let mut result = vec![];
+    for cap in caps {
+        match &cap.parameters {
+            Some(DeviceParameters::Enum { options }) => {
+                for opt in options {
+                    result.push(scene_option_label(cap, &opt.name));
+                }
+            }
+            _ => anyhow::bail!("list_scene_names: unexpected type {cap:#?}"),
+        }
+    }
+    Ok(result)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
     const SCENE_LIST: &str = include_str!("../test-data/scenes.json");
 
+    #[test]
+    fn account_cache_keys() {
+        let primary = GoveeApiClient::new("key");
+        assert_eq!(primary.cache_key("device-list"), "device-list");
+
+        let cabin = GoveeApiClient::new("key").with_account("cabin");
+        assert_eq!(cabin.cache_key("device-list"), "cabin:device-list");
+
+        let mock = cabin.with_base_url("http://127.0.0.1:1234");
+        assert_eq!(
+            mock.cache_key("device-list"),
+            "cabin:device-list@http://127.0.0.1:1234"
+        );
+    }
+
+    #[test]
+    fn diy_scene_names() {
+        let resp: GetDevicesResponse = from_json(LIST_DEVICES_EXAMPLE).unwrap();
+        let device = &resp.data[0];
+        let caps: Vec<DeviceCapability> = device
+            .capabilities
+            .iter()
+            .filter(|cap| cap.kind == DeviceCapabilityKind::DynamicScene)
+            .filter(|cap| matches!(cap.parameters, Some(DeviceParameters::Enum { .. })))
+            .cloned()
+            .collect();
+        let names = scene_names_from_caps(&caps).unwrap();
+        assert!(names.contains(&"DIY: Fade".to_string()), "{names:?}");
+        assert!(!names.contains(&"Fade".to_string()), "{names:?}");
+    }
+
     #[test]
     fn get_device_scenes() {
-        let resp: GetDeviceScenesResponse = from_json(&SCENE_LIST).unwrap();
+        let resp: GetDeviceScenesResponse = from_json(SCENE_LIST).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
@@ -1125,7 +1350,7 @@ mod test {
 
     #[test]
     fn get_device_state() {
-        let resp: GetDeviceStateResponse = from_json(&GET_DEVICE_STATE_EXAMPLE).unwrap();
+        let resp: GetDeviceStateResponse = from_json(GET_DEVICE_STATE_EXAMPLE).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
@@ -1135,19 +1360,42 @@ mod test {
     #[test]
     fn list_devices_issue4() {
         let resp: GetDevicesResponse =
-            from_json(&include_str!("../test-data/list_devices_issue4.json")).unwrap();
+            from_json(include_str!("../test-data/list_devices_issue4.json")).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
+    #[test]
+    fn event_states() {
+        let resp: GetDevicesResponse =
+            from_json(include_str!("../test-data/list_devices_issue4.json")).unwrap();
+        let cap = resp
+            .data
+            .iter()
+            .find_map(|d| d.capability_by_instance("lackWaterEvent"))
+            .unwrap();
+        assert_eq!(cap.event_options()[0].name, "lack");
+
+        // As reported by polling
+        let active = cap.active_event_option(&json!({"value": 1})).unwrap();
+        assert_eq!(active.message.as_deref(), Some("Lack of Water"));
+        assert_eq!(cap.active_event_option(&json!({"value": 0})), None);
+        assert_eq!(cap.active_event_option(&json!({"value": ""})), None);
+
+        // As reported by a push message
+        let pushed = json!([{"name": "lack", "value": 1, "message": "Lack of Water"}]);
+        assert_eq!(cap.active_event_option(&pushed), Some(active));
+        assert_eq!(cap.active_event_option(&json!([])), None);
+    }
+
     #[test]
     fn list_devices_2() {
-        let resp: GetDevicesResponse = from_json(&LIST_DEVICES_EXAMPLE2).unwrap();
+        let resp: GetDevicesResponse = from_json(LIST_DEVICES_EXAMPLE2).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
     #[test]
     fn list_devices() {
-        let resp: GetDevicesResponse = from_json(&LIST_DEVICES_EXAMPLE).unwrap();
+        let resp: GetDevicesResponse = from_json(LIST_DEVICES_EXAMPLE).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
@@ -0,0 +1,378 @@
+//! A local stand-in for the Govee Platform API.
+//! It serves device lists, device state and scene lists from the JSON
+//! files in a directory (by default, the `test-data` directory in this
+//! repo) and applies `control` requests to its in-memory device state,
+//! so that the platform API integration can be exercised without an
+//! API key or network access.
+use crate::platform_api::{
+    from_json, ControlDeviceRequest, ControlDeviceResponse, ControlDeviceResponseCapability,
+    DeviceCapabilityKind, DeviceCapabilityState, DeviceParameters, GetDeviceScenesRequest,
+    GetDeviceScenesResponse, GetDeviceScenesResponsePayload, GetDeviceStateRequest,
+    GetDeviceStateResponse, GetDevicesResponse, HttpDeviceInfo, HttpDeviceState,
+};
+use anyhow::Context;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use parking_lot::Mutex;
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+type DeviceKey = (String, String);
+
+#[derive(Default)]
+pub struct MockPlatformApi {
+    devices: Vec<HttpDeviceInfo>,
+    states: Mutex<HashMap<DeviceKey, HttpDeviceState>>,
+    scenes_by_sku: HashMap<String, GetDeviceScenesResponsePayload>,
+    diy_scenes_by_sku: HashMap<String, GetDeviceScenesResponsePayload>,
+}
+
+pub type MockPlatformApiHandle = Arc<MockPlatformApi>;
+
+fn key(sku: &str, device: &str) -> DeviceKey {
+    (sku.to_string(), device.to_string())
+}
+
+/// Synthesize a plausible state for a device that we have
+/// no recorded state for
+fn initial_state(info: &HttpDeviceInfo) -> HttpDeviceState {
+    let mut capabilities = vec![DeviceCapabilityState {
+        kind: DeviceCapabilityKind::Online,
+        instance: "online".to_string(),
+        state: json!({"value": true}),
+    }];
+
+    for cap in &info.capabilities {
+        let value = match &cap.parameters {
+            Some(DeviceParameters::Enum { options }) => options.first().map(|o| o.value.clone()),
+            Some(DeviceParameters::Integer { range, .. }) => Some(json!(range.min)),
+            _ => None,
+        };
+        if let Some(value) = value {
+            capabilities.push(DeviceCapabilityState {
+                kind: cap.kind.clone(),
+                instance: cap.instance.to_string(),
+                state: json!({"value": value}),
+            });
+        }
+    }
+
+    HttpDeviceState {
+        sku: info.sku.to_string(),
+        device: info.device.to_string(),
+        capabilities,
+    }
+}
+
+impl MockPlatformApi {
+    /// Load the mock data from `dir`. The following files are used:
+    ///
+    /// * `list_devices*.json` - device list responses
+    /// * `get_device_state*.json` - device state responses
+    /// * `scenes*.json` - scene list responses; served for all devices of the same SKU
+    /// * `diy_scenes*.json` - DIY scene list responses; served for all devices of the same SKU
+    pub fn load(dir: &Path) -> anyhow::Result<Self> {
+        let mut mock = Self::default();
+        let mut states = HashMap::new();
+
+        let mut entries = std::fs::read_dir(dir)
+            .with_context(|| format!("reading {dir:?}"))?
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !name.ends_with(".json") {
+                continue;
+            }
+            let path = entry.path();
+            let load = || -> anyhow::Result<Vec<u8>> {
+                std::fs::read(&path).with_context(|| format!("reading {path:?}"))
+            };
+
+            if name.starts_with("list_devices") {
+                let resp: GetDevicesResponse =
+                    from_json(load()?).with_context(|| format!("parsing {path:?}"))?;
+                for info in resp.data {
+                    // The test data has some redacted, duplicate, ids
+                    if !mock
+                        .devices
+                        .iter()
+                        .any(|d| d.sku == info.sku && d.device == info.device)
+                    {
+                        mock.devices.push(info);
+                    }
+                }
+            } else if name.starts_with("get_device_state") {
+                let resp: GetDeviceStateResponse =
+                    from_json(load()?).with_context(|| format!("parsing {path:?}"))?;
+                states.insert(key(&resp.payload.sku, &resp.payload.device), resp.payload);
+            } else if name.starts_with("scenes") || name.starts_with("diy_scenes") {
+                let resp: GetDeviceScenesResponse =
+                    from_json(load()?).with_context(|| format!("parsing {path:?}"))?;
+                let target = if name.starts_with("diy") {
+                    &mut mock.diy_scenes_by_sku
+                } else {
+                    &mut mock.scenes_by_sku
+                };
+                target.insert(resp.payload.sku.to_string(), resp.payload);
+            }
+        }
+
+        for info in &mock.devices {
+            states
+                .entry(key(&info.sku, &info.device))
+                .or_insert_with(|| initial_state(info));
+        }
+        mock.states = Mutex::new(states);
+
+        Ok(mock)
+    }
+
+    pub fn devices(&self) -> &[HttpDeviceInfo] {
+        &self.devices
+    }
+
+    /// Returns the current state of the specified device
+    pub fn device_state(&self, sku: &str, device: &str) -> Option<HttpDeviceState> {
+        self.states.lock().get(&key(sku, device)).cloned()
+    }
+
+    fn control(&self, request: ControlDeviceRequest) -> anyhow::Result<ControlDeviceResponse> {
+        let payload = request.payload;
+        let mut states = self.states.lock();
+        let state = states
+            .get_mut(&key(&payload.sku, &payload.device))
+            .ok_or_else(|| anyhow::anyhow!("device {} not found", payload.device))?;
+
+        let cap = payload.capability;
+        let value = json!({"value": cap.value.clone()});
+        match state
+            .capabilities
+            .iter_mut()
+            .find(|c| c.instance == cap.instance)
+        {
+            Some(existing) => {
+                existing.state = value;
+            }
+            None => {
+                state.capabilities.push(DeviceCapabilityState {
+                    kind: cap.kind.clone(),
+                    instance: cap.instance.to_string(),
+                    state: value,
+                });
+            }
+        }
+
+        Ok(ControlDeviceResponse {
+            request_id: request.request_id,
+            code: 200,
+            message: "success".to_string(),
+            capability: ControlDeviceResponseCapability {
+                kind: cap.kind,
+                instance: cap.instance,
+                value: cap.value,
+                state: json!({"status": "success"}),
+            },
+        })
+    }
+
+    /// Serve the mock API on `addr`, returning the address that
+    /// it is bound to, which is useful when binding to port 0
+    pub async fn serve(self: Arc<Self>, addr: SocketAddr) -> anyhow::Result<SocketAddr> {
+        let app = Router::new()
+            .route("/router/api/v1/user/devices", get(list_devices))
+            .route("/router/api/v1/device/state", post(device_state))
+            .route("/router/api/v1/device/scenes", post(device_scenes))
+            .route("/router/api/v1/device/diy-scenes", post(device_diy_scenes))
+            .route("/router/api/v1/device/control", post(device_control))
+            .with_state(self);
+
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("binding to {addr}"))?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            if let Err(err) = axum::serve(listener, app).await {
+                log::error!("mock platform api server stopped: {err:#}");
+            }
+        });
+        Ok(addr)
+    }
+}
+
+fn error_response(code: StatusCode, message: String) -> Response {
+    log::error!("{message}");
+    let mut response = Json(json!({
+        "code": code.as_u16(),
+        "msg": message,
+    }))
+    .into_response();
+    *response.status_mut() = code;
+    response
+}
+
+async fn list_devices(State(mock): State<MockPlatformApiHandle>) -> Response {
+    Json(GetDevicesResponse {
+        code: 200,
+        message: "success".to_string(),
+        data: mock.devices.clone(),
+    })
+    .into_response()
+}
+
+async fn device_state(
+    State(mock): State<MockPlatformApiHandle>,
+    Json(request): Json<GetDeviceStateRequest>,
+) -> Response {
+    let payload = request.payload;
+    match mock.device_state(&payload.sku, &payload.device) {
+        Some(state) => Json(GetDeviceStateResponse {
+            request_id: request.request_id,
+            code: 200,
+            message: "success".to_string(),
+            payload: state,
+        })
+        .into_response(),
+        None => error_response(
+            StatusCode::BAD_REQUEST,
+            format!("device {} not found", payload.device),
+        ),
+    }
+}
+
+fn scenes_response(
+    scenes_by_sku: &HashMap<String, GetDeviceScenesResponsePayload>,
+    request: GetDeviceScenesRequest,
+) -> Response {
+    let payload = request.payload;
+    let capabilities = scenes_by_sku
+        .get(&payload.sku)
+        .map(|scenes| scenes.capabilities.clone())
+        .unwrap_or_default();
+    Json(GetDeviceScenesResponse {
+        request_id: request.request_id,
+        code: 200,
+        message: "success".to_string(),
+        payload: GetDeviceScenesResponsePayload {
+            sku: payload.sku,
+            device: payload.device,
+            capabilities,
+        },
+    })
+    .into_response()
+}
+
+async fn device_scenes(
+    State(mock): State<MockPlatformApiHandle>,
+    Json(request): Json<GetDeviceScenesRequest>,
+) -> Response {
+    scenes_response(&mock.scenes_by_sku, request)
+}
+
+async fn device_diy_scenes(
+    State(mock): State<MockPlatformApiHandle>,
+    Json(request): Json<GetDeviceScenesRequest>,
+) -> Response {
+    scenes_response(&mock.diy_scenes_by_sku, request)
+}
+
+async fn device_control(
+    State(mock): State<MockPlatformApiHandle>,
+    Json(request): Json<ControlDeviceRequest>,
+) -> Response {
+    log::info!("control {request:?}");
+    match mock.control(request) {
+        Ok(response) => Json(response).into_response(),
+        Err(err) => error_response(StatusCode::BAD_REQUEST, format!("{err:#}")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::GoveeApiClient;
+
+    #[tokio::test]
+    async fn mock_platform_api() {
+        let mock = Arc::new(
+            MockPlatformApi::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data"))
+                .unwrap(),
+        );
+        let addr = mock
+            .clone()
+            .serve("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let client = GoveeApiClient::new("mock").with_base_url(format!("http://{addr}"));
+
+        let info = mock
+            .devices()
+            .iter()
+            .find(|d| d.sku == "H6072")
+            .unwrap()
+            .clone();
+
+        client.set_power_state(&info, true).await.unwrap();
+        client.set_brightness(&info, 42).await.unwrap();
+
+        let state = client.get_device_state(&info).await.unwrap();
+        let value = |instance| {
+            state
+                .capability_by_instance(instance)
+                .map(|c| c.state["value"].clone())
+        };
+        assert_eq!(value("online"), Some(json!(true)));
+        assert_eq!(value("powerSwitch"), Some(json!(1)));
+        assert_eq!(value("brightness"), Some(json!(42)));
+
+        let err = client
+            .get_device_state(&HttpDeviceInfo {
+                sku: "H0000".to_string(),
+                device: "00:00".to_string(),
+                device_name: String::new(),
+                device_type: Default::default(),
+                capabilities: vec![],
+            })
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("device 00:00 not found"));
+    }
+
+    #[tokio::test]
+    async fn diy_scene_by_unlabelled_name() {
+        let mock = Arc::new(
+            MockPlatformApi::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("test-data"))
+                .unwrap(),
+        );
+        let addr = mock
+            .clone()
+            .serve("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let client = GoveeApiClient::new("mock").with_base_url(format!("http://{addr}"));
+        // Avoid fetching the scene library from Govee
+        crate::undoc_api::GoveeUndocumentedApi::set_scenes_for_device("H6601", vec![])
+            .await
+            .unwrap();
+
+        let info = mock
+            .devices()
+            .iter()
+            .find(|d| d.sku == "H6601")
+            .unwrap()
+            .clone();
+
+        // The name is reported as it appears in the effect list
+        let name = client.set_scene_by_name(&info, "fade").await.unwrap();
+        assert_eq!(name, "DIY: Fade");
+        let name = client.set_scene_by_name(&info, "DIY: Fade").await.unwrap();
+        assert_eq!(name, "DIY: Fade");
+    }
+}
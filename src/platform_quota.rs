@@ -0,0 +1,255 @@
+//! Accounting for the daily request limit of the Govee Platform API.
+//!
+//! Requests are counted in hourly buckets that cover a rolling day,
+//! and the counts are persisted in the cache so that restarting the
+//! service doesn't forget about the requests that it has already made.
+//! A portion of the limit is held back for control commands, so that
+//! background polling cannot prevent the user from controlling devices.
+use crate::cache::{cache_lookup, cache_put};
+use crate::config::config;
+use crate::opt_env_var;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The documented daily request limit of the Platform API
+pub const DEFAULT_DAILY_LIMIT: u32 = 10_000;
+/// The percentage of the daily limit that is reserved for control commands
+const CONTROL_RESERVE_PERCENT: u32 = 10;
+const QUOTA_TOPIC: &str = "platform-quota";
+const HOURS_PER_DAY: i64 = 24;
+
+static QUOTAS: Lazy<Mutex<HashMap<String, Arc<PlatformQuota>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestPriority {
+    /// Polling, device and scene lists
+    Background,
+    /// Commands initiated by the user
+    Control,
+}
+
+/// Returns the configured daily request limit
+pub fn daily_limit() -> u32 {
+    match opt_env_var("GOVEE_API_DAILY_LIMIT") {
+        Ok(Some(limit)) => limit,
+        Ok(None) => config()
+            .govee
+            .api_daily_limit
+            .unwrap_or(DEFAULT_DAILY_LIMIT),
+        Err(err) => {
+            log::error!("{err:#}, using the default limit of {DEFAULT_DAILY_LIMIT}");
+            DEFAULT_DAILY_LIMIT
+        }
+    }
+}
+
+/// Returns the quota tracker for the cache key `key`, loading
+/// its prior state from the cache on first use
+pub fn quota_for(account: Option<&str>, key: &str) -> Arc<PlatformQuota> {
+    QUOTAS
+        .lock()
+        .entry(key.to_string())
+        .or_insert_with(|| {
+            let buckets = match cache_lookup(QUOTA_TOPIC, key) {
+                Ok(buckets) => buckets.unwrap_or_default(),
+                Err(err) => {
+                    log::warn!("Failed to load platform API quota {key}: {err:#}");
+                    QuotaBuckets::default()
+                }
+            };
+            Arc::new(PlatformQuota {
+                key: key.to_string(),
+                account: account.map(|a| a.to_string()),
+                limit: daily_limit(),
+                buckets: Mutex::new(buckets),
+            })
+        })
+        .clone()
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+struct QuotaBuckets {
+    /// Number of requests, keyed by the hour in which they were
+    /// made, expressed as hours since the unix epoch
+    by_hour: BTreeMap<i64, u32>,
+}
+
+fn hour_of(now: DateTime<Utc>) -> i64 {
+    now.timestamp().div_euclid(3600)
+}
+
+impl QuotaBuckets {
+    /// Discards the buckets that are no longer part of the rolling day
+    fn prune(&mut self, now: DateTime<Utc>) {
+        let oldest = hour_of(now) - HOURS_PER_DAY + 1;
+        self.by_hour.retain(|&hour, _| hour >= oldest);
+    }
+
+    fn used(&self, now: DateTime<Utc>) -> u32 {
+        let oldest = hour_of(now) - HOURS_PER_DAY + 1;
+        self.by_hour.range(oldest..).map(|(_, &count)| count).sum()
+    }
+}
+
+/// A snapshot of the state of a quota, as reported via HTTP and MQTT
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct QuotaStatus {
+    pub account: Option<String>,
+    pub limit: u32,
+    pub used: u32,
+    pub remaining: u32,
+    /// The number of requests that are reserved for control commands
+    pub reserve: u32,
+    /// How much slower than usual devices are polled
+    pub poll_interval_multiplier: i32,
+}
+
+#[derive(Debug)]
+pub struct PlatformQuota {
+    key: String,
+    account: Option<String>,
+    limit: u32,
+    buckets: Mutex<QuotaBuckets>,
+}
+
+impl PlatformQuota {
+    fn reserve(&self) -> u32 {
+        self.limit * CONTROL_RESERVE_PERCENT / 100
+    }
+
+    fn remaining_at(&self, now: DateTime<Utc>) -> u32 {
+        self.limit.saturating_sub(self.buckets.lock().used(now))
+    }
+
+    /// Accounts for a request that is about to be made, or returns
+    /// an error if the remaining budget doesn't permit it
+    pub fn acquire(&self, priority: RequestPriority) -> anyhow::Result<()> {
+        self.acquire_at(priority, Utc::now())?;
+
+        let buckets = self.buckets.lock().clone();
+        if let Err(err) = cache_put(
+            QUOTA_TOPIC,
+            &self.key,
+            &buckets,
+            Duration::from_secs(2 * 86400),
+        ) {
+            log::warn!("Failed to persist platform API quota {}: {err:#}", self.key);
+        }
+        Ok(())
+    }
+
+    fn acquire_at(&self, priority: RequestPriority, now: DateTime<Utc>) -> anyhow::Result<()> {
+        let mut buckets = self.buckets.lock();
+        buckets.prune(now);
+
+        let remaining = self.limit.saturating_sub(buckets.used(now));
+        let needed = match priority {
+            RequestPriority::Background => self.reserve(),
+            RequestPriority::Control => 0,
+        };
+        anyhow::ensure!(
+            remaining > needed,
+            "Platform API daily request budget is exhausted \
+             ({remaining} of {} remaining, {needed} reserved for control); \
+             not making a {priority:?} request",
+            self.limit
+        );
+
+        *buckets.by_hour.entry(hour_of(now)).or_default() += 1;
+        Ok(())
+    }
+
+    /// Returns the factor by which the poll interval should be
+    /// scaled, so that polling slows down as the budget that is
+    /// available for it runs low
+    pub fn poll_interval_multiplier(&self) -> i32 {
+        self.poll_interval_multiplier_at(Utc::now())
+    }
+
+    fn poll_interval_multiplier_at(&self, now: DateTime<Utc>) -> i32 {
+        let reserve = self.reserve();
+        let available = self.remaining_at(now).saturating_sub(reserve) as f64;
+        let budget = self.limit.saturating_sub(reserve).max(1) as f64;
+        match available / budget {
+            f if f > 0.5 => 1,
+            f if f > 0.25 => 2,
+            f if f > 0.1 => 4,
+            _ => 8,
+        }
+    }
+
+    pub fn status(&self) -> QuotaStatus {
+        let now = Utc::now();
+        let remaining = self.remaining_at(now);
+        QuotaStatus {
+            account: self.account.clone(),
+            limit: self.limit,
+            used: self.limit - remaining,
+            remaining,
+            reserve: self.reserve(),
+            poll_interval_multiplier: self.poll_interval_multiplier_at(now),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn quota(limit: u32) -> PlatformQuota {
+        PlatformQuota {
+            key: "test-quota".to_string(),
+            account: None,
+            limit,
+            buckets: Mutex::new(QuotaBuckets::default()),
+        }
+    }
+
+    #[test]
+    fn rolling_day() {
+        let quota = quota(100);
+        let start = Utc.with_ymd_and_hms(2024, 1, 1, 10, 30, 0).unwrap();
+        for _ in 0..30 {
+            quota
+                .acquire_at(RequestPriority::Background, start)
+                .unwrap();
+        }
+        let later = start + chrono::Duration::hours(12);
+        for _ in 0..20 {
+            quota
+                .acquire_at(RequestPriority::Background, later)
+                .unwrap();
+        }
+        assert_eq!(quota.remaining_at(later), 50);
+        assert_eq!(quota.poll_interval_multiplier_at(later), 2);
+
+        // The first batch falls out of the rolling day
+        let next_day = start + chrono::Duration::hours(24);
+        assert_eq!(quota.remaining_at(next_day), 80);
+        assert_eq!(quota.poll_interval_multiplier_at(next_day), 1);
+    }
+
+    #[test]
+    fn control_reserve() {
+        let quota = quota(100);
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 10, 30, 0).unwrap();
+        for _ in 0..90 {
+            quota.acquire_at(RequestPriority::Background, now).unwrap();
+        }
+        assert_eq!(quota.poll_interval_multiplier_at(now), 8);
+        assert!(quota.acquire_at(RequestPriority::Background, now).is_err());
+
+        for _ in 0..10 {
+            quota.acquire_at(RequestPriority::Control, now).unwrap();
+        }
+        assert!(quota.acquire_at(RequestPriority::Control, now).is_err());
+        assert_eq!(quota.remaining_at(now), 0);
+    }
+}
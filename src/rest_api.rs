@@ -1,3 +1,4 @@
+use crate::backoff::{send_with_breaker, REST_API};
 use crate::cache::{cache_get, CacheComputeResult, CacheGetOptions};
 use crate::platform_api::{http_response_body, ONE_WEEK};
 use reqwest::Method;
@@ -142,13 +143,12 @@ impl RestApiClient {
         &self,
         url: T,
     ) -> anyhow::Result<R> {
-        let response = reqwest::Client::builder()
+        let request = reqwest::Client::builder()
             .timeout(Duration::from_secs(60))
             .build()?
             .request(Method::GET, url)
-            .header("Govee-API-Key", &self.key)
-            .send()
-            .await?;
+            .header("Govee-API-Key", &self.key);
+        let response = send_with_breaker(REST_API, request).await?;
 
         http_response_body(response).await
     }
@@ -163,14 +163,13 @@ impl RestApiClient {
         url: T,
         body: &B,
     ) -> anyhow::Result<R> {
-        let response = reqwest::Client::builder()
+        let request = reqwest::Client::builder()
             .timeout(Duration::from_secs(60))
             .build()?
             .request(method, url)
             .header("Govee-API-Key", &self.key)
-            .json(body)
-            .send()
-            .await?;
+            .json(body);
+        let response = send_with_breaker(REST_API, request).await?;
 
         http_response_body(response).await
     }
@@ -250,14 +249,14 @@ mod test {
     #[test]
     fn list_devices() {
         let resp: GetDevicesResponse =
-            from_json(&include_str!("../test-data/rest-list-devices.json")).unwrap();
+            from_json(include_str!("../test-data/rest-list-devices.json")).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
     #[test]
     fn list_appliances() {
         let resp: GetDevicesResponse =
-            from_json(&include_str!("../test-data/rest-appliances.json")).unwrap();
+            from_json(include_str!("../test-data/rest-appliances.json")).unwrap();
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 }
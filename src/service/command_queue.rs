@@ -0,0 +1,107 @@
+//! Coalescing of control commands that arrive faster than a device
+//! can accept them.
+//! When eg: a brightness slider is dragged in HASS, a burst of commands
+//! is sent for the same device. Rather than delivering each of the stale
+//! intermediate commands in turn, pending commands are merged so that
+//! only the latest desired state is sent once the device is free.
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+
+/// A command that can absorb a newer command for the same device
+pub trait Coalesce: Sized {
+    /// Merges `newer` into `self`, returning it unchanged if the
+    /// two commands must be sent separately
+    fn coalesce(&mut self, newer: Self) -> Option<Self>;
+}
+
+pub struct CommandQueue<T> {
+    pending: Mutex<HashMap<String, VecDeque<T>>>,
+}
+
+impl<T> Default for CommandQueue<T> {
+    fn default() -> Self {
+        Self {
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Coalesce> CommandQueue<T> {
+    /// Adds `command` to the queue for `device_id`, merging it
+    /// into the most recently queued command if possible
+    pub fn push(&self, device_id: &str, command: T) {
+        let mut pending = self.pending.lock();
+        let queue = pending.entry(device_id.to_string()).or_default();
+        let command = match queue.back_mut() {
+            Some(last) => match last.coalesce(command) {
+                Some(command) => command,
+                None => return,
+            },
+            None => command,
+        };
+        queue.push_back(command);
+    }
+
+    /// Takes the oldest pending command for `device_id`.
+    /// Returns None if it was already taken by a task that
+    /// was handling an earlier command.
+    pub fn pop(&self, device_id: &str) -> Option<T> {
+        let mut pending = self.pending.lock();
+        let queue = pending.get_mut(device_id)?;
+        let command = queue.pop_front();
+        if queue.is_empty() {
+            pending.remove(device_id);
+        }
+        command
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Command {
+        on: bool,
+        brightness: Option<u8>,
+        color: Option<u32>,
+    }
+
+    impl Coalesce for Command {
+        fn coalesce(&mut self, newer: Self) -> Option<Self> {
+            if self.on != newer.on {
+                return Some(newer);
+            }
+            self.brightness = newer.brightness.or(self.brightness);
+            self.color = newer.color.or(self.color);
+            None
+        }
+    }
+
+    fn cmd(on: bool, brightness: Option<u8>, color: Option<u32>) -> Command {
+        Command {
+            on,
+            brightness,
+            color,
+        }
+    }
+
+    #[test]
+    fn coalescing() {
+        let queue = CommandQueue::default();
+        for brightness in 1..=50 {
+            queue.push("a", cmd(true, Some(brightness), None));
+        }
+        queue.push("a", cmd(true, None, Some(0xff0000)));
+        queue.push("a", cmd(false, None, None));
+        queue.push("a", cmd(true, Some(20), None));
+        queue.push("b", cmd(false, None, None));
+
+        assert_eq!(queue.pop("a"), Some(cmd(true, Some(50), Some(0xff0000))));
+        assert_eq!(queue.pop("a"), Some(cmd(false, None, None)));
+        assert_eq!(queue.pop("a"), Some(cmd(true, Some(20), None)));
+        assert_eq!(queue.pop("a"), None);
+        assert_eq!(queue.pop("b"), Some(cmd(false, None, None)));
+        assert_eq!(queue.pop("b"), None);
+    }
+}
@@ -1,10 +1,13 @@
 use crate::ble::NotifyHumidifierNightlightParams;
 use crate::commands::serve::POLL_INTERVAL;
+use crate::config::{config, DeviceConfig};
 use crate::lan_api::{DeviceColor, DeviceStatus as LanDeviceStatus, LanDevice};
 use crate::platform_api::{
-    DeviceCapability, DeviceCapabilityState, DeviceType, HttpDeviceInfo, HttpDeviceState,
+    DeviceCapability, DeviceCapabilityKind, DeviceCapabilityState, DeviceType, EventOption,
+    HttpDeviceInfo, HttpDeviceState,
 };
 use crate::service::quirks::{resolve_quirk, Quirk, BULB};
+use crate::service::transport::{Transport, TransportPolicy};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
@@ -16,6 +19,10 @@ pub struct Device {
     pub sku: String,
     pub id: String,
 
+    /// The name of the additional account that owns this device,
+    /// or None if it belongs to the primary account
+    pub account: Option<String>,
+
     /// Probed LAN device information, found either via discovery
     /// or explicit probing by IP address
     pub lan_device: Option<LanDevice>,
@@ -40,10 +47,16 @@ pub struct Device {
     pub target_humidity_percent: Option<u8>,
     pub humidifier_work_mode: Option<u8>,
     pub humidifier_param_by_mode: HashMap<u8, u8>,
+    pub music_settings: MusicSettings,
+    /// The state of each event capability, keyed by its instance name
+    pub event_states: HashMap<String, EventState>,
 
     pub last_polled: Option<DateTime<Utc>>,
 
     active_scene: Option<ActiveSceneInfo>,
+
+    /// The state that was persisted by a prior run of the service
+    restored_state: Option<DeviceState>,
 }
 
 impl std::fmt::Display for Device {
@@ -52,19 +65,44 @@ impl std::fmt::Display for Device {
     }
 }
 
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct EventState {
+    /// The option that is active, or None if the event has cleared
+    pub active: Option<EventOption>,
+    pub updated: DateTime<Utc>,
+}
+
 /// Govee doesn't report the active scene or music mode,
 /// so we maintain our own idea of it, clearing it when
 /// the color of the light is changed
-#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 struct ActiveSceneInfo {
     pub name: String,
     pub color: crate::lan_api::DeviceColor,
     pub kelvin: u32,
 }
 
+/// The parameters used when activating a music mode. Govee
+/// doesn't report these, so we track the values that we last used
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MusicSettings {
+    /// The microphone sensitivity in percent
+    pub sensitivity: u8,
+    pub auto_color: bool,
+}
+
+impl Default for MusicSettings {
+    fn default() -> Self {
+        Self {
+            sensitivity: 100,
+            auto_color: true,
+        }
+    }
+}
+
 /// Represents the device state; synthesized from the various
 /// sources of facts that we have in the Device
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct DeviceState {
     /// Whether the device is powered on
     pub on: bool,
@@ -89,6 +127,55 @@ pub struct DeviceState {
     /// Where the information came from
     pub source: &'static str,
     pub updated: DateTime<Utc>,
+
+    /// True if the state was restored from a prior run of the
+    /// service and hasn't been refreshed from the device since
+    pub stale: bool,
+}
+
+impl DeviceState {
+    /// Returns a short identifier for the API that produced the state
+    pub fn source_kind(&self) -> &'static str {
+        match self.source {
+            "LAN API" => "lan",
+            "AWS IoT API" => "iot",
+            "PLATFORM API" => "platform",
+            _ => "restored",
+        }
+    }
+
+    /// Returns true if `other` differs from this state in any
+    /// respect other than the time at which it was updated
+    pub fn differs_from(&self, other: &Self) -> bool {
+        let mut other = other.clone();
+        other.updated = self.updated;
+        *self != other
+    }
+}
+
+/// The subset of the Device that is saved across restarts
+/// of the service; see Device::persisted_state
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PersistedDeviceState {
+    state: Option<PersistedStateValues>,
+    active_scene: Option<ActiveSceneInfo>,
+    nightlight_state: Option<NotifyHumidifierNightlightParams>,
+    target_humidity_percent: Option<u8>,
+    humidifier_work_mode: Option<u8>,
+    humidifier_param_by_mode: HashMap<u8, u8>,
+    #[serde(default)]
+    music_settings: MusicSettings,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct PersistedStateValues {
+    on: bool,
+    light_on: Option<bool>,
+    online: Option<bool>,
+    kelvin: u32,
+    color: DeviceColor,
+    brightness: u8,
+    updated: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone)]
@@ -109,13 +196,17 @@ impl Device {
         }
     }
 
-    /// Returns the device name; either the name defined in the Govee App,
+    /// Returns the device name; either the name configured for the device
+    /// in the config file, the name defined in the Govee App,
     /// or, if we don't have the information for some reason, then we compute
     /// a name from the SKU and the last couple of bytes from the device id,
     /// similar to the device name that would show up in a BLE scan, or
     /// the default name for the device if not otherwise configured in the
     /// Govee App.
     pub fn name(&self) -> String {
+        if let Some(name) = self.config_override(|d| d.name.as_deref()) {
+            return name.to_string();
+        }
         if let Some(name) = self.govee_name() {
             return name.to_string();
         }
@@ -130,13 +221,43 @@ impl Device {
         None
     }
 
+    /// Returns the room name; either the room configured for the device
+    /// in the config file, or the room defined in the Govee App
     pub fn room_name(&self) -> Option<&str> {
+        if let Some(room) = self.config_override(|d| d.room.as_deref()) {
+            return Some(room);
+        }
         if let Some(info) = &self.undoc_device_info {
             return info.room_name.as_deref();
         }
         None
     }
 
+    /// Returns the first value selected by `f` from the config file
+    /// sections that apply to this device
+    fn config_override<T: ?Sized>(&self, f: impl Fn(&DeviceConfig) -> Option<&T>) -> Option<&T> {
+        config().device_sections(&self.sku, &self.id).find_map(f)
+    }
+
+    /// Returns the icon configured for this device in the config file
+    pub fn icon_override(&self) -> Option<&str> {
+        self.config_override(|d| d.icon.as_deref())
+    }
+
+    /// Returns the policy that determines which transports
+    /// may be used to control and poll this device
+    pub fn transport_policy(&self) -> TransportPolicy {
+        self.config_override(|d| d.transport.as_ref())
+            .copied()
+            .unwrap_or_else(TransportPolicy::global_default)
+    }
+
+    /// Returns true if the config file indicates that this device
+    /// should not be exposed
+    pub fn is_hidden(&self) -> bool {
+        self.config_override(|d| d.hidden.as_ref()).copied() == Some(true)
+    }
+
     /// compute a name from the SKU and the last couple of bytes from the
     /// device id, similar to the device name that would show up in a BLE
     /// scan, or the default name for the device if not otherwise configured
@@ -221,11 +342,47 @@ impl Device {
     }
 
     pub fn set_http_device_state(&mut self, state: HttpDeviceState) {
+        for cap in &state.capabilities {
+            if cap.kind == DeviceCapabilityKind::Event {
+                self.set_event_state(&cap.instance, &cap.state);
+            }
+        }
         self.http_device_state.replace(state);
         self.last_http_device_state_update.replace(Utc::now());
         self.clear_scene_if_color_changed();
     }
 
+    /// Records the state reported for the event capability `instance`.
+    /// Returns false if the device has no such event.
+    pub fn set_event_state(&mut self, instance: &str, state: &serde_json::Value) -> bool {
+        let Some(cap) = self.get_capability_by_instance(instance) else {
+            return false;
+        };
+        if cap.kind != DeviceCapabilityKind::Event {
+            return false;
+        }
+        let active = cap.active_event_option(state);
+        let instance = cap.instance.to_string();
+        let prior = self
+            .event_states
+            .get(&instance)
+            .and_then(|s| s.active.as_ref());
+        if prior != active.as_ref() {
+            match &active {
+                Some(option) => log::info!("{self}: {instance} is now {}", option.name),
+                None => log::info!("{self}: {instance} has cleared"),
+            }
+        }
+        self.event_states.insert(
+            instance,
+            EventState {
+                active,
+                updated: Utc::now(),
+            },
+        );
+        true
+    }
+
     pub fn set_undoc_device_info(
         &mut self,
         entry: crate::undoc_api::DeviceEntry,
@@ -257,6 +414,7 @@ impl Device {
             scene: self.active_scene.as_ref().map(|info| info.name.to_string()),
             source: "AWS IoT API",
             updated,
+            stale: false,
         })
     }
 
@@ -274,6 +432,7 @@ impl Device {
             scene: self.active_scene.as_ref().map(|info| info.name.to_string()),
             source: "LAN API",
             updated,
+            stale: false,
         })
     }
 
@@ -302,7 +461,6 @@ impl Device {
         for cap in &state.capabilities {
             if let Ok(value) = serde_json::from_value::<IntegerValueState>(cap.state.clone()) {
                 if light_instance
-                    .as_deref()
                     .map(|inst| inst == cap.instance.as_str())
                     .unwrap_or(false)
                 {
@@ -345,6 +503,7 @@ impl Device {
             scene: self.active_scene.as_ref().map(|info| info.name.to_string()),
             source: "PLATFORM API",
             updated,
+            stale: false,
         })
     }
 
@@ -361,12 +520,33 @@ impl Device {
         if let Some(state) = self.compute_iot_device_state() {
             candidates.push(state);
         }
+        if let Some(state) = &self.restored_state {
+            let mut state = state.clone();
+            state.scene = self.active_scene.as_ref().map(|info| info.name.to_string());
+            candidates.push(state);
+        }
+
+        // Ignore state from transports that this device may not use
+        let policy = self.transport_policy();
+        candidates.retain(|state| {
+            Transport::from_state_source(state.source)
+                .map(|transport| policy.allows(transport))
+                .unwrap_or(true)
+        });
 
-        candidates.sort_by(|a, b| a.updated.cmp(&b.updated));
+        candidates.sort_by_key(|a| a.updated);
 
         candidates.pop()
     }
 
+    /// Returns the name of the music mode that we believe
+    /// to be active, if any
+    pub fn active_music_mode(&self) -> Option<&str> {
+        self.active_scene
+            .as_ref()
+            .and_then(|info| info.name.strip_prefix("Music: "))
+    }
+
     /// Records the active scene name
     pub fn set_active_scene(&mut self, scene: Option<&str>) {
         match scene {
@@ -387,6 +567,67 @@ impl Device {
         }
     }
 
+    /// Returns the state that should be saved so that it can
+    /// be restored by a subsequent run of the service
+    pub fn persisted_state(&self) -> PersistedDeviceState {
+        PersistedDeviceState {
+            state: self.device_state().map(|s| PersistedStateValues {
+                on: s.on,
+                light_on: s.light_on,
+                online: s.online,
+                kelvin: s.kelvin,
+                color: s.color,
+                brightness: s.brightness,
+                updated: s.updated,
+            }),
+            active_scene: self.active_scene.clone(),
+            nightlight_state: self.nightlight_state,
+            target_humidity_percent: self.target_humidity_percent,
+            humidifier_work_mode: self.humidifier_work_mode,
+            humidifier_param_by_mode: self.humidifier_param_by_mode.clone(),
+            music_settings: self.music_settings,
+        }
+    }
+
+    /// Apply state that was saved by a prior run of the service.
+    /// The restored device state is marked as stale and retains its
+    /// original timestamp, so any state that we receive from the
+    /// device itself will take precedence over it.
+    /// Facts that we have already learned in this run are not replaced.
+    pub fn restore_persisted_state(&mut self, persisted: PersistedDeviceState) {
+        if let Some(s) = persisted.state {
+            self.restored_state.replace(DeviceState {
+                on: s.on,
+                light_on: s.light_on,
+                online: s.online,
+                kelvin: s.kelvin,
+                color: s.color,
+                brightness: s.brightness,
+                scene: None,
+                source: "RESTORED",
+                updated: s.updated,
+                stale: true,
+            });
+        }
+        if self.active_scene.is_none() {
+            self.active_scene = persisted.active_scene;
+        }
+        if self.nightlight_state.is_none() {
+            self.nightlight_state = persisted.nightlight_state;
+        }
+        if self.target_humidity_percent.is_none() {
+            self.target_humidity_percent = persisted.target_humidity_percent;
+        }
+        if self.humidifier_work_mode.is_none() {
+            self.humidifier_work_mode = persisted.humidifier_work_mode;
+        }
+        for (mode, param) in persisted.humidifier_param_by_mode {
+            self.humidifier_param_by_mode.entry(mode).or_insert(param);
+        }
+        self.music_settings = persisted.music_settings;
+        self.clear_scene_if_color_changed();
+    }
+
     pub fn clear_scene_if_color_changed(&mut self) {
         if let Some(info) = &self.active_scene {
             let current = self
@@ -420,6 +661,12 @@ impl Device {
             return true;
         }
 
+        // Events are pushed via IoT when they occur, but only the
+        // platform API reports that they have cleared
+        if self.has_event_capabilities() {
+            return true;
+        }
+
         let device_type = self.device_type();
         match (device_type, self.sku.as_str()) {
             (_, "H7160") => false,
@@ -439,14 +686,16 @@ impl Device {
             return false;
         }
         let device_type = self.device_type();
-        match (device_type, self.sku.as_str()) {
-            (_, "H7160") => true,
-            (DeviceType::Light, _) => true,
-            _ => false,
-        }
+        matches!(
+            (device_type, self.sku.as_str()),
+            (_, "H7160") | (DeviceType::Light, _)
+        )
     }
 
     pub fn avoid_platform_api(&self) -> bool {
+        if !self.transport_policy().allows(Transport::Platform) {
+            return true;
+        }
         if let Some(quirk) = self.resolve_quirk() {
             if quirk.avoid_platform_api {
                 return true;
@@ -484,6 +733,18 @@ impl Device {
         }
     }
 
+    /// Returns true if the device reports events, such as running out of water
+    pub fn has_event_capabilities(&self) -> bool {
+        self.http_device_info
+            .as_ref()
+            .map(|info| {
+                info.capabilities
+                    .iter()
+                    .any(|cap| cap.kind == DeviceCapabilityKind::Event)
+            })
+            .unwrap_or(false)
+    }
+
     pub fn get_capability_by_instance(&self, instance: &str) -> Option<&DeviceCapability> {
         self.http_device_info
             .as_ref()
@@ -587,19 +848,13 @@ impl Device {
             return Some(false);
         }
 
-        if let Some(info) = &self.undoc_device_info {
-            Some(info.entry.device_ext.device_settings.wifi_name.is_none())
-        } else {
-            // Don't know for sure
-            None
-        }
+        self.undoc_device_info
+            .as_ref()
+            .map(|info| info.entry.device_ext.device_settings.wifi_name.is_none())
     }
 
     pub fn is_controllable(&self) -> bool {
-        match self.is_ble_only_device() {
-            Some(true) => false,
-            _ => true,
-        }
+        !matches!(self.is_ble_only_device(), Some(true))
     }
 }
 
@@ -618,4 +873,75 @@ mod test {
         let device = Device::new("H6127", "ce");
         assert_eq!(device.name(), "H6127_CE");
     }
+
+    #[test]
+    fn persist_and_restore() {
+        let mut device = Device::new("H6072", "AA:BB:CC:DD:EE:FF:42:2A");
+        device.set_lan_device_status(LanDeviceStatus {
+            on: true,
+            brightness: 42,
+            color: DeviceColor { r: 255, g: 0, b: 0 },
+            color_temperature_kelvin: 0,
+        });
+        device.set_active_scene(Some("Sunrise"));
+        device.set_humidifier_work_mode_and_param(1, 3);
+        let updated = device.device_state().unwrap().updated;
+
+        let persisted: PersistedDeviceState =
+            serde_json::from_str(&serde_json::to_string(&device.persisted_state()).unwrap())
+                .unwrap();
+
+        let mut restored = Device::new("H6072", "AA:BB:CC:DD:EE:FF:42:2A");
+        restored.restore_persisted_state(persisted);
+        let state = restored.device_state().unwrap();
+        assert!(state.stale);
+        assert!(state.on);
+        assert_eq!(state.brightness, 42);
+        assert_eq!(state.updated, updated);
+        assert_eq!(state.scene.as_deref(), Some("Sunrise"));
+        assert_eq!(restored.humidifier_work_mode, Some(1));
+        assert_eq!(restored.humidifier_param_by_mode.get(&1), Some(&3));
+
+        // Fresh state from the device takes precedence
+        restored.set_lan_device_status(LanDeviceStatus {
+            on: false,
+            brightness: 42,
+            color: DeviceColor { r: 255, g: 0, b: 0 },
+            color_temperature_kelvin: 0,
+        });
+        let state = restored.device_state().unwrap();
+        assert!(!state.stale);
+        assert!(!state.on);
+        assert_eq!(state.scene.as_deref(), Some("Sunrise"));
+    }
+
+    #[test]
+    fn events_need_platform_poll() {
+        let resp: crate::platform_api::GetDevicesResponse = crate::platform_api::from_json(
+            include_str!("../../test-data/list_devices_issue4.json"),
+        )
+        .unwrap();
+        let mut info = resp
+            .data
+            .into_iter()
+            .find(|info| info.capability_by_instance("lackWaterEvent").is_some())
+            .unwrap();
+        info.sku = "H7160".to_string();
+
+        // The H7160 is otherwise polled via IoT
+        let mut device = Device::new("H7160", &info.device);
+        assert!(!device.needs_platform_poll());
+
+        device.set_http_device_info(info);
+        assert!(device.needs_platform_poll());
+        assert!(device.set_event_state(
+            "lackWaterEvent",
+            &serde_json::json!([{"name": "lack", "value": 1}])
+        ));
+        assert!(device.event_states["lackWaterEvent"].active.is_some());
+
+        // Polling reports that the event has cleared
+        assert!(device.set_event_state("lackWaterEvent", &serde_json::json!({"value": 0})));
+        assert!(device.event_states["lackWaterEvent"].active.is_none());
+    }
 }
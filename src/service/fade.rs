@@ -0,0 +1,129 @@
+//! Server-side transitions for lights that are controlled via the
+//! LAN API, which has no native support for them.
+//! A fade is broken into a bounded number of steps, each of which
+//! sends the interpolated brightness and color to the device.
+use crate::lan_api::DeviceColor;
+use std::time::Duration;
+
+/// The upper bound on the number of commands per second that
+/// a fade sends to a device
+pub const MAX_STEPS_PER_SECOND: f64 = 10.0;
+/// Longer transitions are clamped to this duration
+pub const MAX_TRANSITION: Duration = Duration::from_secs(3600);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FadeColor {
+    Rgb(DeviceColor),
+    Kelvin(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FadePoint {
+    /// The brightness in percent
+    pub brightness: u8,
+    /// The color, or None to leave it unchanged
+    pub color: Option<FadeColor>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Fade {
+    pub from: FadePoint,
+    pub to: FadePoint,
+    pub steps: u32,
+    pub interval: Duration,
+}
+
+fn lerp(from: u32, to: u32, fraction: f64) -> u32 {
+    (from as f64 + (to as f64 - from as f64) * fraction).round() as u32
+}
+
+impl Fade {
+    pub fn new(from: FadePoint, to: FadePoint, duration: Duration) -> Self {
+        let duration = duration.min(MAX_TRANSITION);
+        let steps = ((duration.as_secs_f64() * MAX_STEPS_PER_SECOND).ceil() as u32).max(1);
+        Self {
+            from,
+            to,
+            steps,
+            interval: duration / steps,
+        }
+    }
+
+    /// Returns the point to send for step `n`, which ranges from
+    /// 1 through `steps`; the final step is always the target.
+    pub fn step(&self, n: u32) -> FadePoint {
+        let fraction = (n.min(self.steps) as f64) / self.steps as f64;
+
+        let brightness = lerp(
+            self.from.brightness as u32,
+            self.to.brightness as u32,
+            fraction,
+        ) as u8;
+
+        let color = match (self.from.color, self.to.color) {
+            (Some(FadeColor::Rgb(a)), Some(FadeColor::Rgb(b))) => {
+                Some(FadeColor::Rgb(DeviceColor {
+                    r: lerp(a.r as u32, b.r as u32, fraction) as u8,
+                    g: lerp(a.g as u32, b.g as u32, fraction) as u8,
+                    b: lerp(a.b as u32, b.b as u32, fraction) as u8,
+                }))
+            }
+            (Some(FadeColor::Kelvin(a)), Some(FadeColor::Kelvin(b))) => {
+                Some(FadeColor::Kelvin(lerp(a, b, fraction)))
+            }
+            // We can't smoothly move between color modes, so
+            // switch to the target color right away
+            (_, to) => to,
+        };
+
+        FadePoint { brightness, color }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fade_steps() {
+        let fade = Fade::new(
+            FadePoint {
+                brightness: 0,
+                color: Some(FadeColor::Rgb(DeviceColor { r: 0, g: 0, b: 255 })),
+            },
+            FadePoint {
+                brightness: 100,
+                color: Some(FadeColor::Rgb(DeviceColor { r: 255, g: 0, b: 0 })),
+            },
+            Duration::from_secs(2),
+        );
+        assert_eq!(fade.steps, 20);
+        assert_eq!(fade.interval, Duration::from_millis(100));
+        assert_eq!(
+            fade.step(10),
+            FadePoint {
+                brightness: 50,
+                color: Some(FadeColor::Rgb(DeviceColor {
+                    r: 128,
+                    g: 0,
+                    b: 128
+                })),
+            }
+        );
+        assert_eq!(fade.step(20), fade.to);
+
+        let fade = Fade::new(
+            FadePoint {
+                brightness: 80,
+                color: Some(FadeColor::Rgb(DeviceColor { r: 0, g: 0, b: 255 })),
+            },
+            FadePoint {
+                brightness: 20,
+                color: Some(FadeColor::Kelvin(2700)),
+            },
+            Duration::from_millis(10),
+        );
+        assert_eq!(fade.steps, 1);
+        assert_eq!(fade.step(1), fade.to);
+    }
+}
@@ -0,0 +1,187 @@
+//! Virtual light groups, defined by the `[group.<name>]` sections
+//! of the config file. A group is exposed to Home Assistant as a
+//! single light; commands for it are sent to all of its members
+//! concurrently and its state is aggregated from theirs.
+use crate::config::{config, GroupConfig};
+use crate::lan_api::DeviceColor;
+use crate::service::device::{Device, DeviceState};
+use crate::service::hass::topic_safe_string;
+use crate::service::state::StateHandle;
+
+#[derive(Clone, Copy, Debug)]
+pub struct LightGroup {
+    pub name: &'static str,
+    pub config: &'static GroupConfig,
+}
+
+/// The state of a group, aggregated from the states of its members
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroupState {
+    /// True if any member is on
+    pub on: bool,
+    /// The average brightness of the members that are on
+    pub brightness: u8,
+    /// The color or color temperature of the first member that is on
+    pub color: DeviceColor,
+    pub kelvin: u32,
+    /// The scene, if all of the members that are on have the same scene
+    pub scene: Option<String>,
+}
+
+/// Returns all of the groups defined in the config file
+pub fn light_groups() -> impl Iterator<Item = LightGroup> {
+    config()
+        .group
+        .iter()
+        .map(|(name, config)| LightGroup { name, config })
+}
+
+/// Returns the group whose topic safe name is `name`
+pub fn group_by_topic_name(name: &str) -> Option<LightGroup> {
+    light_groups().find(|g| g.topic_safe_name() == name)
+}
+
+/// Returns the group named `name`, ignoring case
+pub fn group_by_name(name: &str) -> Option<LightGroup> {
+    light_groups().find(|g| g.name.eq_ignore_ascii_case(name))
+}
+
+/// Returns the groups that `device` is a member of
+pub fn groups_for_device(device: &Device) -> impl Iterator<Item = LightGroup> + '_ {
+    light_groups().filter(|g| g.contains(device))
+}
+
+impl LightGroup {
+    pub fn topic_safe_name(&self) -> String {
+        topic_safe_string(self.name)
+    }
+
+    /// Rooms are matched against the effective room of the device,
+    /// so a `room` override in the config file takes precedence over
+    /// the room from the Govee App
+    pub fn contains(&self, device: &Device) -> bool {
+        is_light(device)
+            && self
+                .config
+                .matches(&device.sku, &device.id, device.room_name())
+    }
+
+    /// Returns the members of the group, ordered by name
+    pub async fn members(&self, state: &StateHandle) -> Vec<Device> {
+        let mut members: Vec<Device> = state
+            .devices()
+            .await
+            .into_iter()
+            .filter(|d| self.contains(d))
+            .collect();
+        members.sort_by_key(|d| d.name());
+        members
+    }
+
+    /// Returns the single room that all of the members are in, if any
+    pub fn common_room(members: &[Device]) -> Option<String> {
+        let room = members.first()?.room_name()?;
+        members
+            .iter()
+            .all(|d| d.room_name() == Some(room))
+            .then(|| room.to_string())
+    }
+}
+
+/// Hidden devices are allowed to be members, so that the group
+/// can stand in for them in Home Assistant
+fn is_light(device: &Device) -> bool {
+    device.is_controllable()
+        && (device.supports_rgb()
+            || device.get_color_temperature_range().is_some()
+            || device.supports_brightness())
+}
+
+pub fn aggregate_state(states: &[DeviceState]) -> GroupState {
+    let on: Vec<&DeviceState> = states
+        .iter()
+        .filter(|s| s.light_on.unwrap_or(s.on))
+        .collect();
+
+    let Some(first) = on.first() else {
+        return GroupState {
+            on: false,
+            brightness: 0,
+            color: DeviceColor::default(),
+            kelvin: 0,
+            scene: None,
+        };
+    };
+
+    let total: u32 = on.iter().map(|s| s.brightness as u32).sum();
+    let brightness = (total as f64 / on.len() as f64).round() as u8;
+
+    let scene = first
+        .scene
+        .clone()
+        .filter(|scene| on.iter().all(|s| s.scene.as_ref() == Some(scene)));
+
+    GroupState {
+        on: true,
+        brightness,
+        color: first.color,
+        kelvin: first.kelvin,
+        scene,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Utc;
+
+    fn state(on: bool, brightness: u8, scene: Option<&str>) -> DeviceState {
+        DeviceState {
+            on,
+            light_on: Some(on),
+            online: Some(true),
+            kelvin: 0,
+            color: DeviceColor { r: 255, g: 0, b: 0 },
+            brightness,
+            scene: scene.map(|s| s.to_string()),
+            source: "LAN API",
+            updated: Utc::now(),
+            stale: false,
+        }
+    }
+
+    #[test]
+    fn aggregate() {
+        assert!(!aggregate_state(&[]).on);
+        assert!(!aggregate_state(&[state(false, 100, None)]).on);
+
+        let agg = aggregate_state(&[
+            state(true, 20, Some("Aurora")),
+            state(false, 100, None),
+            state(true, 51, Some("Aurora")),
+        ]);
+        assert!(agg.on);
+        assert_eq!(agg.brightness, 36);
+        assert_eq!(agg.scene.as_deref(), Some("Aurora"));
+
+        let agg = aggregate_state(&[
+            state(true, 20, Some("Aurora")),
+            state(true, 50, Some("Sunset")),
+        ]);
+        assert_eq!(agg.scene, None);
+    }
+
+    #[test]
+    fn membership() {
+        let config = GroupConfig {
+            devices: vec!["H6072".to_string(), "AA:BB:CC:DD:EE:FF:00:01".to_string()],
+            rooms: vec!["Living Room".to_string()],
+            icon: None,
+        };
+        assert!(config.matches("H6072", "11:22", None));
+        assert!(config.matches("H6199", "aa:bb:cc:dd:ee:ff:00:01", None));
+        assert!(config.matches("H6199", "11:22", Some("living room")));
+        assert!(!config.matches("H6199", "11:22", Some("Kitchen")));
+        assert!(!config.matches("H6199", "11:22", None));
+    }
+}
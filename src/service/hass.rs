@@ -1,22 +1,40 @@
+use crate::config::config;
 use crate::hass_mqtt::climate::mqtt_set_temperature;
-use crate::hass_mqtt::enumerator::{enumerate_all_entites, enumerate_entities_for_device};
+use crate::hass_mqtt::enumerator::{
+    enumerate_all_entites, enumerate_entities_for_device, enumerate_group_lights,
+    enumerate_platform_quotas,
+};
+use crate::hass_mqtt::fan::{mqtt_fan_set_preset, mqtt_fan_set_speed};
 use crate::hass_mqtt::humidifier::{mqtt_device_set_work_mode, mqtt_humidifier_set_target};
 use crate::hass_mqtt::instance::EntityList;
-use crate::hass_mqtt::number::mqtt_number_command;
-use crate::hass_mqtt::select::mqtt_set_mode_scene;
-use crate::lan_api::DeviceColor;
+use crate::hass_mqtt::light::GroupLight;
+use crate::hass_mqtt::number::{mqtt_number_command, mqtt_set_music_sensitivity};
+use crate::hass_mqtt::select::{mqtt_set_mode_scene, mqtt_set_music_mode};
+use crate::hass_mqtt::switch::mqtt_set_music_auto_color;
+use crate::lan_api::{truthy, DeviceColor};
 use crate::opt_env_var;
 use crate::platform_api::{from_json, DeviceType};
+use crate::service::command_queue::{Coalesce, CommandQueue};
 use crate::service::device::Device as ServiceDevice;
+use crate::service::fade::{FadeColor, FadePoint};
+use crate::service::group::{group_by_topic_name, groups_for_device, LightGroup};
 use crate::service::state::StateHandle;
 use crate::temperature::TemperatureScale;
 use anyhow::Context;
 use async_channel::Receiver;
 use mosquitto_rs::router::{MqttRouter, Params, Payload, State};
 use mosquitto_rs::{Client, Event, QoS};
+use once_cell::sync::{Lazy, OnceCell};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::task::JoinSet;
+
+/// Light commands that are waiting for their device to become free
+static PENDING_LIGHT_COMMANDS: Lazy<CommandQueue<HassLightCommand>> =
+    Lazy::new(CommandQueue::default);
 
 const HASS_REGISTER_DELAY: tokio::time::Duration = tokio::time::Duration::from_secs(15);
 
@@ -29,10 +47,34 @@ pub struct HassArguments {
 
     /// The mqtt broker port
     /// You may also set this via the GOVEE_MQTT_PORT environment variable.
-    /// If unspecified, uses 1883
+    /// If unspecified, uses 1883, or 8883 when TLS is enabled
     #[arg(long, global = true)]
     mqtt_port: Option<u16>,
 
+    /// Use TLS to connect to the broker.
+    /// You may also set this via the GOVEE_MQTT_TLS environment variable.
+    /// TLS is implied by setting any of the other mqtt TLS options.
+    #[arg(long, global = true)]
+    mqtt_tls: bool,
+
+    /// The PEM encoded CA certificate file used to verify the broker.
+    /// You may also set this via the GOVEE_MQTT_CA_FILE environment variable.
+    /// If unspecified, the system certificates in /etc/ssl/certs are used.
+    /// The broker certificate is always verified; there is no option
+    /// to skip verification.
+    #[arg(long, global = true)]
+    mqtt_ca_file: Option<PathBuf>,
+
+    /// The PEM encoded client certificate file to present to the broker.
+    /// You may also set this via the GOVEE_MQTT_CERT_FILE environment variable.
+    #[arg(long, global = true)]
+    mqtt_cert_file: Option<PathBuf>,
+
+    /// The PEM encoded private key file for the client certificate.
+    /// You may also set this via the GOVEE_MQTT_KEY_FILE environment variable.
+    #[arg(long, global = true)]
+    mqtt_key_file: Option<PathBuf>,
+
     /// The username to authenticate against the broker
     /// You may also set this via the GOVEE_MQTT_USER environment variable.
     #[arg(long, global = true)]
@@ -43,11 +85,29 @@ pub struct HassArguments {
     #[arg(long, global = true)]
     mqtt_password: Option<String>,
 
+    /// The local address to bind to when connecting to the broker
     #[arg(long, global = true)]
     mqtt_bind_address: Option<String>,
 
-    #[arg(long, global = true, default_value = "homeassistant")]
-    hass_discovery_prefix: String,
+    /// The topic under which the command and state topics are placed.
+    /// You may also set this via the GOVEE_MQTT_BASE_TOPIC environment
+    /// variable. If unspecified, uses "gv2mqtt", or "gv2mqtt/<instance-id>"
+    /// when an instance id is set.
+    #[arg(long, global = true)]
+    mqtt_base_topic: Option<String>,
+
+    /// Identifies this instance when multiple instances share the same
+    /// broker and Home Assistant. It is used to qualify the unique ids
+    /// of the entities and devices registered with Home Assistant.
+    /// You may also set this via the GOVEE_MQTT_INSTANCE_ID environment
+    /// variable.
+    #[arg(long, global = true)]
+    mqtt_instance_id: Option<String>,
+
+    /// The discovery prefix that home assistant is configured to use.
+    /// If unspecified, uses "homeassistant"
+    #[arg(long, global = true)]
+    hass_discovery_prefix: Option<String>,
 
     /// The temperature scale to use when showing temperature values as
     /// entities in home assistant. Can be either "C" or "F" for Celsius
@@ -62,7 +122,7 @@ impl HassArguments {
     pub fn opt_mqtt_host(&self) -> anyhow::Result<Option<String>> {
         match &self.mqtt_host {
             Some(h) => Ok(Some(h.to_string())),
-            None => opt_env_var("GOVEE_MQTT_HOST"),
+            None => Ok(opt_env_var("GOVEE_MQTT_HOST")?.or_else(|| config().mqtt.host.clone())),
         }
     }
 
@@ -70,7 +130,8 @@ impl HassArguments {
         self.opt_mqtt_host()?.ok_or_else(|| {
             anyhow::anyhow!(
                 "Please specify the mqtt broker either via the \
-                --mqtt-host parameter or by setting $GOVEE_MQTT_HOST"
+                --mqtt-host parameter, by setting $GOVEE_MQTT_HOST \
+                or via mqtt.host in the config file"
             )
         })
     }
@@ -78,42 +139,195 @@ impl HassArguments {
     pub fn mqtt_port(&self) -> anyhow::Result<u16> {
         match self.mqtt_port {
             Some(p) => Ok(p),
-            None => Ok(opt_env_var("GOVEE_MQTT_PORT")?.unwrap_or(1883)),
+            None => {
+                let default_port = if self.mqtt_tls()?.is_some() {
+                    8883
+                } else {
+                    1883
+                };
+                Ok(opt_env_var("GOVEE_MQTT_PORT")?
+                    .or(config().mqtt.port)
+                    .unwrap_or(default_port))
+            }
+        }
+    }
+
+    fn opt_path(
+        arg: &Option<PathBuf>,
+        env_name: &str,
+        file: &Option<PathBuf>,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        match arg {
+            Some(p) => Ok(Some(p.clone())),
+            None => Ok(opt_env_var(env_name)?.or_else(|| file.clone())),
+        }
+    }
+
+    /// Returns the TLS settings for the broker connection,
+    /// or None if TLS is not enabled
+    pub fn mqtt_tls(&self) -> anyhow::Result<Option<MqttTlsConfig>> {
+        let mqtt_config = &config().mqtt;
+        let ca_file = Self::opt_path(
+            &self.mqtt_ca_file,
+            "GOVEE_MQTT_CA_FILE",
+            &mqtt_config.ca_file,
+        )?;
+        let cert_file = Self::opt_path(
+            &self.mqtt_cert_file,
+            "GOVEE_MQTT_CERT_FILE",
+            &mqtt_config.cert_file,
+        )?;
+        let key_file = Self::opt_path(
+            &self.mqtt_key_file,
+            "GOVEE_MQTT_KEY_FILE",
+            &mqtt_config.key_file,
+        )?;
+
+        let enabled = if self.mqtt_tls {
+            true
+        } else if let Some(v) = opt_env_var::<String>("GOVEE_MQTT_TLS")? {
+            truthy(&v)?
+        } else {
+            mqtt_config.tls.unwrap_or(false)
+        };
+
+        if !enabled && ca_file.is_none() && cert_file.is_none() && key_file.is_none() {
+            return Ok(None);
         }
+        if cert_file.is_some() != key_file.is_some() {
+            anyhow::bail!(
+                "The mqtt client certificate and key files either both need \
+                to be set, or both need to be unset"
+            );
+        }
+
+        Ok(Some(MqttTlsConfig {
+            ca_file,
+            cert_file,
+            key_file,
+        }))
     }
 
     pub fn mqtt_username(&self) -> anyhow::Result<Option<String>> {
         match self.mqtt_username.clone() {
             Some(u) => Ok(Some(u)),
-            None => opt_env_var("GOVEE_MQTT_USER"),
+            None => Ok(opt_env_var("GOVEE_MQTT_USER")?.or_else(|| config().mqtt.username.clone())),
         }
     }
 
     pub fn mqtt_password(&self) -> anyhow::Result<Option<String>> {
         match self.mqtt_password.clone() {
             Some(u) => Ok(Some(u)),
-            None => opt_env_var("GOVEE_MQTT_PASSWORD"),
+            None => {
+                Ok(opt_env_var("GOVEE_MQTT_PASSWORD")?.or_else(|| config().mqtt.password.clone()))
+            }
+        }
+    }
+
+    pub fn mqtt_bind_address(&self) -> Option<String> {
+        self.mqtt_bind_address
+            .clone()
+            .or_else(|| config().mqtt.bind_address.clone())
+    }
+
+    pub fn mqtt_namespace(&self) -> anyhow::Result<MqttNamespace> {
+        let mqtt_config = &config().mqtt;
+        let instance_id = match &self.mqtt_instance_id {
+            Some(id) => Some(id.to_string()),
+            None => {
+                opt_env_var("GOVEE_MQTT_INSTANCE_ID")?.or_else(|| mqtt_config.instance_id.clone())
+            }
+        };
+        let base_topic = match &self.mqtt_base_topic {
+            Some(topic) => Some(topic.to_string()),
+            None => {
+                opt_env_var("GOVEE_MQTT_BASE_TOPIC")?.or_else(|| mqtt_config.base_topic.clone())
+            }
+        };
+
+        if let Some(id) = &instance_id {
+            if id.is_empty()
+                || !id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                anyhow::bail!(
+                    "Invalid mqtt instance id '{id}'; only letters, \
+                    digits, '-' and '_' are allowed"
+                );
+            }
         }
+        if let Some(topic) = &base_topic {
+            if topic.is_empty()
+                || topic.starts_with('/')
+                || topic.ends_with('/')
+                || topic.contains(['+', '#'])
+            {
+                anyhow::bail!(
+                    "Invalid mqtt base topic '{topic}'; it must not be empty, \
+                    start or end with '/', or contain wildcards"
+                );
+            }
+        }
+
+        let base_topic = match (base_topic, &instance_id) {
+            (Some(topic), _) => topic,
+            (None, Some(id)) => format!("{DEFAULT_BASE_TOPIC}/{id}"),
+            (None, None) => DEFAULT_BASE_TOPIC.to_string(),
+        };
+
+        Ok(MqttNamespace {
+            base_topic,
+            instance_id,
+        })
+    }
+
+    pub fn hass_discovery_prefix(&self) -> String {
+        self.hass_discovery_prefix
+            .clone()
+            .or_else(|| config().hass.discovery_prefix.clone())
+            .unwrap_or_else(|| "homeassistant".to_string())
     }
 
     pub fn temperature_scale(&self) -> anyhow::Result<TemperatureScale> {
         match &self.temperature_scale {
             Some(s) => Ok(s.parse()?),
-            None => {
-                Ok(opt_env_var("GOVEE_TEMPERATURE_SCALE")?.unwrap_or(TemperatureScale::Celsius))
-            }
+            None => Ok(opt_env_var("GOVEE_TEMPERATURE_SCALE")?
+                .or(config().hass.temperature_scale)
+                .unwrap_or(TemperatureScale::Celsius)),
         }
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct MqttTlsConfig {
+    pub ca_file: Option<PathBuf>,
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+}
+
+/// Where to find the CA certificates when no CA file is configured
+const SYSTEM_CA_PATH: &str = "/etc/ssl/certs";
+
 #[derive(Clone)]
 pub struct HassClient {
     client: Client,
+    /// The lights for the configured groups, keyed by group name,
+    /// as built by the most recent registration
+    group_lights: Arc<parking_lot::Mutex<HashMap<&'static str, GroupLight>>>,
 }
 
 impl HassClient {
     async fn register_with_hass(&self, state: &StateHandle) -> anyhow::Result<()> {
-        let entities = enumerate_all_entites(state).await?;
+        let mut entities = enumerate_all_entites(state).await?;
+        let group_lights = enumerate_group_lights(state).await?;
+        for light in &group_lights {
+            entities.add(light.clone());
+        }
+        *self.group_lights.lock() = group_lights
+            .into_iter()
+            .map(|light| (light.group_name(), light))
+            .collect();
 
         // Register the configs
         log::trace!("register_with_hass: register entities");
@@ -175,10 +389,25 @@ impl HassClient {
     ) -> anyhow::Result<()> {
         let mut entities = EntityList::new();
         enumerate_entities_for_device(device, state, &mut entities).await?;
+        let group_lights: Vec<GroupLight> = {
+            let lights = self.group_lights.lock();
+            groups_for_device(device)
+                .filter_map(|group| lights.get(group.name).cloned())
+                .collect()
+        };
+        for light in group_lights {
+            entities.add(light);
+        }
         entities.notify_state(self).await?;
 
         Ok(())
     }
+
+    pub async fn advise_hass_of_platform_quota(&self, state: &StateHandle) -> anyhow::Result<()> {
+        let mut entities = EntityList::new();
+        enumerate_platform_quotas(state, &mut entities).await;
+        entities.notify_state(self).await
+    }
 }
 
 pub fn topic_safe_string(s: &str) -> String {
@@ -200,20 +429,80 @@ pub fn topic_safe_id(device: &ServiceDevice) -> String {
     id
 }
 
+/// Determines the topics and Home Assistant unique ids that are used
+/// by this instance, so that multiple instances can share a broker
+#[derive(Debug, Clone)]
+pub struct MqttNamespace {
+    pub base_topic: String,
+    pub instance_id: Option<String>,
+}
+
+impl Default for MqttNamespace {
+    fn default() -> Self {
+        Self {
+            base_topic: DEFAULT_BASE_TOPIC.to_string(),
+            instance_id: None,
+        }
+    }
+}
+
+const DEFAULT_BASE_TOPIC: &str = "gv2mqtt";
+static MQTT_NAMESPACE: OnceCell<MqttNamespace> = OnceCell::new();
+
+fn mqtt_namespace() -> &'static MqttNamespace {
+    MQTT_NAMESPACE.get_or_init(MqttNamespace::default)
+}
+
+/// The topic under which all of our command and state topics live
+pub fn base_topic() -> &'static str {
+    &mqtt_namespace().base_topic
+}
+
+/// Qualifies a Home Assistant unique id or device identifier with
+/// the instance id, if one is configured
+pub fn instance_unique_id(id: &str) -> String {
+    match &mqtt_namespace().instance_id {
+        Some(instance) => format!("{instance}-{id}"),
+        None => id.to_string(),
+    }
+}
+
 pub fn switch_instance_state_topic(device: &ServiceDevice, instance: &str) -> String {
     format!(
-        "gv2mqtt/switch/{id}/{instance}/state",
+        "{}/switch/{id}/{instance}/state",
+        base_topic(),
         id = topic_safe_id(device)
     )
 }
 
 pub fn light_state_topic(device: &ServiceDevice) -> String {
-    format!("gv2mqtt/light/{id}/state", id = topic_safe_id(device))
+    format!(
+        "{}/light/{id}/state",
+        base_topic(),
+        id = topic_safe_id(device)
+    )
+}
+
+pub fn group_light_command_topic(group: &LightGroup) -> String {
+    format!(
+        "{}/group/{name}/command",
+        base_topic(),
+        name = group.topic_safe_name()
+    )
+}
+
+pub fn group_light_state_topic(group: &LightGroup) -> String {
+    format!(
+        "{}/group/{name}/state",
+        base_topic(),
+        name = group.topic_safe_name()
+    )
 }
 
 pub fn light_segment_state_topic(device: &ServiceDevice, segment: u32) -> String {
     format!(
-        "gv2mqtt/light/{id}/state/{segment}",
+        "{}/light/{id}/state/{segment}",
+        base_topic(),
         id = topic_safe_id(device)
     )
 }
@@ -221,15 +510,15 @@ pub fn light_segment_state_topic(device: &ServiceDevice, segment: u32) -> String
 /// All entities use the same topic so that we can mark unavailable
 /// via last-will
 pub fn availability_topic() -> String {
-    "gv2mqtt/availability".to_string()
+    format!("{}/availability", base_topic())
 }
 
 pub fn oneclick_topic() -> String {
-    "gv2mqtt/oneclick".to_string()
+    format!("{}/oneclick", base_topic())
 }
 
 pub fn purge_cache_topic() -> String {
-    "gv2mqtt/purge-caches".to_string()
+    format!("{}/purge-caches", base_topic())
 }
 
 #[derive(Deserialize)]
@@ -250,13 +539,62 @@ async fn mqtt_request_platform_data(
     Ok(())
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 struct HassLightCommand {
     state: String,
     color_temp: Option<u32>,
     color: Option<DeviceColor>,
     effect: Option<String>,
     brightness: Option<u8>,
+    /// The duration of the transition, in seconds
+    transition: Option<f64>,
+}
+
+impl Coalesce for HassLightCommand {
+    fn coalesce(&mut self, newer: Self) -> Option<Self> {
+        // Power changes must be delivered in the order they were made
+        if !self.state.eq_ignore_ascii_case(&newer.state) {
+            return Some(newer);
+        }
+
+        self.brightness = newer.brightness.or(self.brightness);
+        // The transition applies to the command as it was sent;
+        // an instant change must not inherit an earlier fade
+        self.transition = newer.transition;
+        // The color, color temperature and effect are mutually
+        // exclusive, so the newest of them replaces all the others
+        if newer.color.is_some() || newer.color_temp.is_some() || newer.effect.is_some() {
+            self.color = newer.color;
+            self.color_temp = newer.color_temp;
+            self.effect = newer.effect;
+        }
+        None
+    }
+}
+
+/// Queues `command` for the device labelled `id`, then waits for the
+/// device to become free and applies the pending commands, merged
+/// with any others that arrived in the meantime.
+/// If an earlier task already applied the merged command, there is
+/// nothing left to do.
+async fn coalesce_light_command(
+    state: &StateHandle,
+    id: &str,
+    command: HassLightCommand,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_read_only(id).await?;
+    PENDING_LIGHT_COMMANDS.push(&device.id, command);
+
+    let permit = state.acquire_control_permit(&device).await?;
+    let Some(command) = PENDING_LIGHT_COMMANDS.pop(&device.id) else {
+        log::trace!("Command for {device} was merged into an earlier one");
+        return Ok(());
+    };
+    // Taking control forgets the brightness from before a fade off,
+    // so take it first in case this command turns the light back on
+    let brightness_before_off = state.take_brightness_before_fade_off(&device.id);
+    let device = state.take_control(&device.id, permit).await?;
+    apply_light_command(state, &device, &command, brightness_before_off).await
 }
 
 /// HASS is sending a command to a light
@@ -265,22 +603,92 @@ async fn mqtt_light_command(
     Params(IdParameter { id }): Params<IdParameter>,
     State(state): State<StateHandle>,
 ) -> anyhow::Result<()> {
-    let device = state.resolve_device_for_control(&id).await?;
+    let command: HassLightCommand = serde_json::from_str(&payload)?;
+    log::info!("Command for {id}: {payload}");
 
+    coalesce_light_command(&state, &id, command).await
+}
+
+#[derive(Deserialize)]
+struct NameParameter {
+    name: String,
+}
+
+/// HASS is sending a command to a light group.
+/// The command is sent to all members concurrently, so that
+/// the whole group changes at once.
+async fn mqtt_group_command(
+    Payload(payload): Payload<String>,
+    Params(NameParameter { name }): Params<NameParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let group =
+        group_by_topic_name(&name).ok_or_else(|| anyhow::anyhow!("group '{name}' not found"))?;
     let command: HassLightCommand = serde_json::from_str(&payload)?;
-    log::info!("Command for {device}: {payload}");
+    log::info!("Command for group {}: {payload}", group.name);
+
+    let members = group.members(&state).await;
+    let num_members = members.len();
+    let mut tasks = JoinSet::new();
+    for member in members {
+        let state = state.clone();
+        let command = command.clone();
+        tasks.spawn(async move {
+            let result = coalesce_light_command(&state, &member.id, command).await;
+            (member, result)
+        });
+    }
+
+    let mut failed = 0;
+    while let Some(joined) = tasks.join_next().await {
+        let (member, result) = joined?;
+        if let Err(err) = result {
+            log::error!("group {}: command for {member} failed: {err:#}", group.name);
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!(
+            "group {}: command failed for {failed} of {num_members} members",
+            group.name
+        );
+    }
+    Ok(())
+}
+
+/// Applies `command` to `device`. `brightness_before_off` is the
+/// brightness to restore if the command turns the light back on
+/// after it was faded off.
+async fn apply_light_command(
+    state: &StateHandle,
+    device: &ServiceDevice,
+    command: &HassLightCommand,
+    brightness_before_off: Option<u8>,
+) -> anyhow::Result<()> {
+    if let Some(transition) = command
+        .transition
+        .and_then(|t| Duration::try_from_secs_f64(t).ok())
+        .filter(|t| !t.is_zero())
+    {
+        // Scenes can't be faded, and only the LAN API is
+        // fast enough to step through a fade
+        if state.lan_device_for(device).await.is_some() && command.effect.is_none() {
+            return fade_light(state, device, command, transition, brightness_before_off).await;
+        }
+    }
 
     let is_light = device.device_type() == DeviceType::Light;
 
     if command.state == "OFF" {
         if is_light {
             state
-                .device_light_power_on(&device, false)
+                .device_light_power_on(device, false)
                 .await
                 .context("mqtt_light_command: state.device_power_on")?;
         } else {
             state
-                .device_set_brightness(&device, 0)
+                .device_set_brightness(device, 0)
                 .await
                 .context("mqtt_light_command: state.device_set_brightness")?;
         }
@@ -289,7 +697,7 @@ async fn mqtt_light_command(
 
         if let Some(brightness) = command.brightness {
             state
-                .device_set_brightness(&device, brightness)
+                .device_set_brightness(device, brightness)
                 .await
                 .context("mqtt_light_command: state.device_set_brightness")?;
             power_on = false;
@@ -297,7 +705,7 @@ async fn mqtt_light_command(
 
         if let Some(effect) = &command.effect {
             state
-                .device_set_scene(&device, effect)
+                .device_set_scene(device, effect)
                 .await
                 .context("mqtt_light_command: state.device_set_scene")?;
             // It doesn't make sense to vary color properties
@@ -309,14 +717,14 @@ async fn mqtt_light_command(
 
         if let Some(color) = &command.color {
             state
-                .device_set_color_rgb(&device, color.r, color.g, color.b)
+                .device_set_color_rgb(device, color.r, color.g, color.b)
                 .await
                 .context("mqtt_light_command: state.device_set_color_rgb")?;
             power_on = false;
         }
         if let Some(color_temp) = command.color_temp {
             state
-                .device_set_color_temperature(&device, mired_to_kelvin(color_temp))
+                .device_set_color_temperature(device, mired_to_kelvin(color_temp))
                 .await
                 .context("mqtt_light_command: state.device_set_color_temperature")?;
             power_on = false;
@@ -325,16 +733,22 @@ async fn mqtt_light_command(
         if power_on {
             if is_light {
                 state
-                    .device_light_power_on(&device, true)
+                    .device_light_power_on(device, true)
                     .await
                     .context("mqtt_light_command: state.device_power_on")?;
+                if let Some(brightness) = brightness_before_off {
+                    state
+                        .device_set_brightness(device, brightness)
+                        .await
+                        .context("mqtt_light_command: state.device_set_brightness")?;
+                }
             } else if command.brightness.is_none() {
                 // The device is not primarily a light and we don't have
                 // a guaranteed way to power it on without setting the
                 // brightness to something, and we know we didn't set
                 // the brightness just now, so let's turn it on 100%
                 state
-                    .device_set_brightness(&device, 100)
+                    .device_set_brightness(device, 100)
                     .await
                     .context("mqtt_light_command: state.device_set_brightness")?;
             }
@@ -344,6 +758,48 @@ async fn mqtt_light_command(
     Ok(())
 }
 
+async fn fade_light(
+    state: &StateHandle,
+    device: &ServiceDevice,
+    command: &HassLightCommand,
+    transition: Duration,
+    brightness_before_off: Option<u8>,
+) -> anyhow::Result<()> {
+    let power_off = command.state == "OFF";
+    let target = if power_off {
+        FadePoint {
+            brightness: 0,
+            color: None,
+        }
+    } else {
+        let current = device.device_state();
+        let is_on = current
+            .as_ref()
+            .map(|s| s.light_on.unwrap_or(s.on))
+            .unwrap_or(false);
+        let brightness = match command.brightness {
+            Some(brightness) => brightness,
+            None => match current {
+                Some(s) if is_on => s.brightness,
+                current => brightness_before_off
+                    .or(current.map(|s| s.brightness).filter(|&b| b > 0))
+                    .unwrap_or(100),
+            },
+        };
+        let color = match (&command.color, command.color_temp) {
+            (Some(color), _) => Some(FadeColor::Rgb(*color)),
+            (None, Some(mired)) => Some(FadeColor::Kelvin(mired_to_kelvin(mired))),
+            (None, None) => None,
+        };
+        FadePoint { brightness, color }
+    };
+
+    state
+        .device_fade(device, target, power_off, transition)
+        .await
+        .context("mqtt_light_command: state.device_fade")
+}
+
 #[derive(Deserialize)]
 struct IdAndSeg {
     id: String,
@@ -361,35 +817,26 @@ async fn mqtt_light_segment_command(
     let command: HassLightCommand = from_json(&payload)?;
     log::info!("Command for {device} segment {segment}: {payload}");
 
-    if let Some(client) = state.get_platform_client().await {
-        let info = device
-            .http_device_info
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("HTTP device info is missing"))?;
-
-        log::info!("Using Platform API to control {device} segment");
-
-        if let Some(brightness) = command.brightness {
-            client
-                .set_segment_brightness(&info, segment, brightness)
-                .await?;
-        } else if command.state == "OFF" {
-            // Do nothing here. We used to set brightness to zero,
-            // but it is problematic:
-            // * Some devices don't have a 0
-            // * Setting it to 0 will power up the rest of the device,
-            //   so if HASS is turning off all lights in an area, the
-            //   effect is that they will turn off and then immediate
-            //   on again when there are segments involved
-            // client.set_segment_brightness(&info, segment, 0).await?;
-        }
-        if let Some(color) = &command.color {
-            client
-                .set_segment_rgb(&info, segment, color.r, color.g, color.b)
-                .await?;
-        }
-    } else {
-        anyhow::bail!("set segments for {device}: Platform API is not available");
+    if let Some(brightness) = command.brightness {
+        state
+            .device_set_segment_brightness(&device, segment, brightness)
+            .await
+            .context("mqtt_light_segment_command: state.device_set_segment_brightness")?;
+    } else if command.state == "OFF" {
+        // Do nothing here. We used to set brightness to zero,
+        // but it is problematic:
+        // * Some devices don't have a 0
+        // * Setting it to 0 will power up the rest of the device,
+        //   so if HASS is turning off all lights in an area, the
+        //   effect is that they will turn off and then immediate
+        //   on again when there are segments involved
+        // client.set_segment_brightness(&info, segment, 0).await?;
+    }
+    if let Some(color) = &command.color {
+        state
+            .device_set_segment_rgb(&device, segment, color.r, color.g, color.b)
+            .await
+            .context("mqtt_light_segment_command: state.device_set_segment_rgb")?;
     }
 
     Ok(())
@@ -412,23 +859,7 @@ async fn mqtt_oneclick(
     State(state): State<StateHandle>,
 ) -> anyhow::Result<()> {
     log::info!("mqtt_oneclick: {name}");
-
-    let undoc = state
-        .get_undoc_client()
-        .await
-        .ok_or_else(|| anyhow::anyhow!("Undoc API client is not available"))?;
-    let items = undoc.parse_one_clicks().await?;
-    let item = items
-        .iter()
-        .find(|item| item.name == name)
-        .ok_or_else(|| anyhow::anyhow!("didn't find item {name}"))?;
-
-    let iot = state
-        .get_iot_client()
-        .await
-        .ok_or_else(|| anyhow::anyhow!("AWS IoT client is not available"))?;
-
-    iot.activate_one_click(&item).await
+    state.activate_one_click(&name).await
 }
 
 #[derive(Deserialize)]
@@ -453,7 +884,7 @@ async fn mqtt_switch_command(
 
     if instance == "powerSwitch" {
         state.device_power_on(&device, on).await?;
-    } else if let Some(client) = state.get_platform_client().await {
+    } else if let Some(client) = state.platform_client_for(&device).await {
         if let Some(http_dev) = &device.http_device_info {
             client.set_toggle_state(http_dev, &instance, on).await?;
         } else {
@@ -467,19 +898,11 @@ async fn mqtt_switch_command(
 }
 
 pub fn mired_to_kelvin(mired: u32) -> u32 {
-    if mired == 0 {
-        0
-    } else {
-        1000000 / mired
-    }
+    1000000u32.checked_div(mired).unwrap_or(0)
 }
 
 pub fn kelvin_to_mired(kelvin: u32) -> u32 {
-    if kelvin == 0 {
-        0
-    } else {
-        1000000 / kelvin
-    }
+    1000000u32.checked_div(kelvin).unwrap_or(0)
 }
 
 /// HASS is advising us that its status has changed
@@ -521,52 +944,107 @@ async fn run_mqtt_loop(
             .await?;
 
         router
-            .route("gv2mqtt/light/:id/command", mqtt_light_command)
+            .route(
+                format!("{}/light/:id/command", base_topic()),
+                mqtt_light_command,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/group/:name/command", base_topic()),
+                mqtt_group_command,
+            )
             .await?;
+
         router
             .route(
-                "gv2mqtt/light/:id/command/:segment",
+                format!("{}/light/:id/command/:segment", base_topic()),
                 mqtt_light_segment_command,
             )
             .await?;
         router
-            .route("gv2mqtt/switch/:id/command/:instance", mqtt_switch_command)
+            .route(
+                format!("{}/switch/:id/command/:instance", base_topic()),
+                mqtt_switch_command,
+            )
             .await?;
 
         router.route(oneclick_topic(), mqtt_oneclick).await?;
         router.route(purge_cache_topic(), mqtt_purge_caches).await?;
         router
             .route(
-                "gv2mqtt/:id/request-platform-data",
+                format!("{}/:id/request-platform-data", base_topic()),
                 mqtt_request_platform_data,
             )
             .await?;
         router
             .route(
-                "gv2mqtt/number/:id/command/:mode_name/:work_mode",
+                format!("{}/number/:id/command/:mode_name/:work_mode", base_topic()),
                 mqtt_number_command,
             )
             .await?;
         router
-            .route("gv2mqtt/humidifier/:id/set-mode", mqtt_device_set_work_mode)
+            .route(
+                format!("{}/humidifier/:id/set-mode", base_topic()),
+                mqtt_device_set_work_mode,
+            )
             .await?;
         router
-            .route("gv2mqtt/:id/set-work-mode", mqtt_device_set_work_mode)
+            .route(
+                format!("{}/:id/set-work-mode", base_topic()),
+                mqtt_device_set_work_mode,
+            )
             .await?;
         router
             .route(
-                "gv2mqtt/humidifier/:id/set-target",
+                format!("{}/humidifier/:id/set-target", base_topic()),
                 mqtt_humidifier_set_target,
             )
             .await?;
         router
             .route(
-                "gv2mqtt/:id/set-temperature/:instance/:units",
+                format!("{}/fan/:id/set-speed", base_topic()),
+                mqtt_fan_set_speed,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/fan/:id/set-preset", base_topic()),
+                mqtt_fan_set_preset,
+            )
+            .await?;
+        router
+            .route(
+                format!("{}/:id/set-temperature/:instance/:units", base_topic()),
                 mqtt_set_temperature,
             )
             .await?;
         router
-            .route("gv2mqtt/:id/set-mode-scene", mqtt_set_mode_scene)
+            .route(
+                format!("{}/:id/set-mode-scene", base_topic()),
+                mqtt_set_mode_scene,
+            )
+            .await?;
+
+        router
+            .route(
+                format!("{}/:id/set-music-mode", base_topic()),
+                mqtt_set_music_mode,
+            )
+            .await?;
+
+        router
+            .route(
+                format!("{}/:id/set-music-sensitivity", base_topic()),
+                mqtt_set_music_sensitivity,
+            )
+            .await?;
+
+        router
+            .route(
+                format!("{}/:id/set-music-auto-color", base_topic()),
+                mqtt_set_music_auto_color,
+            )
             .await?;
 
         tokio::time::sleep(HASS_REGISTER_DELAY).await;
@@ -574,7 +1052,7 @@ async fn run_mqtt_loop(
             .get_hass_client()
             .await
             .expect("have hass client")
-            .register_with_hass(&state)
+            .register_with_hass(state)
             .await
             .context("register_with_hass")?;
 
@@ -597,10 +1075,12 @@ async fn run_mqtt_loop(
             }
             Event::Disconnected(reason) => {
                 log::warn!("MQTT disconnected with reason={reason}");
+                crate::metrics::mqtt_connected(false);
                 need_rebuild = true;
             }
             Event::Connected(status) => {
                 log::info!("MQTT connected with status={status}");
+                crate::metrics::mqtt_connected(true);
                 if need_rebuild {
                     router = rebuild_router(&client, &state).await?;
                 }
@@ -617,10 +1097,17 @@ pub async fn spawn_hass_integration(
     state: StateHandle,
     args: &HassArguments,
 ) -> anyhow::Result<()> {
-    let client = Client::with_id(
-        &format!("govee2mqtt/{}", uuid::Uuid::new_v4().simple()),
-        true,
-    )?;
+    let namespace = args.mqtt_namespace()?;
+    let client_id = match &namespace.instance_id {
+        Some(instance) => format!("govee2mqtt/{instance}/{}", uuid::Uuid::new_v4().simple()),
+        None => format!("govee2mqtt/{}", uuid::Uuid::new_v4().simple()),
+    };
+    log::info!("Using mqtt base topic {}", namespace.base_topic);
+    MQTT_NAMESPACE
+        .set(namespace)
+        .map_err(|_| anyhow::anyhow!("mqtt namespace was already initialized"))?;
+
+    let client = Client::with_id(&client_id, true)?;
 
     state.set_temperature_scale(args.temperature_scale()?).await;
 
@@ -637,12 +1124,30 @@ pub async fn spawn_hass_integration(
         );
     }
     client.set_username_and_password(mqtt_username.as_deref(), mqtt_password.as_deref())?;
+    if let Some(tls) = args.mqtt_tls()? {
+        log::info!("Using TLS to connect to mqtt broker {mqtt_host}:{mqtt_port}");
+        // mosquitto-rs only wraps mosquitto_tls_set, so there is no way
+        // to offer an option to skip verification of the broker certificate
+        client
+            .configure_tls(
+                tls.ca_file.as_ref(),
+                if tls.ca_file.is_none() {
+                    Some(std::path::Path::new(SYSTEM_CA_PATH))
+                } else {
+                    None
+                },
+                tls.cert_file.as_ref(),
+                tls.key_file.as_ref(),
+                None,
+            )
+            .context("configure_tls for mqtt broker")?;
+    }
     client
         .connect(
             &mqtt_host,
             mqtt_port.into(),
             Duration::from_secs(120),
-            args.mqtt_bind_address.as_deref(),
+            args.mqtt_bind_address().as_deref(),
         )
         .await
         .with_context(|| format!("connecting to mqtt broker {mqtt_host}:{mqtt_port}"))?;
@@ -651,10 +1156,11 @@ pub async fn spawn_hass_integration(
     state
         .set_hass_client(HassClient {
             client: client.clone(),
+            group_lights: Default::default(),
         })
         .await;
 
-    let disco_prefix = args.hass_discovery_prefix.clone();
+    let disco_prefix = args.hass_discovery_prefix();
     state.set_hass_disco_prefix(disco_prefix).await;
 
     tokio::spawn(async move {
@@ -694,3 +1200,111 @@ fn test_camel_case_to_space_separated() {
         "Oscillation Toggle"
     );
 }
+
+#[cfg(test)]
+#[test]
+fn test_mqtt_namespace() {
+    use clap::Parser;
+    let namespace = |args: &[&str]| {
+        HassArguments::parse_from(std::iter::once("govee").chain(args.iter().copied()))
+            .mqtt_namespace()
+            .map(|ns| (ns.base_topic, ns.instance_id))
+    };
+
+    assert_eq!(namespace(&[]).unwrap(), ("gv2mqtt".to_string(), None));
+    assert_eq!(
+        namespace(&["--mqtt-instance-id", "site-b"]).unwrap(),
+        ("gv2mqtt/site-b".to_string(), Some("site-b".to_string()))
+    );
+    assert_eq!(
+        namespace(&[
+            "--mqtt-instance-id",
+            "site-b",
+            "--mqtt-base-topic",
+            "govee/b"
+        ])
+        .unwrap(),
+        ("govee/b".to_string(), Some("site-b".to_string()))
+    );
+    k9::snapshot!(
+        namespace(&["--mqtt-instance-id", "site/b"])
+            .unwrap_err()
+            .to_string(),
+        "Invalid mqtt instance id 'site/b'; only letters, digits, '-' and '_' are allowed"
+    );
+    k9::snapshot!(
+        namespace(&["--mqtt-base-topic", "govee/#"])
+            .unwrap_err()
+            .to_string(),
+        "Invalid mqtt base topic 'govee/#'; it must not be empty, start or end with '/', or contain wildcards"
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_light_command_coalesce() {
+    let command = |json: &str| serde_json::from_str::<HassLightCommand>(json).unwrap();
+    let coalesce = |older: &str, newer: &str| {
+        let mut older = command(older);
+        let unmerged = older.coalesce(command(newer));
+        (older, unmerged)
+    };
+
+    // The state is compared case-insensitively
+    let (merged, unmerged) = coalesce(
+        r#"{"state":"ON","brightness":10}"#,
+        r#"{"state":"on","brightness":20}"#,
+    );
+    assert_eq!(unmerged, None);
+    assert_eq!(merged, command(r#"{"state":"ON","brightness":20}"#));
+
+    // Power changes are never merged
+    let (merged, unmerged) = coalesce(r#"{"state":"ON"}"#, r#"{"state":"OFF"}"#);
+    assert_eq!(merged, command(r#"{"state":"ON"}"#));
+    assert_eq!(unmerged, Some(command(r#"{"state":"OFF"}"#)));
+
+    // The brightness is retained when the newer command doesn't set it
+    let (merged, _) = coalesce(
+        r#"{"state":"ON","brightness":10}"#,
+        r#"{"state":"ON","color":{"r":255,"g":0,"b":0}}"#,
+    );
+    assert_eq!(
+        merged,
+        command(r#"{"state":"ON","brightness":10,"color":{"r":255,"g":0,"b":0}}"#)
+    );
+
+    // The color, color temperature and effect replace each other
+    let (merged, _) = coalesce(
+        r#"{"state":"ON","color":{"r":255,"g":0,"b":0}}"#,
+        r#"{"state":"ON","color_temp":4000}"#,
+    );
+    assert_eq!(merged, command(r#"{"state":"ON","color_temp":4000}"#));
+    let (merged, _) = coalesce(
+        r#"{"state":"ON","color_temp":4000}"#,
+        r#"{"state":"ON","effect":"Sunset"}"#,
+    );
+    assert_eq!(merged, command(r#"{"state":"ON","effect":"Sunset"}"#));
+    let (merged, _) = coalesce(
+        r#"{"state":"ON","effect":"Sunset"}"#,
+        r#"{"state":"ON","brightness":50}"#,
+    );
+    assert_eq!(
+        merged,
+        command(r#"{"state":"ON","effect":"Sunset","brightness":50}"#)
+    );
+
+    // An instant change doesn't inherit the transition of an older command
+    let (merged, _) = coalesce(
+        r#"{"state":"ON","brightness":10,"transition":10}"#,
+        r#"{"state":"ON","brightness":80}"#,
+    );
+    assert_eq!(merged, command(r#"{"state":"ON","brightness":80}"#));
+    let (merged, _) = coalesce(
+        r#"{"state":"ON","brightness":10}"#,
+        r#"{"state":"ON","brightness":80,"transition":2.5}"#,
+    );
+    assert_eq!(
+        merged,
+        command(r#"{"state":"ON","brightness":80,"transition":2.5}"#)
+    );
+}
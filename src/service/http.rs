@@ -1,14 +1,22 @@
+use crate::platform_quota::QuotaStatus;
 use crate::service::coordinator::Coordinator;
 use crate::service::device::{Device, DeviceState};
+use crate::service::state::DesiredState;
+use crate::service::state::DeviceStateEvent;
 use crate::service::state::StateHandle;
+use crate::service::transport::TransportPolicy;
 use anyhow::Context;
-use axum::extract::{Path, State};
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
-use axum::routing::get;
+use axum::routing::{get, post};
 use axum::{Json, Router};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
 use tower_http::services::ServeDir;
 
 fn response_with_code<T: ToString + std::fmt::Display>(code: StatusCode, err: T) -> Response {
@@ -42,13 +50,13 @@ async fn resolve_device_for_control(
     id: &str,
 ) -> Result<Coordinator, Response> {
     state
-        .resolve_device_for_control(&id)
+        .resolve_device_for_control(id)
         .await
         .map_err(not_found)
 }
 
 async fn resolve_device_read_only(state: &StateHandle, id: &str) -> Result<Device, Response> {
-    state.resolve_device_read_only(&id).await.map_err(not_found)
+    state.resolve_device_read_only(id).await.map_err(not_found)
 }
 
 /// Returns a json array of device information
@@ -63,15 +71,18 @@ async fn list_devices(State(state): State<StateHandle>) -> Result<Response, Resp
         pub name: String,
         pub room: Option<String>,
         pub ip: Option<IpAddr>,
+        pub transport: TransportPolicy,
         pub state: Option<DeviceState>,
     }
 
     let devices: Vec<_> = devices
         .into_iter()
+        .filter(|d| !d.is_hidden())
         .map(|d| DeviceItem {
             name: d.name(),
             room: d.room_name().map(|r| r.to_string()),
             ip: d.ip_addr(),
+            transport: d.transport_policy(),
             state: d.device_state(),
             sku: d.sku,
             id: d.id,
@@ -81,6 +92,18 @@ async fn list_devices(State(state): State<StateHandle>) -> Result<Response, Resp
     Ok(Json(devices).into_response())
 }
 
+/// Returns a json array describing the daily request budget
+/// of the Platform API client of each account
+async fn platform_quota(State(state): State<StateHandle>) -> Result<Response, Response> {
+    let quotas: Vec<QuotaStatus> = state
+        .platform_clients()
+        .await
+        .iter()
+        .map(|client| client.quota().status())
+        .collect();
+    Ok(Json(quotas).into_response())
+}
+
 /// Turns on a given device
 async fn device_power_on(
     State(state): State<StateHandle>,
@@ -175,6 +198,30 @@ async fn device_set_scene(
     Ok(response_with_code(StatusCode::OK, "ok"))
 }
 
+/// Applies a combination of changes to a given device, returning
+/// the resulting device state
+async fn device_set_state(
+    State(state): State<StateHandle>,
+    Path(id): Path<String>,
+    body: Result<Json<DesiredState>, JsonRejection>,
+) -> Result<Response, Response> {
+    let Json(desired) = body.map_err(|err| bad_request(err.body_text()))?;
+    desired.validate().map_err(bad_request)?;
+
+    let device = resolve_device_for_control(&state, &id).await?;
+
+    state
+        .device_set_desired_state(&device, &desired)
+        .await
+        .map_err(generic)?;
+
+    let device_state = state
+        .device_by_id(&device.id)
+        .await
+        .and_then(|d| d.device_state());
+    Ok(Json(device_state).into_response())
+}
+
 /// Returns a JSON array of the available scene names for a given device
 async fn device_list_scenes(
     State(state): State<StateHandle>,
@@ -188,12 +235,13 @@ async fn device_list_scenes(
 }
 
 async fn list_one_clicks(State(state): State<StateHandle>) -> Result<Response, Response> {
-    let undoc = state
-        .get_undoc_client()
+    let items: Vec<_> = state
+        .list_one_clicks()
         .await
-        .ok_or_else(|| anyhow::anyhow!("Undoc API client is not available"))
-        .map_err(generic)?;
-    let items = undoc.parse_one_clicks().await.map_err(generic)?;
+        .map_err(generic)?
+        .into_iter()
+        .map(|oc| oc.item)
+        .collect();
 
     Ok(Json(items).into_response())
 }
@@ -202,29 +250,99 @@ async fn activate_one_click(
     State(state): State<StateHandle>,
     Path(name): Path<String>,
 ) -> Result<Response, Response> {
-    let undoc = state
-        .get_undoc_client()
+    let one_click = state
+        .list_one_clicks()
         .await
-        .ok_or_else(|| anyhow::anyhow!("Undoc API client is not available"))
-        .map_err(generic)?;
-    let items = undoc.parse_one_clicks().await.map_err(generic)?;
-    let item = items
-        .iter()
-        .find(|item| item.name == name)
+        .map_err(generic)?
+        .into_iter()
+        .find(|oc| oc.item.name == name)
         .ok_or_else(|| anyhow::anyhow!("didn't find item {name}"))
         .map_err(not_found)?;
 
-    let iot = state
-        .get_iot_client()
-        .await
-        .ok_or_else(|| anyhow::anyhow!("AWS IoT client is not available"))
-        .map_err(generic)?;
-
-    iot.activate_one_click(&item).await.map_err(generic)?;
+    state.run_one_click(&one_click).await.map_err(generic)?;
 
     Ok(response_with_code(StatusCode::OK, "ok"))
 }
 
+#[derive(Deserialize)]
+struct EventStreamParams {
+    /// Only report changes to this device
+    device: Option<String>,
+    /// Only report changes that were produced by this source
+    source: Option<String>,
+}
+
+/// Streams the state of devices as Server-Sent Events.
+/// The current state of each matching device is sent first,
+/// followed by an event each time that the state changes.
+async fn device_events(
+    State(state): State<StateHandle>,
+    Query(params): Query<EventStreamParams>,
+) -> Result<Response, Response> {
+    let device_id = match &params.device {
+        Some(label) => Some(resolve_device_read_only(&state, label).await?.id),
+        None => None,
+    };
+    if let Some(source) = &params.source {
+        if !["lan", "iot", "platform", "restored"].contains(&source.as_str()) {
+            return Err(bad_request(format!(
+                "invalid source '{source}'; expected one of lan, iot, platform or restored"
+            )));
+        }
+    }
+
+    let matches = move |event: &DeviceStateEvent| {
+        device_id.as_ref().map(|id| *id == event.id).unwrap_or(true)
+            && params
+                .source
+                .as_ref()
+                .map(|source| source == event.source)
+                .unwrap_or(true)
+    };
+
+    // Subscribe before taking the snapshot, so that we can't miss a change
+    let changes =
+        BroadcastStream::new(state.subscribe_state_events()).filter_map(|event| event.ok());
+
+    let mut current = vec![];
+    for device in state.devices().await {
+        if device.is_hidden() {
+            continue;
+        }
+        if let Some(device_state) = device.device_state() {
+            current.push(DeviceStateEvent {
+                name: device.name(),
+                source: device_state.source_kind(),
+                state: device_state,
+                sku: device.sku,
+                id: device.id,
+            });
+        }
+    }
+
+    let stream = tokio_stream::iter(current)
+        .chain(changes)
+        .filter(matches)
+        .map(|event| Event::default().event("state").json_data(&event));
+
+    Ok(Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+/// Returns the service metrics in the Prometheus text format
+async fn metrics() -> Result<Response, Response> {
+    let text = crate::metrics::render().map_err(generic)?;
+    Ok((
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        text,
+    )
+        .into_response())
+}
+
 async fn redirect_to_index() -> Response {
     axum::response::Redirect::to("/assets/index.html").into_response()
 }
@@ -245,8 +363,15 @@ pub async fn run_http_server(state: StateHandle, port: u16) -> anyhow::Result<()
         .route("/api/device/:id/color/:color", get(device_set_color))
         .route("/api/device/:id/scene/:scene", get(device_set_scene))
         .route("/api/device/:id/scenes", get(device_list_scenes))
+        .route(
+            "/api/device/:id/state",
+            post(device_set_state).put(device_set_state),
+        )
+        .route("/api/events", get(device_events))
+        .route("/api/platform-quota", get(platform_quota))
         .route("/api/oneclicks", get(list_one_clicks))
         .route("/api/oneclick/activate/:scene", get(activate_one_click))
+        .route("/metrics", get(metrics))
         .route("/", get(redirect_to_index))
         .nest_service("/assets", ServeDir::new("assets"))
         .with_state(state);
@@ -262,3 +387,49 @@ pub async fn run_http_server(state: StateHandle, port: u16) -> anyhow::Result<()
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn validate(json: &str) -> anyhow::Result<Option<(u8, u8, u8)>> {
+        serde_json::from_str::<DesiredState>(json)?.validate()
+    }
+
+    #[test]
+    fn desired_state_validation() {
+        assert_eq!(
+            validate(r#"{"on": true, "brightness": 50, "color": "red"}"#).unwrap(),
+            Some((255, 0, 0))
+        );
+        assert_eq!(
+            validate(r#"{"scene": "Sunrise", "brightness": 20}"#).unwrap(),
+            None
+        );
+
+        k9::snapshot!(
+            validate(r#"{"on": false, "brightness": 50}"#)
+                .unwrap_err()
+                .to_string(),
+            "cannot change other properties while turning the device off"
+        );
+        k9::snapshot!(
+            validate(r#"{"scene": "Sunrise", "kelvin": 3000}"#)
+                .unwrap_err()
+                .to_string(),
+            "scene cannot be combined with color or kelvin"
+        );
+        k9::snapshot!(
+            validate(r#"{"brightness": 150}"#).unwrap_err().to_string(),
+            "brightness 150 is out of range 0-100"
+        );
+        k9::snapshot!(
+            validate(r#"{"color": "nope"}"#).unwrap_err().to_string(),
+            "error parsing color 'nope': invalid unknown format"
+        );
+        k9::snapshot!(
+            validate(r#"{"brightnes": 150}"#).unwrap_err().to_string(),
+            "unknown field `brightnes`, expected one of `on`, `brightness`, `color`, `kelvin`, `scene` at line 1 column 12"
+        );
+    }
+}
@@ -1,8 +1,10 @@
 use crate::ble::{Base64HexBytes, GoveeBlePacket, HumidifierAutoMode, NotifyHumidifierMode};
 use crate::lan_api::{DeviceColor, DeviceStatus};
-use crate::platform_api::from_json;
+use crate::platform_api::{from_json, DeviceCapabilityKind, DeviceCapabilityState};
 use crate::service::state::StateHandle;
-use crate::undoc_api::{ms_timestamp, DeviceEntry, LoginAccountResponse, ParsedOneClick};
+use crate::undoc_api::{
+    ms_timestamp, DeviceEntry, GoveeUndocumentedApi, LoginAccountResponse, ParsedOneClick,
+};
 use crate::Args;
 use anyhow::Context;
 use async_channel::Receiver;
@@ -233,6 +235,21 @@ pub async fn start_iot_client(
     acct: Option<LoginAccountResponse>,
 ) -> anyhow::Result<()> {
     let client = args.undoc_args.api_client()?;
+    start_iot_client_for_account(args, state, &client, None, acct).await
+}
+
+/// Connect to AWS IoT using the credentials of the undoc API `client`.
+/// `account` is the name of the additional account that the client
+/// belongs to, or None for the primary account.
+pub async fn start_iot_client_for_account(
+    args: &Args,
+    state: StateHandle,
+    client: &GoveeUndocumentedApi,
+    account: Option<&str>,
+    acct: Option<LoginAccountResponse>,
+) -> anyhow::Result<()> {
+    let key_path = client.account_file_path(&args.undoc_args.iot_key_path());
+    let cert_path = client.account_file_path(&args.undoc_args.iot_cert_path());
     let acct = match acct {
         Some(a) => a,
         None => client.login_account_cached().await?,
@@ -250,12 +267,12 @@ pub async fn start_iot_client(
         let pem = priv_key
             .private_key_to_pem_pkcs8()
             .context("to_pem_pkcs8")?;
-        std::fs::write(&args.undoc_args.govee_iot_key, &pem)?;
+        std::fs::write(&key_path, &pem)?;
     }
     for cert in container.cert_bags(&res.p12_pass).context("cert_bags")? {
         let cert = openssl::x509::X509::from_der(&cert).context("x509 from der")?;
         let pem = cert.to_pem().context("cert.to_pem")?;
-        std::fs::write(&args.undoc_args.govee_iot_cert, &pem)?;
+        std::fs::write(&cert_path, &pem)?;
     }
 
     let client = mosquitto_rs::Client::with_id(
@@ -269,10 +286,10 @@ pub async fn start_iot_client(
     .context("new client")?;
     client
         .configure_tls(
-            Some(&args.undoc_args.amazon_root_ca),
+            Some(&args.undoc_args.amazon_root_ca_path()),
             None::<&std::path::Path>,
-            Some(&args.undoc_args.govee_iot_cert),
-            Some(&args.undoc_args.govee_iot_key),
+            Some(&cert_path),
+            Some(&key_path),
             None,
         )
         .context("configure_tls")?;
@@ -284,18 +301,24 @@ pub async fn start_iot_client(
     .await
     .context("timeout connecting to IoT in AWS")?
     .context("failed to connect to IoT in AWS")?;
-    log::info!("Connected to IoT: {status}");
+    match account {
+        Some(account) => log::info!("Connected to IoT for account {account}: {status}"),
+        None => log::info!("Connected to IoT: {status}"),
+    }
 
     let subscriptions = client.subscriber().expect("first and only");
 
-    state
-        .set_iot_client(IotClient {
-            client: client.clone(),
-        })
-        .await;
+    let iot = IotClient {
+        client: client.clone(),
+    };
+    match account {
+        Some(account) => state.set_account_iot_client(account, iot).await,
+        None => state.set_iot_client(iot).await,
+    }
 
+    let account = account.map(str::to_string);
     tokio::spawn(async move {
-        if let Err(err) = run_iot_subscriber(subscriptions, state, client, acct).await {
+        if let Err(err) = run_iot_subscriber(subscriptions, state, client, acct, account).await {
             log::error!("IoT loop failed: {err:#}");
         }
         log::info!("IoT loop terminated");
@@ -314,11 +337,16 @@ struct Packet {
     cmd: Option<String>,
     /// This is an embedded json string
     msg: Option<String>,
+    #[serde(default)]
     state: StateUpdate,
     op: Option<OpData>,
+    /// Event notifications, such as lackWaterEvent, use the
+    /// same representation as the Platform API
+    #[serde(default)]
+    capabilities: Vec<DeviceCapabilityState>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 struct StateUpdate {
     #[serde(rename = "onOff")]
     pub on_off: Option<u8>,
@@ -363,6 +391,18 @@ impl Packet {
         self.state.device.as_deref()
     }
 
+    /// Returns true if the packet carries events but no state,
+    /// in which case it says nothing about the power or color
+    fn is_event_only(&self) -> bool {
+        let state = &self.state;
+        !self.capabilities.is_empty()
+            && self.op.is_none()
+            && state.on_off.is_none()
+            && state.brightness.is_none()
+            && state.color.is_none()
+            && state.color_temperature_kelvin.is_none()
+    }
+
     fn sku_and_device(&self) -> Option<(&str, &str)> {
         let sku = self.sku()?;
         let device = self.device()?;
@@ -375,6 +415,7 @@ async fn run_iot_subscriber(
     state: StateHandle,
     client: mosquitto_rs::Client,
     acct: LoginAccountResponse,
+    account: Option<String>,
 ) -> anyhow::Result<()> {
     while let Ok(event) = subscriptions.recv().await {
         match event {
@@ -388,6 +429,16 @@ async fn run_iot_subscriber(
                         if let Some((sku, device_id)) = packet.sku_and_device() {
                             {
                                 let mut device = state.device_mut(sku, device_id).await;
+                                for cap in &packet.capabilities {
+                                    if cap.kind == DeviceCapabilityKind::Event
+                                        && !device.set_event_state(&cap.instance, &cap.state)
+                                    {
+                                        log::debug!(
+                                            "{device} has no event capability {}",
+                                            cap.instance
+                                        );
+                                    }
+                                }
                                 let mut state = match device.iot_device_status.clone() {
                                     Some(state) => state,
                                     None => match device.device_state() {
@@ -426,7 +477,7 @@ async fn run_iot_subscriber(
                                                     g: nl.g,
                                                     b: nl.b,
                                                 };
-                                                device.set_nightlight_state(nl.clone());
+                                                device.set_nightlight_state(nl);
                                             }
                                             GoveeBlePacket::NotifyHumidifierAutoMode(
                                                 HumidifierAutoMode { target_humidity },
@@ -466,7 +517,9 @@ async fn run_iot_subscriber(
                                 if let Some(on_off) = packet.state.on_off {
                                     state.on = on_off != 0;
                                 }
-                                device.set_iot_device_status(state);
+                                if !packet.is_event_only() {
+                                    device.set_iot_device_status(state);
+                                }
                             }
                             state.notify_of_state_change(device_id).await?;
                         }
@@ -478,9 +531,11 @@ async fn run_iot_subscriber(
             }
             Event::Disconnected(reason) => {
                 log::warn!("IoT disconnected with reason {reason}");
+                crate::metrics::iot_connected(account.as_deref(), false);
             }
             Event::Connected(status) => {
                 log::info!("IoT (re)connected with status {status}");
+                crate::metrics::iot_connected(account.as_deref(), true);
 
                 client
                     .subscribe(&acct.topic, mosquitto_rs::QoS::AtMostOnce)
@@ -1,7 +1,12 @@
+pub mod command_queue;
 pub mod coordinator;
 pub mod device;
+pub mod fade;
+pub mod group;
 pub mod hass;
 pub mod http;
 pub mod iot;
 pub mod quirks;
+pub mod schedule;
 pub mod state;
+pub mod transport;
@@ -1,17 +1,22 @@
+use crate::config::parse_toml;
 use crate::platform_api::DeviceType;
 use crate::temperature::TemperatureUnits;
-use once_cell::sync::Lazy;
+use anyhow::Context;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Deserializer};
 use std::borrow::Cow;
 use std::collections::HashMap;
+use std::path::Path;
 
 #[allow(unused)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
 pub enum HumidityUnits {
     RelativePercent,
     RelativePercentTimes100,
 }
 
 impl HumidityUnits {
+    #[allow(clippy::wrong_self_convention)]
     pub fn from_reading_to_relative_percent(&self, value: f64) -> f64 {
         match self {
             Self::RelativePercent => value,
@@ -38,6 +43,9 @@ pub struct Quirk {
     /// their state.
     pub iot_api_supported: bool,
     pub show_as_preset_buttons: Option<&'static [&'static str]>,
+    /// The work modes that select the speed of a fan or
+    /// air purifier, slowest first
+    pub fan_speed_modes: Option<&'static [&'static str]>,
 }
 
 impl Quirk {
@@ -60,6 +68,7 @@ impl Quirk {
             platform_humidity_sensor_units: None,
             iot_api_supported: false,
             show_as_preset_buttons: None,
+            fan_speed_modes: None,
         }
     }
 
@@ -79,6 +88,14 @@ impl Quirk {
         Self::device(sku, DeviceType::Humidifier, "mdi:air-humidifier")
     }
 
+    pub fn fan<SKU: Into<Cow<'static, str>>>(sku: SKU) -> Self {
+        Self::device(sku, DeviceType::Fan, "mdi:fan")
+    }
+
+    pub fn air_purifier<SKU: Into<Cow<'static, str>>>(sku: SKU) -> Self {
+        Self::device(sku, DeviceType::AirPurifier, "mdi:air-purifier")
+    }
+
     pub fn thermometer<SKU: Into<Cow<'static, str>>>(sku: SKU) -> Self {
         Self::device(sku, DeviceType::Thermometer, "mdi:thermometer")
     }
@@ -123,6 +140,11 @@ impl Quirk {
         self
     }
 
+    pub fn with_fan_speed_modes(mut self, modes: &'static [&'static str]) -> Self {
+        self.fan_speed_modes.replace(modes);
+        self
+    }
+
     pub fn with_broken_platform(mut self) -> Self {
         self.avoid_platform_api = true;
         self
@@ -146,6 +168,145 @@ impl Quirk {
 }
 
 static QUIRKS: Lazy<HashMap<String, Quirk>> = Lazy::new(load_quirks);
+static USER_QUIRKS: OnceCell<HashMap<String, QuirkOverride>> = OnceCell::new();
+
+/// An entry from a user-supplied quirks file.
+/// Each field that is set replaces the corresponding field of the
+/// built-in quirk for the same SKU. If there is no built-in quirk
+/// for the SKU, the entry is applied over the defaults for its
+/// `device_type`, which is assumed to be a light if not specified.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuirkOverride {
+    #[serde(deserialize_with = "deserialize_device_type")]
+    pub device_type: Option<DeviceType>,
+    pub icon: Option<String>,
+    pub supports_rgb: Option<bool>,
+    pub supports_brightness: Option<bool>,
+    pub color_temp_range: Option<(u32, u32)>,
+    pub avoid_platform_api: Option<bool>,
+    pub ble_only: Option<bool>,
+    pub lan_api_capable: Option<bool>,
+    pub iot_api_supported: Option<bool>,
+    pub platform_temperature_sensor_units: Option<TemperatureUnits>,
+    pub platform_humidity_sensor_units: Option<HumidityUnits>,
+    pub show_as_preset_buttons: Option<Vec<String>>,
+    pub fan_speed_modes: Option<Vec<String>>,
+}
+
+/// Accepts either the full platform API name, such as
+/// "devices.types.light", or the short form "light"
+fn deserialize_device_type<'de, D>(d: D) -> Result<Option<DeviceType>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(d)?;
+    for candidate in [s.clone(), format!("devices.types.{s}")] {
+        if let Ok(device_type) = candidate.parse::<DeviceType>() {
+            return Ok(Some(device_type));
+        }
+    }
+    Err(serde::de::Error::custom(format!("unknown device type {s}")))
+}
+
+/// The quirks table lives for the duration of the process,
+/// so it is fine to leak these to satisfy the 'static lifetime
+fn leak_names(names: &[String]) -> &'static [&'static str] {
+    let names: Vec<&'static str> = names
+        .iter()
+        .map(|name| &*Box::leak(name.clone().into_boxed_str()))
+        .collect();
+    Box::leak(names.into_boxed_slice())
+}
+
+impl QuirkOverride {
+    fn apply(&self, sku: &str, base: Option<&Quirk>) -> Quirk {
+        let mut quirk = match base {
+            Some(quirk) => quirk.clone(),
+            None => match self.device_type.clone().unwrap_or(DeviceType::Light) {
+                DeviceType::Light => Quirk::light(sku.to_string(), BULB),
+                device_type => Quirk::device(sku.to_string(), device_type, BULB),
+            },
+        };
+
+        if let Some(device_type) = &self.device_type {
+            quirk.device_type = device_type.clone();
+        }
+        if let Some(icon) = &self.icon {
+            quirk.icon = Cow::Owned(icon.to_string());
+        }
+        if let Some(v) = self.supports_rgb {
+            quirk.supports_rgb = v;
+        }
+        if let Some(v) = self.supports_brightness {
+            quirk.supports_brightness = v;
+        }
+        if let Some(range) = self.color_temp_range {
+            quirk.color_temp_range = Some(range);
+        }
+        if let Some(v) = self.avoid_platform_api {
+            quirk.avoid_platform_api = v;
+        }
+        if let Some(v) = self.ble_only {
+            quirk.ble_only = v;
+        }
+        if let Some(v) = self.lan_api_capable {
+            quirk.lan_api_capable = v;
+        }
+        if let Some(v) = self.iot_api_supported {
+            quirk.iot_api_supported = v;
+        }
+        if let Some(units) = self.platform_temperature_sensor_units {
+            quirk.platform_temperature_sensor_units = Some(units);
+        }
+        if let Some(units) = self.platform_humidity_sensor_units {
+            quirk.platform_humidity_sensor_units = Some(units);
+        }
+        if let Some(modes) = &self.show_as_preset_buttons {
+            quirk.show_as_preset_buttons = Some(leak_names(modes));
+        }
+        if let Some(modes) = &self.fan_speed_modes {
+            quirk.fan_speed_modes = Some(leak_names(modes));
+        }
+
+        quirk
+    }
+}
+
+/// Parses the quirks file. SKUs are matched case-insensitively,
+/// so the keys are normalized to uppercase.
+fn parse_user_quirks(text: &str) -> anyhow::Result<HashMap<String, QuirkOverride>> {
+    let parsed: HashMap<String, QuirkOverride> = parse_toml(text)?;
+    let mut quirks = HashMap::new();
+    for (sku, quirk) in parsed {
+        let upper = sku.to_uppercase();
+        if quirks.insert(upper.clone(), quirk).is_some() {
+            anyhow::bail!("SKU {upper} is specified more than once");
+        }
+    }
+    Ok(quirks)
+}
+
+/// Loads additional quirks from the specified file.
+/// This must be called prior to resolving any quirks in order
+/// for them to take effect.
+pub fn load_user_quirks(path: &Path) -> anyhow::Result<()> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("reading quirks file {path:?}"))?;
+    let quirks =
+        parse_user_quirks(&text).with_context(|| format!("loading quirks file {path:?}"))?;
+    USER_QUIRKS
+        .set(quirks)
+        .map_err(|_| anyhow::anyhow!("quirks file was already loaded"))
+}
+
+/// Returns true if the user-supplied quirks file has an entry for sku
+pub fn has_user_quirk(sku: &str) -> bool {
+    USER_QUIRKS
+        .get()
+        .map(|quirks| quirks.contains_key(sku))
+        .unwrap_or(false)
+}
 
 const STRIP: &str = "mdi:led-strip-variant";
 const STRIP_ALT: &str = "mdi:led-strip";
@@ -163,6 +324,19 @@ const OUTDOOR_LAMP: &str = "mdi:outdoor-lamp";
 const SPOTLIGHT: &str = "mdi:lightbulb-spot";
 
 fn load_quirks() -> HashMap<String, Quirk> {
+    let mut map = builtin_quirks();
+
+    if let Some(user_quirks) = USER_QUIRKS.get() {
+        for (sku, entry) in user_quirks {
+            let quirk = entry.apply(sku, map.get(sku));
+            map.insert(sku.to_string(), quirk);
+        }
+    }
+
+    map
+}
+
+fn builtin_quirks() -> HashMap<String, Quirk> {
     let mut map = HashMap::new();
     for quirk in [
         Quirk::lan_api_capable_light("H610A", STRIP),
@@ -227,6 +401,8 @@ fn load_quirks() -> HashMap<String, Quirk> {
             .with_platform_temperature_sensor_units(TemperatureUnits::Farenheit),
         Quirk::space_heater("H7135")
             .with_platform_temperature_sensor_units(TemperatureUnits::Farenheit),
+        Quirk::fan("H7111"),
+        Quirk::air_purifier("H7121").with_fan_speed_modes(&["Low", "Medium", "High"]),
         Quirk::thermometer("H5051")
             .with_platform_temperature_sensor_units(TemperatureUnits::Farenheit)
             .with_platform_humidity_sensor_units(HumidityUnits::RelativePercent),
@@ -313,3 +489,69 @@ fn load_quirks() -> HashMap<String, Quirk> {
 pub fn resolve_quirk(sku: &str) -> Option<&'static Quirk> {
     QUIRKS.get(sku)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn user_quirks() {
+        let user_quirks = parse_user_quirks(
+            r#"
+[H6072]
+icon = "mdi:lamp"
+iot_api_supported = false
+
+[h9999]
+lan_api_capable = true
+color_temp_range = [2700, 6500]
+
+[H7999]
+device_type = "humidifier"
+show_as_preset_buttons = ["Auto", "Sleep"]
+platform_humidity_sensor_units = "RelativePercentTimes100"
+"#,
+        )
+        .unwrap();
+
+        let builtin = builtin_quirks();
+
+        let q = user_quirks["H6072"].apply("H6072", builtin.get("H6072"));
+        assert_eq!(q.icon, "mdi:lamp");
+        assert!(!q.iot_api_supported);
+        // Retained from the built-in entry
+        assert!(q.lan_api_capable);
+        assert!(q.supports_rgb);
+
+        let q = user_quirks["H9999"].apply("H9999", builtin.get("H9999"));
+        assert_eq!(q.device_type, DeviceType::Light);
+        assert!(q.lan_api_capable);
+        assert!(q.supports_rgb);
+        assert_eq!(q.color_temp_range, Some((2700, 6500)));
+
+        let q = user_quirks["H7999"].apply("H7999", builtin.get("H7999"));
+        assert_eq!(q.device_type, DeviceType::Humidifier);
+        assert!(!q.supports_rgb);
+        assert!(q.should_show_mode_as_preset("Sleep"));
+        assert_eq!(
+            q.platform_humidity_sensor_units,
+            Some(HumidityUnits::RelativePercentTimes100)
+        );
+    }
+
+    #[test]
+    fn user_quirks_errors() {
+        let err = parse_user_quirks("[H6072]\nble = true\n").unwrap_err();
+        k9::snapshot!(err.to_string(), "H6072.ble: unknown field `ble`, expected one of `device_type`, `icon`, `supports_rgb`, `supports_brightness`, `color_temp_range`, `avoid_platform_api`, `ble_only`, `lan_api_capable`, `iot_api_supported`, `platform_temperature_sensor_units`, `platform_humidity_sensor_units`, `show_as_preset_buttons`, `fan_speed_modes`");
+
+        let err = parse_user_quirks("[H6072]\ndevice_type = \"toaster\"\n").unwrap_err();
+        k9::snapshot!(
+            err.to_string(),
+            "H6072.device_type: unknown device type toaster"
+        );
+
+        let err =
+            parse_user_quirks("[H6072]\nble_only = true\n[h6072]\nble_only = false\n").unwrap_err();
+        k9::snapshot!(err.to_string(), "SKU H6072 is specified more than once");
+    }
+}
@@ -0,0 +1,563 @@
+//! Schedules that are defined by the `[[schedule]]` sections of the
+//! config file, and that run without any help from Home Assistant or
+//! the Govee cloud.
+//! A schedule fires either at times that match a cron-like expression,
+//! or at a time relative to sunrise or sunset, which are computed locally
+//! from the latitude and longitude in the `[general]` section.
+use crate::config::{config, ScheduleConfig};
+use crate::service::device::Device;
+use crate::service::group::group_by_name;
+use crate::service::state::StateHandle;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use std::f64::consts::PI;
+use std::str::FromStr;
+use tokio::task::JoinSet;
+
+/// After the system was suspended, or the clock jumped forwards,
+/// schedules that were missed by more than this are not run
+const MAX_CATCH_UP_MINUTES: i64 = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SunEvent {
+    Sunrise,
+    Sunset,
+}
+
+/// Computes the time of sunrise or sunset on `date` at `location`,
+/// using the sunrise equation.
+/// Returns None if the sun doesn't rise or set on that date, as
+/// happens near the poles.
+pub fn sun_event(date: NaiveDate, event: SunEvent, location: Location) -> Option<DateTime<Utc>> {
+    let j2000 = NaiveDate::from_ymd_opt(2000, 1, 1).expect("valid date");
+    let days = (date - j2000).num_days() as f64;
+
+    // Mean solar time, expressed as days since the J2000 epoch
+    let mean_solar = days + 0.0008 - location.longitude / 360.;
+    let anomaly = (357.5291 + 0.98560028 * mean_solar)
+        .rem_euclid(360.)
+        .to_radians();
+    let center =
+        1.9148 * anomaly.sin() + 0.02 * (2. * anomaly).sin() + 0.0003 * (3. * anomaly).sin();
+    let ecliptic_longitude = (anomaly.to_degrees() + center + 180. + 102.9372)
+        .rem_euclid(360.)
+        .to_radians();
+    let transit = mean_solar + 0.0053 * anomaly.sin() - 0.0069 * (2. * ecliptic_longitude).sin();
+
+    let declination = (ecliptic_longitude.sin() * 23.4397f64.to_radians().sin()).asin();
+    let latitude = location.latitude.to_radians();
+    // -0.833 degrees accounts for refraction and the size of the sun's disc
+    let cos_hour_angle = ((-0.833f64).to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let hour_angle = cos_hour_angle.acos() / (2. * PI);
+
+    let day = match event {
+        SunEvent::Sunrise => transit - hour_angle,
+        SunEvent::Sunset => transit + hour_angle,
+    };
+    // The J2000 epoch is noon UTC on 2000-01-01
+    let j2000_unix = 946_728_000.;
+    Utc.timestamp_opt((j2000_unix + day * 86400.).round() as i64, 0)
+        .single()
+}
+
+fn truncate_to_minute<T: TimeZone>(t: DateTime<T>) -> DateTime<T> {
+    t.with_second(0)
+        .and_then(|t| t.with_nanosecond(0))
+        .expect("zero is a valid second")
+}
+
+/// A parsed cron expression, consisting of the minute, hour,
+/// day of month, month and day of week fields.
+/// Each field is represented as a bitmask of the matching values.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CronSpec {
+    text: String,
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    /// True if both the day of month and day of week are restricted,
+    /// in which case matching either of them is sufficient
+    either_day: bool,
+}
+
+const MONTH_NAMES: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const DAY_NAMES: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Parses a single field of a cron expression into a bitmask.
+/// Returns the mask and whether the field was unrestricted.
+fn parse_cron_field(
+    field: &str,
+    min: u32,
+    max: u32,
+    names: &[&str],
+) -> anyhow::Result<(u64, bool)> {
+    let value = |s: &str| -> anyhow::Result<u32> {
+        let lower = s.to_ascii_lowercase();
+        let value = match names.iter().position(|name| *name == lower) {
+            Some(idx) => idx as u32 + min,
+            None => s
+                .parse()
+                .map_err(|_| anyhow::anyhow!("'{s}' is not a valid value"))?,
+        };
+        anyhow::ensure!(
+            (min..=max).contains(&value),
+            "{value} is outside the range {min}-{max}"
+        );
+        Ok(value)
+    };
+
+    let mut mask = 0u64;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step
+                    .parse()
+                    .ok()
+                    .filter(|&s| s > 0)
+                    .ok_or_else(|| anyhow::anyhow!("'{step}' is not a valid step"))?;
+                (range, step)
+            }
+            None => (item, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            // A step without a range runs through to the end
+            None if step > 1 => (value(range)?, max),
+            None => {
+                let value = value(range)?;
+                (value, value)
+            }
+        };
+        anyhow::ensure!(start <= end, "'{range}' is an empty range");
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok((mask, field == "*"))
+}
+
+impl FromStr for CronSpec {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minute, hour, dom, month, dow] = fields[..] else {
+            anyhow::bail!(
+                "expected 5 fields (minute, hour, day of month, month, day of week) in '{s}'"
+            );
+        };
+        let field = |name, field, min, max, names| {
+            parse_cron_field(field, min, max, names)
+                .map_err(|err| anyhow::anyhow!("{name} field of '{s}': {err:#}"))
+        };
+
+        let (minutes, _) = field("minute", minute, 0, 59, &[])?;
+        let (hours, _) = field("hour", hour, 0, 23, &[])?;
+        let (days_of_month, any_dom) = field("day of month", dom, 1, 31, &[])?;
+        let (months, _) = field("month", month, 1, 12, MONTH_NAMES)?;
+        let (mut days_of_week, any_dow) = field("day of week", dow, 0, 7, DAY_NAMES)?;
+        // Both 0 and 7 are Sunday
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week |= 1;
+        }
+
+        Ok(Self {
+            text: s.to_string(),
+            minutes,
+            hours,
+            days_of_month,
+            months,
+            days_of_week,
+            either_day: !any_dom && !any_dow,
+        })
+    }
+}
+
+impl CronSpec {
+    pub fn matches<T: Datelike + Timelike>(&self, t: &T) -> bool {
+        let bit = |mask: u64, value: u32| mask & (1 << value) != 0;
+        let dom = bit(self.days_of_month, t.day());
+        let dow = bit(self.days_of_week, t.weekday().num_days_from_sunday());
+        let day = if self.either_day {
+            dom || dow
+        } else {
+            dom && dow
+        };
+        bit(self.minutes, t.minute())
+            && bit(self.hours, t.hour())
+            && bit(self.months, t.month())
+            && day
+    }
+}
+
+/// Returns true if `spec` fires at `minute`. Cron expressions are in
+/// local time, so daylight saving transitions need some care: when the
+/// clocks go back, a repeated local time only fires the first time
+/// around, and when they go forward, local times that were skipped
+/// fire at the first minute after the change.
+fn cron_fires_at(spec: &CronSpec, minute: &DateTime<Tz>) -> bool {
+    let tz = minute.timezone();
+    let local = minute.naive_local();
+    if tz.from_local_datetime(&local).earliest().as_ref() != Some(minute) {
+        return false;
+    }
+    if spec.matches(&local) {
+        return true;
+    }
+
+    let prior = (minute.with_timezone(&Utc) - Duration::minutes(1))
+        .with_timezone(&tz)
+        .naive_local();
+    let mut skipped = prior + Duration::minutes(1);
+    while skipped < local {
+        if spec.matches(&skipped) {
+            return true;
+        }
+        skipped += Duration::minutes(1);
+    }
+    false
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    Cron(CronSpec),
+    Sun { event: SunEvent, offset: Duration },
+}
+
+/// Parses an offset such as `+30m`, `-1h` or `+1h30m`
+fn parse_offset(s: &str) -> anyhow::Result<Duration> {
+    let (sign, mut rest) = match s.chars().next() {
+        Some('+') => (1, &s[1..]),
+        Some('-') => (-1, &s[1..]),
+        _ => anyhow::bail!("expected the offset '{s}' to start with + or -"),
+    };
+    anyhow::ensure!(!rest.is_empty(), "the offset '{s}' is empty");
+
+    let mut offset = Duration::zero();
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let value: i64 = rest[..digits]
+            .parse()
+            .map_err(|_| anyhow::anyhow!("expected a number in the offset '{s}'"))?;
+        let unit = rest[digits..].chars().next();
+        offset += match unit {
+            Some('h') => Duration::hours(value),
+            Some('m') => Duration::minutes(value),
+            _ => anyhow::bail!("expected the offset '{s}' to use h or m units"),
+        };
+        rest = &rest[digits + 1..];
+    }
+    Ok(offset * sign)
+}
+
+impl FromStr for Trigger {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        let lower = s.to_ascii_lowercase();
+        for (prefix, event) in [("sunrise", SunEvent::Sunrise), ("sunset", SunEvent::Sunset)] {
+            if let Some(offset) = lower.strip_prefix(prefix) {
+                let offset = if offset.is_empty() {
+                    Duration::zero()
+                } else {
+                    parse_offset(offset)?
+                };
+                return Ok(Self::Sun { event, offset });
+            }
+        }
+
+        // A plain time of day fires every day
+        if let Ok(time) = chrono::NaiveTime::parse_from_str(s, "%H:%M") {
+            return Ok(Self::Cron(
+                format!("{} {} * * *", time.minute(), time.hour()).parse()?,
+            ));
+        }
+
+        Ok(Self::Cron(s.parse()?))
+    }
+}
+
+impl std::fmt::Display for Trigger {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Cron(spec) => write!(f, "{}", spec.text),
+            Self::Sun { event, offset } => {
+                let event = match event {
+                    SunEvent::Sunrise => "sunrise",
+                    SunEvent::Sunset => "sunset",
+                };
+                let minutes = offset.num_minutes();
+                if minutes == 0 {
+                    write!(f, "{event}")
+                } else {
+                    write!(f, "{event}{minutes:+}m")
+                }
+            }
+        }
+    }
+}
+
+impl Trigger {
+    pub fn needs_location(&self) -> bool {
+        matches!(self, Self::Sun { .. })
+    }
+
+    /// Returns true if the trigger fires during the minute `minute`
+    pub fn fires_at(&self, minute: &DateTime<Tz>, location: Option<Location>) -> bool {
+        match self {
+            Self::Cron(spec) => cron_fires_at(spec, minute),
+            Self::Sun { event, offset } => {
+                let Some(location) = location else {
+                    return false;
+                };
+                let minute = minute.with_timezone(&Utc);
+                // The offset may move the event into an adjacent day
+                let date = minute.date_naive();
+                [date.pred_opt(), Some(date), date.succ_opt()]
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|date| sun_event(date, *event, location))
+                    .any(|t| truncate_to_minute(t + *offset) == minute)
+            }
+        }
+    }
+}
+
+impl ScheduleConfig {
+    pub fn fires_at(&self, minute: &DateTime<Tz>, location: Option<Location>) -> bool {
+        (self.days.is_empty() || self.days.contains(&minute.weekday()))
+            && self.at.fires_at(minute, location)
+    }
+}
+
+/// Returns the time zone in which schedules are evaluated
+pub fn schedule_timezone() -> Tz {
+    config()
+        .general
+        .timezone
+        .unwrap_or_else(crate::local_timezone)
+}
+
+/// Runs the schedules from the config file until the service stops
+pub async fn run_scheduler(state: StateHandle) -> anyhow::Result<()> {
+    let schedules = &config().schedule;
+    if schedules.is_empty() {
+        return Ok(());
+    }
+
+    let tz = schedule_timezone();
+    let location = config().general.location();
+    log::info!("Running {} schedule(s) in time zone {tz}", schedules.len());
+
+    let mut last = truncate_to_minute(Utc::now());
+    loop {
+        let next = last + Duration::minutes(1);
+        let delay = (next - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(delay).await;
+
+        let now = truncate_to_minute(Utc::now());
+        let mut minute =
+            (last + Duration::minutes(1)).max(now - Duration::minutes(MAX_CATCH_UP_MINUTES));
+        while minute <= now {
+            let local = minute.with_timezone(&tz);
+            for schedule in schedules {
+                if schedule.fires_at(&local, location) {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = run_schedule(&state, schedule).await {
+                            log::error!("schedule {}: {err:#}", schedule.label());
+                        }
+                    });
+                }
+            }
+            minute += Duration::minutes(1);
+        }
+        last = now;
+    }
+}
+
+/// Applies the actions of `schedule` to all of its devices concurrently
+async fn run_schedule(
+    state: &StateHandle,
+    schedule: &'static ScheduleConfig,
+) -> anyhow::Result<()> {
+    log::info!("Running schedule {}", schedule.label());
+
+    if let Some(name) = &schedule.oneclick {
+        state.activate_one_click(name).await?;
+    }
+
+    let mut targets: Vec<Device> = vec![];
+    for label in &schedule.devices {
+        match state.resolve_device_read_only(label).await {
+            Ok(device) => targets.push(device),
+            Err(err) => log::error!("schedule {}: {err:#}", schedule.label()),
+        }
+    }
+    for name in &schedule.groups {
+        if let Some(group) = group_by_name(name) {
+            targets.extend(group.members(state).await);
+        }
+    }
+    targets.sort_by(|a, b| a.id.cmp(&b.id));
+    targets.dedup_by(|a, b| a.id == b.id);
+
+    let num_targets = targets.len();
+    let mut tasks = JoinSet::new();
+    for device in targets {
+        let state = state.clone();
+        tasks.spawn(async move {
+            let result = async {
+                let device = state.resolve_device_for_control(&device.id).await?;
+                state
+                    .device_set_desired_state(&device, &schedule.desired_state())
+                    .await
+            }
+            .await;
+            (device, result)
+        });
+    }
+
+    let mut failed = 0;
+    while let Some(joined) = tasks.join_next().await {
+        let (device, result) = joined?;
+        if let Err(err) = result {
+            log::error!("schedule {}: {device} failed: {err:#}", schedule.label());
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("failed for {failed} of {num_targets} devices");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cron() {
+        let spec: CronSpec = "*/15 7-9 * * mon-fri".parse().unwrap();
+        let tz = chrono_tz::Europe::London;
+        // 2024-06-21 is a Friday
+        let at = |d, h, m| tz.with_ymd_and_hms(2024, 6, d, h, m, 0).unwrap();
+        assert!(spec.matches(&at(21, 7, 45)));
+        assert!(!spec.matches(&at(21, 7, 46)));
+        assert!(!spec.matches(&at(21, 10, 0)));
+        assert!(!spec.matches(&at(22, 8, 0)));
+
+        // Either the day of month or the day of week is sufficient
+        let spec: CronSpec = "0 12 1 * sun".parse().unwrap();
+        assert!(spec.matches(&at(1, 12, 0)));
+        assert!(spec.matches(&at(23, 12, 0)));
+        assert!(!spec.matches(&at(22, 12, 0)));
+
+        let spec: CronSpec = "30 6 * jun 7".parse().unwrap();
+        assert!(spec.matches(&at(23, 6, 30)));
+
+        k9::snapshot!(
+            "61 * * * *".parse::<CronSpec>().unwrap_err().to_string(),
+            "minute field of '61 * * * *': 61 is outside the range 0-59"
+        );
+        k9::snapshot!(
+            "* * * *".parse::<CronSpec>().unwrap_err().to_string(),
+            "expected 5 fields (minute, hour, day of month, month, day of week) in '* * * *'"
+        );
+    }
+
+    #[test]
+    fn daylight_saving() {
+        let tz = chrono_tz::Europe::London;
+        let fires_on = |y, m, d, at: &str| {
+            let trigger: Trigger = at.parse().unwrap();
+            let start = Utc.with_ymd_and_hms(y, m, d, 0, 0, 0).unwrap();
+            (0..24 * 60)
+                .map(|n| (start + Duration::minutes(n)).with_timezone(&tz))
+                .filter(|minute| trigger.fires_at(minute, None))
+                .map(|minute| minute.to_rfc3339())
+                .collect::<Vec<_>>()
+        };
+
+        // The clocks go back at 02:00 BST, so 01:30 happens twice
+        assert_eq!(
+            fires_on(2024, 10, 27, "01:30"),
+            vec!["2024-10-27T01:30:00+01:00"]
+        );
+        // The clocks go forward at 01:00 GMT, so 01:30 doesn't happen,
+        // and it fires along with 02:00 instead
+        assert_eq!(
+            fires_on(2024, 3, 31, "01:30"),
+            vec!["2024-03-31T02:00:00+01:00"]
+        );
+        assert_eq!(
+            fires_on(2024, 3, 31, "*/20 1-2 * * *"),
+            vec![
+                "2024-03-31T02:00:00+01:00",
+                "2024-03-31T02:20:00+01:00",
+                "2024-03-31T02:40:00+01:00"
+            ]
+        );
+    }
+
+    #[test]
+    fn triggers() {
+        let parse = |s: &str| s.parse::<Trigger>().map(|t| t.to_string());
+        assert_eq!(parse("sunset").unwrap(), "sunset");
+        assert_eq!(parse("Sunset-30m").unwrap(), "sunset-30m");
+        assert_eq!(parse("sunrise+1h30m").unwrap(), "sunrise+90m");
+        assert_eq!(parse("07:30").unwrap(), "30 7 * * *");
+        k9::snapshot!(
+            parse("sunset+30s").unwrap_err().to_string(),
+            "expected the offset '+30s' to use h or m units"
+        );
+        assert!(parse("noon").is_err());
+    }
+
+    #[test]
+    fn sunrise_sunset() {
+        let london = Location {
+            latitude: 51.5072,
+            longitude: -0.1276,
+        };
+        let date = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        let near = |t: DateTime<Utc>, h, m| {
+            let expected = Utc.with_ymd_and_hms(2024, 6, 21, h, m, 0).unwrap();
+            (t - expected).num_minutes().abs() <= 2
+        };
+        let sunrise = sun_event(date, SunEvent::Sunrise, london).unwrap();
+        assert!(near(sunrise, 3, 43), "{sunrise}");
+        let sunset = sun_event(date, SunEvent::Sunset, london).unwrap();
+        assert!(near(sunset, 20, 21), "{sunset}");
+
+        let trigger: Trigger = "sunset-30m".parse().unwrap();
+        let fires = truncate_to_minute(sunset - Duration::minutes(30))
+            .with_timezone(&chrono_tz::Europe::London);
+        assert!(trigger.fires_at(&fires, Some(london)));
+        assert!(!trigger.fires_at(&(fires + Duration::minutes(1)), Some(london)));
+        assert!(!trigger.fires_at(&fires, None));
+
+        let tromso = Location {
+            latitude: 69.6492,
+            longitude: 18.9553,
+        };
+        assert_eq!(sun_event(date, SunEvent::Sunrise, tromso), None);
+    }
+}
@@ -1,20 +1,35 @@
-use crate::ble::{Base64HexBytes, SetHumidifierMode, SetHumidifierNightlightParams};
+use crate::ble::{
+    segment_mask, Base64HexBytes, SetHumidifierMode, SetHumidifierNightlightParams, SetMusicMode,
+    SetSegmentBrightness, SetSegmentRgb,
+};
+use crate::cache::{cache_lookup, cache_put};
 use crate::lan_api::{Client as LanClient, DeviceStatus as LanDeviceStatus, LanDevice};
+use crate::metrics;
 use crate::platform_api::{DeviceCapability, GoveeApiClient};
 use crate::service::coordinator::Coordinator;
-use crate::service::device::Device;
+use crate::service::device::{Device, DeviceState, MusicSettings, PersistedDeviceState};
+use crate::service::fade::{Fade, FadeColor, FadePoint};
 use crate::service::hass::{topic_safe_id, HassClient};
 use crate::service::iot::IotClient;
+use crate::service::transport::Transport;
 use crate::temperature::{TemperatureScale, TemperatureValue};
-use crate::undoc_api::GoveeUndocumentedApi;
+use crate::undoc_api::{GoveeUndocumentedApi, ParsedOneClick};
 use anyhow::Context;
+use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Instant;
-use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard, Semaphore};
+use tokio::sync::{
+    broadcast, MappedMutexGuard, Mutex, MutexGuard, OwnedSemaphorePermit, Semaphore,
+};
+use tokio::task::AbortHandle;
 use tokio::time::{sleep, Duration};
 
+const PERSISTED_STATE_TOPIC: &str = "device-state";
+/// Saved state older than this isn't worth restoring
+const PERSISTED_STATE_TTL: Duration = Duration::from_secs(86400 * 7);
+
 #[derive(Default)]
 pub struct State {
     devices_by_id: Mutex<HashMap<String, Device>>,
@@ -23,9 +38,136 @@ pub struct State {
     platform_client: Mutex<Option<GoveeApiClient>>,
     undoc_client: Mutex<Option<GoveeUndocumentedApi>>,
     iot_client: Mutex<Option<IotClient>>,
+    accounts: Mutex<HashMap<String, AccountClients>>,
     hass_client: Mutex<Option<HassClient>>,
     hass_discovery_prefix: Mutex<String>,
     temperature_scale: Mutex<TemperatureScale>,
+    state_events: StateEvents,
+    fades: Fades,
+}
+
+/// Tracks the fades that are in progress, so that they can be
+/// cancelled when another command arrives for the same device
+#[derive(Default)]
+struct Fades {
+    task_by_id: parking_lot::Mutex<HashMap<String, FadeTask>>,
+    /// Distinguishes the fades of a device from one another
+    next_generation: std::sync::atomic::AtomicU64,
+    /// The brightness that devices had before they were faded off,
+    /// so that it can be restored when they are turned back on
+    brightness_before_off: parking_lot::Mutex<HashMap<String, u8>>,
+}
+
+struct FadeTask {
+    generation: u64,
+    handle: AbortHandle,
+}
+
+impl Fades {
+    /// Forgets the fade for `device_id`, unless it has
+    /// since been replaced by another fade
+    fn finished(&self, device_id: &str, generation: u64) {
+        let mut tasks = self.task_by_id.lock();
+        if tasks.get(device_id).map(|task| task.generation) == Some(generation) {
+            tasks.remove(device_id);
+        }
+    }
+}
+
+/// The clients for one of the additional accounts from the config
+/// file; the primary account uses the dedicated fields in State
+#[derive(Default, Clone)]
+struct AccountClients {
+    platform: Option<GoveeApiClient>,
+    undoc: Option<GoveeUndocumentedApi>,
+    iot: Option<IotClient>,
+}
+
+/// A One-Click shortcut, along with the account that it belongs to
+#[derive(Clone, Debug)]
+pub struct OneClick {
+    /// The additional account; None for the primary account
+    pub account: Option<String>,
+    pub item: ParsedOneClick,
+}
+
+/// Describes a change to the synthesized state of a device
+#[derive(Serialize, Clone, Debug)]
+pub struct DeviceStateEvent {
+    pub id: String,
+    pub sku: String,
+    pub name: String,
+    /// The API that produced the state; one of `lan`, `iot`,
+    /// `platform` or `restored`
+    pub source: &'static str,
+    pub state: DeviceState,
+}
+
+/// Distributes DeviceStateEvents to any interested subscribers,
+/// such as the event stream in the HTTP API
+struct StateEvents {
+    tx: broadcast::Sender<DeviceStateEvent>,
+    last_state_by_id: parking_lot::Mutex<HashMap<String, DeviceState>>,
+}
+
+impl Default for StateEvents {
+    fn default() -> Self {
+        let (tx, _rx) = broadcast::channel(128);
+        Self {
+            tx,
+            last_state_by_id: Default::default(),
+        }
+    }
+}
+
+/// The desired state of a device, as accepted by device_set_desired_state.
+/// Fields that are omitted are left unchanged.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DesiredState {
+    pub on: Option<bool>,
+    /// The brightness in percent (0-100)
+    pub brightness: Option<u8>,
+    /// A CSS color, such as `#ff0000` or `red`
+    pub color: Option<String>,
+    /// The color temperature in kelvin
+    pub kelvin: Option<u32>,
+    pub scene: Option<String>,
+}
+
+impl DesiredState {
+    /// Checks that the combination of fields makes sense,
+    /// returning the parsed RGB color, if any
+    pub fn validate(&self) -> anyhow::Result<Option<(u8, u8, u8)>> {
+        if self.on == Some(false)
+            && (self.brightness.is_some()
+                || self.color.is_some()
+                || self.kelvin.is_some()
+                || self.scene.is_some())
+        {
+            anyhow::bail!("cannot change other properties while turning the device off");
+        }
+        if self.scene.is_some() && (self.color.is_some() || self.kelvin.is_some()) {
+            anyhow::bail!("scene cannot be combined with color or kelvin");
+        }
+        if self.color.is_some() && self.kelvin.is_some() {
+            anyhow::bail!("color cannot be combined with kelvin");
+        }
+        if let Some(brightness) = self.brightness {
+            if brightness > 100 {
+                anyhow::bail!("brightness {brightness} is out of range 0-100");
+            }
+        }
+        match &self.color {
+            Some(color) => {
+                let parsed = csscolorparser::parse(color)
+                    .map_err(|err| anyhow::anyhow!("error parsing color '{color}': {err}"))?;
+                let [r, g, b, _a] = parsed.to_rgba8();
+                Ok(Some((r, g, b)))
+            }
+            None => Ok(None),
+        }
+    }
 }
 
 pub type StateHandle = Arc<State>;
@@ -52,13 +194,33 @@ impl State {
     }
 
     /// Returns a mutable version of the specified device, creating
-    /// an entry for it if necessary.
-    pub async fn device_mut(&self, sku: &str, id: &str) -> MappedMutexGuard<Device> {
+    /// an entry for it if necessary, in which case any state that
+    /// was persisted by a prior run is restored.
+    pub async fn device_mut(&self, sku: &str, id: &str) -> MappedMutexGuard<'_, Device> {
+        let devices = self.devices_by_id.lock().await;
+        if devices.contains_key(id) {
+            return MutexGuard::map(devices, |devices| {
+                devices.get_mut(id).expect("device to exist")
+            });
+        }
+        drop(devices);
+
+        // Restoring reads from the cache, so do that without holding
+        // the lock and off the async executor. Another task may have
+        // created the device in the meantime, in which case it wins.
+        let restored = {
+            let sku = sku.to_string();
+            let id = id.to_string();
+            tokio::task::spawn_blocking(move || restore_device(&sku, &id)).await
+        }
+        .unwrap_or_else(|err| {
+            log::warn!("Failed to restore persisted state for {id}: {err:#}");
+            Device::new(sku, id)
+        });
+
         let devices = self.devices_by_id.lock().await;
         MutexGuard::map(devices, |devices| {
-            devices
-                .entry(id.to_string())
-                .or_insert_with(|| Device::new(sku, id))
+            devices.entry(id.to_string()).or_insert(restored)
         })
     }
 
@@ -97,12 +259,33 @@ impl State {
         self: &Arc<Self>,
         label: &str,
     ) -> anyhow::Result<Coordinator> {
-        let device = self
-            .resolve_device(label)
-            .await
-            .ok_or_else(|| anyhow::anyhow!("device '{label}' not found"))?;
-        let semaphore = self.semaphore_for_device(&device).await;
-        let permit = semaphore.acquire_owned().await?;
+        let device = self.resolve_device_read_only(label).await?;
+        let permit = self.acquire_control_permit(&device).await?;
+        self.take_control(&device.id, permit).await
+    }
+
+    /// Waits until the calling task is permitted to control `device`.
+    /// The permit is released when it is dropped, or can be turned
+    /// into a Coordinator via take_control.
+    pub async fn acquire_control_permit(
+        &self,
+        device: &Device,
+    ) -> anyhow::Result<OwnedSemaphorePermit> {
+        let semaphore = self.semaphore_for_device(device).await;
+        Ok(semaphore.acquire_owned().await?)
+    }
+
+    /// Produces a Coordinator for the device labelled `label`, using
+    /// a permit obtained from acquire_control_permit.
+    /// The device is resolved again, so that the Coordinator reflects
+    /// any state that changed while waiting for the permit.
+    pub async fn take_control(
+        self: &Arc<Self>,
+        label: &str,
+        permit: OwnedSemaphorePermit,
+    ) -> anyhow::Result<Coordinator> {
+        let device = self.resolve_device_read_only(label).await?;
+        self.cancel_fade(&device.id);
         let (tx, rx) = tokio::sync::oneshot::channel();
 
         // Schedule a task that will poll the device a short
@@ -112,6 +295,7 @@ impl State {
         let device_id = device.id.to_string();
         tokio::spawn(async move {
             let _ = rx.await;
+            state.persist_device_state(&device_id).await;
             state.poll_after_control(device_id).await
         });
 
@@ -185,8 +369,135 @@ impl State {
         self.undoc_client.lock().await.clone()
     }
 
+    pub async fn set_account_platform_client(&self, account: &str, client: GoveeApiClient) {
+        self.accounts
+            .lock()
+            .await
+            .entry(account.to_string())
+            .or_default()
+            .platform
+            .replace(client);
+    }
+
+    pub async fn set_account_undoc_client(&self, account: &str, client: GoveeUndocumentedApi) {
+        self.accounts
+            .lock()
+            .await
+            .entry(account.to_string())
+            .or_default()
+            .undoc
+            .replace(client);
+    }
+
+    pub async fn set_account_iot_client(&self, account: &str, client: IotClient) {
+        self.accounts
+            .lock()
+            .await
+            .entry(account.to_string())
+            .or_default()
+            .iot
+            .replace(client);
+    }
+
+    async fn account_clients(&self, account: &str) -> AccountClients {
+        self.accounts
+            .lock()
+            .await
+            .get(account)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Returns the platform clients of the primary account, followed
+    /// by those of the additional accounts, ordered by account name
+    pub async fn platform_clients(&self) -> Vec<GoveeApiClient> {
+        let mut clients: Vec<GoveeApiClient> =
+            self.get_platform_client().await.into_iter().collect();
+        let accounts = self.accounts.lock().await;
+        let mut names: Vec<&String> = accounts.keys().collect();
+        names.sort();
+        for name in names {
+            if let Some(client) = &accounts[name].platform {
+                clients.push(client.clone());
+            }
+        }
+        clients
+    }
+
+    /// Returns the undocumented API clients of the primary account,
+    /// followed by those of the additional accounts, ordered by account name
+    async fn undoc_clients(&self) -> Vec<(Option<String>, GoveeUndocumentedApi)> {
+        let mut clients: Vec<_> = self
+            .get_undoc_client()
+            .await
+            .into_iter()
+            .map(|client| (None, client))
+            .collect();
+        let accounts = self.accounts.lock().await;
+        let mut names: Vec<&String> = accounts.keys().collect();
+        names.sort();
+        for name in names {
+            if let Some(client) = &accounts[name].undoc {
+                clients.push((Some(name.to_string()), client.clone()));
+            }
+        }
+        clients
+    }
+
+    /// Returns the platform client for the account that owns `device`
+    /// to control or poll it, unless its transport policy forbids that
+    pub async fn platform_client_for(&self, device: &Device) -> Option<GoveeApiClient> {
+        if !device.transport_policy().allows(Transport::Platform) {
+            return None;
+        }
+        self.platform_metadata_client_for(device).await
+    }
+
+    /// Returns the platform client for the account that owns `device`
+    /// to look up information about it, such as its list of scenes
+    pub async fn platform_metadata_client_for(&self, device: &Device) -> Option<GoveeApiClient> {
+        if !device.transport_policy().allows_cloud_metadata() {
+            return None;
+        }
+        match &device.account {
+            Some(account) => self.account_clients(account).await.platform,
+            None => self.get_platform_client().await,
+        }
+    }
+
+    /// Returns the IoT client for the account that owns `device`,
+    /// unless its transport policy forbids using it
+    pub async fn iot_client_for(&self, device: &Device) -> Option<IotClient> {
+        if !device.transport_policy().allows(Transport::Iot) {
+            return None;
+        }
+        match &device.account {
+            Some(account) => self.account_clients(account).await.iot,
+            None => self.get_iot_client().await,
+        }
+    }
+
+    /// Returns the LAN device to use to control `device`, unless its
+    /// transport policy forbids that, or prefers the IoT API and that
+    /// is available for the device
+    pub async fn lan_device_for<'a>(&self, device: &'a Device) -> Option<&'a LanDevice> {
+        let lan_dev = device.lan_device.as_ref()?;
+        let policy = device.transport_policy();
+        if !policy.allows(Transport::Lan) {
+            return None;
+        }
+        if policy.prefers_iot()
+            && device.iot_api_supported()
+            && device.undoc_device_info.is_some()
+            && self.iot_client_for(device).await.is_some()
+        {
+            return None;
+        }
+        Some(lan_dev)
+    }
+
     pub async fn poll_iot_api(self: &Arc<Self>, device: &Device) -> anyhow::Result<bool> {
-        if let Some(iot) = self.get_iot_client().await {
+        if let Some(iot) = self.iot_client_for(device).await {
             if let Some(info) = device.undoc_device_info.clone() {
                 if iot.is_device_compatible(&info.entry) {
                     let device_state = device.device_state();
@@ -218,7 +529,7 @@ impl State {
     }
 
     pub async fn poll_platform_api(self: &Arc<Self>, device: &Device) -> anyhow::Result<bool> {
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.platform_client_for(device).await {
             let device_state = device.device_state();
             log::info!("requesting update via Platform API {device} {device_state:?}");
             if let Some(info) = &device.http_device_info {
@@ -255,7 +566,15 @@ impl State {
             Some(client) => {
                 let deadline = Instant::now() + Duration::from_secs(5);
                 while Instant::now() <= deadline {
-                    let status = client.query_status(device).await?;
+                    let started = Instant::now();
+                    let status = client.query_status(device).await;
+                    metrics::poll_duration(
+                        &device.device,
+                        metrics::LAN,
+                        status.is_ok(),
+                        started.elapsed(),
+                    );
+                    let status = status?;
                     let accepted = (acceptor)(&status);
                     self.device_mut(&device.sku, &device.device)
                         .await
@@ -279,10 +598,14 @@ impl State {
         value: V,
     ) -> anyhow::Result<()> {
         let value: JsonValue = value.into();
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to send {value:?} control to {device}");
-                client.control_device(info, capability, value).await?;
+                metrics::command(
+                    metrics::PLATFORM,
+                    "control",
+                    client.control_device(info, capability, value).await,
+                )?;
                 return Ok(());
             }
         }
@@ -311,27 +634,35 @@ impl State {
                 )
             })?;
 
-        if let Some(lan_dev) = &device.lan_device {
+        if let Some(lan_dev) = self.lan_device_for(device).await {
             log::info!("Using LAN API to set {device} light power state");
-            lan_dev.send_turn(on).await?;
+            metrics::command(metrics::LAN, "power", lan_dev.send_turn(on).await)?;
             self.poll_lan_api(lan_dev, |status| status.on == on).await?;
             return Ok(());
         }
 
         if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
+            if let Some(iot) = self.iot_client_for(device).await {
                 if let Some(info) = &device.undoc_device_info {
                     log::info!("Using IoT API to set {device} light power state");
-                    iot.set_power_state(&info.entry, on).await?;
+                    metrics::command(
+                        metrics::IOT,
+                        "power",
+                        iot.set_power_state(&info.entry, on).await,
+                    )?;
                     return Ok(());
                 }
             }
         }
 
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} light {instance_name} state");
-                client.set_toggle_state(info, instance_name, on).await?;
+                metrics::command(
+                    metrics::PLATFORM,
+                    "power",
+                    client.set_toggle_state(info, instance_name, on).await,
+                )?;
                 return Ok(());
             }
         }
@@ -344,27 +675,35 @@ impl State {
         device: &Device,
         on: bool,
     ) -> anyhow::Result<()> {
-        if let Some(lan_dev) = &device.lan_device {
+        if let Some(lan_dev) = self.lan_device_for(device).await {
             log::info!("Using LAN API to set {device} power state");
-            lan_dev.send_turn(on).await?;
+            metrics::command(metrics::LAN, "power", lan_dev.send_turn(on).await)?;
             self.poll_lan_api(lan_dev, |status| status.on == on).await?;
             return Ok(());
         }
 
         if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
+            if let Some(iot) = self.iot_client_for(device).await {
                 if let Some(info) = &device.undoc_device_info {
                     log::info!("Using IoT API to set {device} power state");
-                    iot.set_power_state(&info.entry, on).await?;
+                    metrics::command(
+                        metrics::IOT,
+                        "power",
+                        iot.set_power_state(&info.entry, on).await,
+                    )?;
                     return Ok(());
                 }
             }
         }
 
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} power state");
-                client.set_power_state(info, on).await?;
+                metrics::command(
+                    metrics::PLATFORM,
+                    "power",
+                    client.set_power_state(info, on).await,
+                )?;
                 return Ok(());
             }
         }
@@ -387,28 +726,40 @@ impl State {
             return Ok(());
         }
 
-        if let Some(lan_dev) = &device.lan_device {
+        if let Some(lan_dev) = self.lan_device_for(device).await {
             log::info!("Using LAN API to set {device} brightness");
-            lan_dev.send_brightness(percent).await?;
+            metrics::command(
+                metrics::LAN,
+                "brightness",
+                lan_dev.send_brightness(percent).await,
+            )?;
             self.poll_lan_api(lan_dev, |status| status.brightness == percent)
                 .await?;
             return Ok(());
         }
 
         if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
+            if let Some(iot) = self.iot_client_for(device).await {
                 if let Some(info) = &device.undoc_device_info {
                     log::info!("Using IoT API to set {device} brightness");
-                    iot.set_brightness(&info.entry, percent).await?;
+                    metrics::command(
+                        metrics::IOT,
+                        "brightness",
+                        iot.set_brightness(&info.entry, percent).await,
+                    )?;
                     return Ok(());
                 }
             }
         }
 
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} brightness");
-                client.set_brightness(info, percent).await?;
+                metrics::command(
+                    metrics::PLATFORM,
+                    "brightness",
+                    client.set_brightness(info, percent).await,
+                )?;
                 return Ok(());
             }
         }
@@ -420,9 +771,13 @@ impl State {
         device: &Device,
         kelvin: u32,
     ) -> anyhow::Result<()> {
-        if let Some(lan_dev) = &device.lan_device {
+        if let Some(lan_dev) = self.lan_device_for(device).await {
             log::info!("Using LAN API to set {device} color temperature");
-            lan_dev.send_color_temperature_kelvin(kelvin).await?;
+            metrics::command(
+                metrics::LAN,
+                "color_temperature",
+                lan_dev.send_color_temperature_kelvin(kelvin).await,
+            )?;
             self.poll_lan_api(lan_dev, |status| status.color_temperature_kelvin == kelvin)
                 .await?;
             self.device_mut(&device.sku, &device.id)
@@ -432,19 +787,27 @@ impl State {
         }
 
         if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
+            if let Some(iot) = self.iot_client_for(device).await {
                 if let Some(info) = &device.undoc_device_info {
                     log::info!("Using IoT API to set {device} color temperature");
-                    iot.set_color_temperature(&info.entry, kelvin).await?;
+                    metrics::command(
+                        metrics::IOT,
+                        "color_temperature",
+                        iot.set_color_temperature(&info.entry, kelvin).await,
+                    )?;
                     return Ok(());
                 }
             }
         }
 
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} color temperature");
-                client.set_color_temperature(info, kelvin).await?;
+                metrics::command(
+                    metrics::PLATFORM,
+                    "color_temperature",
+                    client.set_color_temperature(info, kelvin).await,
+                )?;
                 self.device_mut(&device.sku, &device.id)
                     .await
                     .set_active_scene(None);
@@ -461,14 +824,18 @@ impl State {
         apply: F,
     ) -> anyhow::Result<bool> {
         let mut params: SetHumidifierNightlightParams =
-            device.nightlight_state.clone().unwrap_or_default().into();
+            device.nightlight_state.unwrap_or_default().into();
         (apply)(&mut params);
 
         if let Ok(command) = Base64HexBytes::encode_for_sku(&device.sku, &params) {
-            if let Some(iot) = self.get_iot_client().await {
+            if let Some(iot) = self.iot_client_for(device).await {
                 if let Some(info) = &device.undoc_device_info {
                     log::info!("Using IoT API to set {device} color");
-                    iot.send_real(&info.entry, vec![command.base64()]).await?;
+                    metrics::command(
+                        metrics::IOT,
+                        "nightlight",
+                        iot.send_real(&info.entry, vec![command.base64()]).await,
+                    )?;
                     return Ok(true);
                 }
             }
@@ -490,17 +857,25 @@ impl State {
                 param: value as u8,
             },
         ) {
-            if let Some(iot) = self.get_iot_client().await {
+            if let Some(iot) = self.iot_client_for(device).await {
                 if let Some(info) = &device.undoc_device_info {
-                    iot.send_real(&info.entry, vec![command.base64()]).await?;
+                    metrics::command(
+                        metrics::IOT,
+                        "work_mode",
+                        iot.send_real(&info.entry, vec![command.base64()]).await,
+                    )?;
                     return Ok(());
                 }
             }
         }
 
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
-                client.set_work_mode(info, work_mode, value).await?;
+                metrics::command(
+                    metrics::PLATFORM,
+                    "work_mode",
+                    client.set_work_mode(info, work_mode, value).await,
+                )?;
                 return Ok(());
             }
         }
@@ -526,10 +901,10 @@ impl State {
             return Ok(());
         }
 
-        if let Some(lan_dev) = &device.lan_device {
+        if let Some(lan_dev) = self.lan_device_for(device).await {
             let color = crate::lan_api::DeviceColor { r, g, b };
             log::info!("Using LAN API to set {device} color");
-            lan_dev.send_color_rgb(color).await?;
+            metrics::command(metrics::LAN, "color", lan_dev.send_color_rgb(color).await)?;
             self.poll_lan_api(lan_dev, |status| status.color == color)
                 .await?;
             self.device_mut(&device.sku, &device.id)
@@ -539,19 +914,27 @@ impl State {
         }
 
         if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
+            if let Some(iot) = self.iot_client_for(device).await {
                 if let Some(info) = &device.undoc_device_info {
                     log::info!("Using IoT API to set {device} color");
-                    iot.set_color_rgb(&info.entry, r, g, b).await?;
+                    metrics::command(
+                        metrics::IOT,
+                        "color",
+                        iot.set_color_rgb(&info.entry, r, g, b).await,
+                    )?;
                     return Ok(());
                 }
             }
         }
 
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} color");
-                client.set_color_rgb(info, r, g, b).await?;
+                metrics::command(
+                    metrics::PLATFORM,
+                    "color",
+                    client.set_color_rgb(info, r, g, b).await,
+                )?;
                 self.device_mut(&device.sku, &device.id)
                     .await
                     .set_active_scene(None);
@@ -561,17 +944,378 @@ impl State {
         anyhow::bail!("Unable to control color for {device}");
     }
 
+    /// Stop any fade that is in progress for the specified device.
+    /// The brightness from before a fade off is forgotten too, as
+    /// it is stale once another command has been applied; a command
+    /// that restores it must take it before calling this.
+    pub fn cancel_fade(&self, device_id: &str) {
+        if let Some(task) = self.fades.task_by_id.lock().remove(device_id) {
+            task.handle.abort();
+        }
+        self.fades.brightness_before_off.lock().remove(device_id);
+    }
+
+    /// Returns the brightness that the device had before it was
+    /// faded off, if any, forgetting it in the process
+    pub fn take_brightness_before_fade_off(&self, device_id: &str) -> Option<u8> {
+        self.fades.brightness_before_off.lock().remove(device_id)
+    }
+
+    /// Fade the light of a LAN device to `to` over `duration`,
+    /// turning it off at the end if `power_off` is true.
+    /// The fade runs in the background and is cancelled if
+    /// another command arrives for the device.
+    pub async fn device_fade(
+        self: &Arc<Self>,
+        device: &Device,
+        to: FadePoint,
+        power_off: bool,
+        duration: Duration,
+    ) -> anyhow::Result<()> {
+        let lan_dev = self
+            .lan_device_for(device)
+            .await
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Unable to fade {device} without the LAN API"))?;
+
+        let current = device.device_state();
+        let is_on = current
+            .as_ref()
+            .map(|s| s.light_on.unwrap_or(s.on))
+            .unwrap_or(false);
+
+        if power_off && !is_on {
+            log::info!("Using LAN API to set {device} power state");
+            metrics::command(metrics::LAN, "power", lan_dev.send_turn(false).await)?;
+            return Ok(());
+        }
+
+        let from = FadePoint {
+            brightness: match &current {
+                Some(s) if is_on => s.brightness,
+                _ => 0,
+            },
+            color: current.as_ref().map(|s| {
+                if s.kelvin != 0 {
+                    FadeColor::Kelvin(s.kelvin)
+                } else {
+                    FadeColor::Rgb(s.color)
+                }
+            }),
+        };
+        if power_off {
+            self.fades
+                .brightness_before_off
+                .lock()
+                .insert(device.id.to_string(), from.brightness);
+        }
+
+        let fade = Fade::new(from, to, duration);
+        log::info!(
+            "Using LAN API to fade {device} in {} steps over {duration:?}",
+            fade.steps
+        );
+
+        let state = self.clone();
+        let device_id = device.id.to_string();
+        let generation = self
+            .fades
+            .next_generation
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        // Hold the lock while spawning, so that the task can't
+        // finish and forget itself before it has been recorded
+        let mut tasks = self.fades.task_by_id.lock();
+        let task = tokio::spawn(async move {
+            if let Err(err) = state.run_fade(&lan_dev, fade, power_off).await {
+                log::error!("Fade for {} failed: {err:#}", lan_dev.device);
+            }
+            state.fades.finished(&lan_dev.device, generation);
+        });
+        let task = FadeTask {
+            generation,
+            handle: task.abort_handle(),
+        };
+        if let Some(prior) = tasks.insert(device_id, task) {
+            prior.handle.abort();
+        }
+
+        Ok(())
+    }
+
+    async fn run_fade(
+        self: &Arc<Self>,
+        lan_dev: &LanDevice,
+        fade: Fade,
+        power_off: bool,
+    ) -> anyhow::Result<()> {
+        if fade.from.brightness == 0 {
+            metrics::command(metrics::LAN, "power", lan_dev.send_turn(true).await)?;
+        }
+
+        let mut last: Option<FadePoint> = None;
+        for n in 1..=fade.steps {
+            if n > 1 {
+                sleep(fade.interval).await;
+            }
+            let point = fade.step(n);
+
+            if point.color != last.map(|p| p.color).unwrap_or(fade.from.color) {
+                match point.color {
+                    Some(FadeColor::Rgb(color)) => {
+                        metrics::command(
+                            metrics::LAN,
+                            "fade",
+                            lan_dev.send_color_rgb(color).await,
+                        )?;
+                    }
+                    Some(FadeColor::Kelvin(kelvin)) => {
+                        metrics::command(
+                            metrics::LAN,
+                            "fade",
+                            lan_dev.send_color_temperature_kelvin(kelvin).await,
+                        )?;
+                    }
+                    None => {}
+                }
+            }
+
+            // A brightness of 0 isn't valid; the device is turned
+            // off at the end of the fade instead
+            let brightness = point.brightness.max(1);
+            if Some(brightness) != last.map(|p| p.brightness.max(1)) {
+                metrics::command(
+                    metrics::LAN,
+                    "fade",
+                    lan_dev.send_brightness(brightness).await,
+                )?;
+            }
+
+            last.replace(point);
+        }
+
+        if power_off {
+            metrics::command(metrics::LAN, "power", lan_dev.send_turn(false).await)?;
+        }
+        if fade.to.color.is_some() {
+            self.device_mut(&lan_dev.sku, &lan_dev.device)
+                .await
+                .set_active_scene(None);
+        }
+
+        let target = fade.to.brightness;
+        self.poll_lan_api(lan_dev, |status| {
+            if power_off {
+                !status.on
+            } else {
+                status.on && status.brightness == target
+            }
+        })
+        .await
+    }
+
+    /// Send a BLE packet to the device via the LAN or IoT API.
+    /// Returns false if neither is available for the device.
+    async fn try_send_real(
+        self: &Arc<Self>,
+        device: &Device,
+        command_name: &str,
+        command: Base64HexBytes,
+    ) -> anyhow::Result<bool> {
+        if let Some(lan_dev) = self.lan_device_for(device).await {
+            log::info!("Using LAN API to send {command_name} to {device}");
+            metrics::command(
+                metrics::LAN,
+                command_name,
+                lan_dev.send_real(vec![command.base64()]).await,
+            )?;
+            return Ok(true);
+        }
+
+        if device.iot_api_supported() {
+            if let Some(iot) = self.iot_client_for(device).await {
+                if let Some(info) = &device.undoc_device_info {
+                    log::info!("Using IoT API to send {command_name} to {device}");
+                    metrics::command(
+                        metrics::IOT,
+                        command_name,
+                        iot.send_real(&info.entry, vec![command.base64()]).await,
+                    )?;
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    pub async fn device_set_segment_rgb(
+        self: &Arc<Self>,
+        device: &Device,
+        segment: u32,
+        r: u8,
+        g: u8,
+        b: u8,
+    ) -> anyhow::Result<()> {
+        if let Ok(segments) = segment_mask(segment) {
+            let command = Base64HexBytes::encode_for_sku(
+                "Generic:Light",
+                &SetSegmentRgb { r, g, b, segments },
+            )?;
+            if self.try_send_real(device, "segment_color", command).await? {
+                return Ok(());
+            }
+        }
+
+        if let Some(client) = self.platform_client_for(device).await {
+            if let Some(info) = &device.http_device_info {
+                log::info!("Using Platform API to set {device} segment {segment} color");
+                metrics::command(
+                    metrics::PLATFORM,
+                    "segment_color",
+                    client.set_segment_rgb(info, segment, r, g, b).await,
+                )?;
+                return Ok(());
+            }
+        }
+
+        anyhow::bail!("Unable to control segment {segment} color for {device}");
+    }
+
+    pub async fn device_set_segment_brightness(
+        self: &Arc<Self>,
+        device: &Device,
+        segment: u32,
+        percent: u8,
+    ) -> anyhow::Result<()> {
+        if let Ok(segments) = segment_mask(segment) {
+            let command = Base64HexBytes::encode_for_sku(
+                "Generic:Light",
+                &SetSegmentBrightness {
+                    brightness: percent,
+                    segments,
+                },
+            )?;
+            if self
+                .try_send_real(device, "segment_brightness", command)
+                .await?
+            {
+                return Ok(());
+            }
+        }
+
+        if let Some(client) = self.platform_client_for(device).await {
+            if let Some(info) = &device.http_device_info {
+                log::info!("Using Platform API to set {device} segment {segment} brightness");
+                metrics::command(
+                    metrics::PLATFORM,
+                    "segment_brightness",
+                    client.set_segment_brightness(info, segment, percent).await,
+                )?;
+                return Ok(());
+            }
+        }
+
+        anyhow::bail!("Unable to control segment {segment} brightness for {device}");
+    }
+
+    /// Activate the named music mode, using the music settings
+    /// that were most recently set for the device
+    pub async fn device_set_music_mode(
+        self: &Arc<Self>,
+        device: &Device,
+        mode: &str,
+    ) -> anyhow::Result<()> {
+        let settings = self
+            .device_by_id(&device.id)
+            .await
+            .map(|d| d.music_settings)
+            .unwrap_or_default();
+        self.send_music_mode(device, mode, settings).await
+    }
+
+    /// Change the sensitivity and auto color settings that are used
+    /// for music modes, re-applying the active music mode, if any
+    pub async fn device_set_music_settings<F: FnOnce(&mut MusicSettings)>(
+        self: &Arc<Self>,
+        device: &Device,
+        apply: F,
+    ) -> anyhow::Result<()> {
+        let (settings, active_mode) = {
+            let mut device = self.device_mut(&device.sku, &device.id).await;
+            (apply)(&mut device.music_settings);
+            (
+                device.music_settings,
+                device.active_music_mode().map(|m| m.to_string()),
+            )
+        };
+
+        if let Some(mode) = active_mode {
+            self.send_music_mode(device, &mode, settings).await?;
+        }
+
+        self.notify_of_state_change(&device.id).await
+    }
+
+    async fn send_music_mode(
+        self: &Arc<Self>,
+        device: &Device,
+        mode: &str,
+        settings: MusicSettings,
+    ) -> anyhow::Result<()> {
+        let info = device
+            .http_device_info
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Music modes are not known for {device}"))?;
+        let value = info
+            .music_mode_value(mode)
+            .ok_or_else(|| anyhow::anyhow!("{device} has no music mode {mode}"))?;
+
+        let mut sent = false;
+        if let Ok(mode) = u8::try_from(value) {
+            let command = Base64HexBytes::encode_for_sku(
+                "Generic:Light",
+                &SetMusicMode {
+                    mode,
+                    sensitivity: settings.sensitivity,
+                    auto_color: settings.auto_color,
+                    ..SetMusicMode::default()
+                },
+            )?;
+            sent = self.try_send_real(device, "music_mode", command).await?;
+        }
+
+        if !sent {
+            let client = self
+                .platform_client_for(device)
+                .await
+                .ok_or_else(|| anyhow::anyhow!("Unable to set music mode for {device}"))?;
+            log::info!("Using Platform API to set {device} to music mode {mode}");
+            metrics::command(
+                metrics::PLATFORM,
+                "music_mode",
+                client
+                    .set_music_mode(info, value, settings.sensitivity, settings.auto_color)
+                    .await,
+            )?;
+        }
+
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .set_active_scene(Some(&format!("Music: {mode}")));
+        Ok(())
+    }
+
     pub async fn poll_after_control(self: &Arc<Self>, id: String) {
         let Some(device) = self.device_by_id(&id).await else {
             return;
         };
 
-        let iot_available = self.get_iot_client().await.is_some();
+        let iot_available = self.iot_client_for(&device).await.is_some();
 
         if device.pollable_via_iot() && iot_available {
             return;
         }
-        if device.pollable_via_lan() {
+        if device.pollable_via_lan() && self.lan_device_for(&device).await.is_some() {
             return;
         }
 
@@ -589,7 +1333,7 @@ impl State {
 
     pub async fn device_list_scenes(&self, device: &Device) -> anyhow::Result<Vec<String>> {
         // TODO: some plumbing to maintain offline scene controls for preferred-LAN control
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.platform_metadata_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 return Ok(sort_and_dedup_scenes(client.list_scene_names(info).await?));
             }
@@ -606,12 +1350,16 @@ impl State {
         instance_name: &str,
         target: TemperatureValue,
     ) -> anyhow::Result<()> {
-        if let Some(client) = self.get_platform_client().await {
+        if let Some(client) = self.platform_client_for(device).await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} target temperature to {target}");
-                client
-                    .set_target_temperature(info, instance_name, target)
-                    .await?;
+                metrics::command(
+                    metrics::PLATFORM,
+                    "target_temperature",
+                    client
+                        .set_target_temperature(info, instance_name, target)
+                        .await,
+                )?;
                 return Ok(());
             }
         }
@@ -619,30 +1367,90 @@ impl State {
         anyhow::bail!("Unable to set temperature for {device}");
     }
 
+    /// Applies a combination of changes to `device`.
+    /// Power is applied first, so that the device is able to
+    /// accept the subsequent changes. The brightness is applied
+    /// last, as activating a scene can change it.
+    pub async fn device_set_desired_state(
+        self: &Arc<Self>,
+        device: &Device,
+        desired: &DesiredState,
+    ) -> anyhow::Result<()> {
+        let color = desired.validate()?;
+
+        if let Some(on) = desired.on {
+            self.device_power_on(device, on)
+                .await
+                .context("setting power state")?;
+        }
+        if let Some(scene) = &desired.scene {
+            self.device_set_scene(device, scene)
+                .await
+                .context("setting scene")?;
+        }
+        if let Some((r, g, b)) = color {
+            self.device_set_color_rgb(device, r, g, b)
+                .await
+                .context("setting color")?;
+        }
+        if let Some(kelvin) = desired.kelvin {
+            self.device_set_color_temperature(device, kelvin)
+                .await
+                .context("setting color temperature")?;
+        }
+        if let Some(brightness) = desired.brightness {
+            self.device_set_brightness(device, brightness)
+                .await
+                .context("setting brightness")?;
+        }
+        Ok(())
+    }
+
     pub async fn device_set_scene(
         self: &Arc<Self>,
         device: &Device,
         scene: &str,
     ) -> anyhow::Result<()> {
+        if let Some(mode) = scene.strip_prefix("Music: ") {
+            if device
+                .http_device_info
+                .as_ref()
+                .and_then(|info| info.music_mode_value(mode))
+                .is_some()
+            {
+                return self.device_set_music_mode(device, mode).await;
+            }
+        }
+
         // TODO: some plumbing to maintain offline scene controls for preferred-LAN control
         let avoid_platform_api = device.avoid_platform_api();
 
         if !avoid_platform_api {
-            if let Some(client) = self.get_platform_client().await {
+            if let Some(client) = self.platform_client_for(device).await {
                 if let Some(info) = &device.http_device_info {
                     log::info!("Using Platform API to set {device} to scene {scene}");
-                    client.set_scene_by_name(info, scene).await?;
+                    // Record the name from the effect list, which may differ
+                    // from `scene`, eg: a DIY scene selected without its label
+                    let name = metrics::command(
+                        metrics::PLATFORM,
+                        "scene",
+                        client.set_scene_by_name(info, scene).await,
+                    )?;
                     self.device_mut(&device.sku, &device.id)
                         .await
-                        .set_active_scene(Some(scene));
+                        .set_active_scene(Some(&name));
                     return Ok(());
                 }
             }
         }
 
-        if let Some(lan_dev) = &device.lan_device {
+        if let Some(lan_dev) = self.lan_device_for(device).await {
             log::info!("Using LAN API to set {device} to scene {scene}");
-            lan_dev.set_scene_by_name(scene).await?;
+            metrics::command(
+                metrics::LAN,
+                "scene",
+                lan_dev.set_scene_by_name(scene).await,
+            )?;
 
             self.device_mut(&device.sku, &device.id)
                 .await
@@ -653,13 +1461,121 @@ impl State {
         anyhow::bail!("Unable to set scene for {device}");
     }
 
+    /// Returns the One-Click shortcuts of all of the accounts.
+    /// An account whose shortcuts cannot be retrieved is skipped.
+    pub async fn list_one_clicks(&self) -> anyhow::Result<Vec<OneClick>> {
+        let clients = self.undoc_clients().await;
+        if clients.is_empty() {
+            anyhow::bail!("Undoc API client is not available");
+        }
+
+        let mut result = vec![];
+        for (account, undoc) in clients {
+            match undoc.parse_one_clicks().await {
+                Ok(items) => {
+                    result.extend(items.into_iter().map(|item| OneClick {
+                        account: account.clone(),
+                        item,
+                    }));
+                }
+                Err(err) => {
+                    log::warn!(
+                        "Failed to parse one-clicks for account {}: {err:#}",
+                        account.as_deref().unwrap_or("primary")
+                    );
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Activates the One-Click shortcut named `name`
+    pub async fn activate_one_click(&self, name: &str) -> anyhow::Result<()> {
+        let one_click = self
+            .list_one_clicks()
+            .await?
+            .into_iter()
+            .find(|oc| oc.item.name == name)
+            .ok_or_else(|| anyhow::anyhow!("didn't find item {name}"))?;
+        self.run_one_click(&one_click).await
+    }
+
+    /// Activates `one_click` using the IoT client of its account
+    pub async fn run_one_click(&self, one_click: &OneClick) -> anyhow::Result<()> {
+        let iot = match &one_click.account {
+            Some(account) => self.account_clients(account).await.iot,
+            None => self.get_iot_client().await,
+        }
+        .ok_or_else(|| anyhow::anyhow!("AWS IoT client is not available"))?;
+
+        iot.activate_one_click(&one_click.item).await
+    }
+
+    /// Save the state of the device to the cache, so that it
+    /// can be restored when the service is restarted.
+    /// This happens on every state change, so the sqlite write
+    /// is made on the blocking pool rather than on the executor.
+    pub async fn persist_device_state(&self, device_id: &str) {
+        let Some(device) = self.device_by_id(device_id).await else {
+            return;
+        };
+        let id = device.id.to_string();
+        let persisted = device.persisted_state();
+        let result = tokio::task::spawn_blocking(move || {
+            cache_put(PERSISTED_STATE_TOPIC, &id, &persisted, PERSISTED_STATE_TTL)
+        })
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|result| result);
+        if let Err(err) = result {
+            log::warn!("Failed to persist state of {device}: {err:#}");
+        }
+    }
+
+    /// Returns a receiver for the events that are emitted whenever
+    /// the state of a device changes
+    pub fn subscribe_state_events(&self) -> broadcast::Receiver<DeviceStateEvent> {
+        self.state_events.tx.subscribe()
+    }
+
+    fn emit_state_event(&self, device: &Device) {
+        if device.is_hidden() {
+            return;
+        }
+        let Some(state) = device.device_state() else {
+            return;
+        };
+
+        let changed = self
+            .state_events
+            .last_state_by_id
+            .lock()
+            .insert(device.id.to_string(), state.clone())
+            .map(|prior| prior.differs_from(&state))
+            .unwrap_or(true);
+
+        if changed {
+            // An error here just means that nobody is listening
+            let _ = self.state_events.tx.send(DeviceStateEvent {
+                id: device.id.to_string(),
+                sku: device.sku.to_string(),
+                name: device.name(),
+                source: state.source_kind(),
+                state,
+            });
+        }
+    }
+
     // Take care not to call this while you hold a mutable device
     // reference, as that will deadlock!
     pub async fn notify_of_state_change(self: &Arc<Self>, device_id: &str) -> anyhow::Result<()> {
-        let Some(canonical_device) = self.device_by_id(&device_id).await else {
+        let Some(canonical_device) = self.device_by_id(device_id).await else {
             anyhow::bail!("cannot find device {device_id}!?");
         };
 
+        self.persist_device_state(device_id).await;
+        self.emit_state_event(&canonical_device);
+
         if let Some(hass) = self.get_hass_client().await {
             hass.advise_hass_of_light_state(&canonical_device, self)
                 .await?;
@@ -669,8 +1585,85 @@ impl State {
     }
 }
 
+/// Create a new device, restoring the state that was
+/// saved by a prior run of the service, if any
+fn restore_device(sku: &str, id: &str) -> Device {
+    let mut device = Device::new(sku, id);
+    match cache_lookup::<PersistedDeviceState>(PERSISTED_STATE_TOPIC, id) {
+        Ok(Some(persisted)) => {
+            log::debug!("Restoring persisted state for {device}");
+            device.restore_persisted_state(persisted);
+        }
+        Ok(None) => {}
+        Err(err) => {
+            log::warn!("Failed to restore persisted state for {device}: {err:#}");
+        }
+    }
+    device
+}
+
 pub fn sort_and_dedup_scenes(mut scenes: Vec<String>) -> Vec<String> {
     scenes.sort_by_key(|s| s.to_ascii_lowercase());
     scenes.dedup();
     scenes
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lan_api::DeviceColor;
+
+    #[test]
+    fn brightness_before_fade_off_is_forgotten_by_other_commands() {
+        let state = State::new();
+        let id = "AA:BB:CC:DD:EE:FF:42:2A";
+        let remember = || {
+            state
+                .fades
+                .brightness_before_off
+                .lock()
+                .insert(id.to_string(), 42);
+        };
+
+        remember();
+        assert_eq!(state.take_brightness_before_fade_off(id), Some(42));
+        assert_eq!(state.take_brightness_before_fade_off(id), None);
+
+        // Taking control of the device for any other command
+        // makes the remembered brightness stale
+        remember();
+        state.cancel_fade(id);
+        assert_eq!(state.take_brightness_before_fade_off(id), None);
+    }
+
+    #[test]
+    fn state_events_are_only_emitted_on_change() {
+        let state = State::new();
+        let mut rx = state.subscribe_state_events();
+
+        let mut device = Device::new("H6072", "AA:BB:CC:DD:EE:FF:42:2A");
+        let mut status = LanDeviceStatus {
+            on: true,
+            brightness: 42,
+            color: DeviceColor { r: 255, g: 0, b: 0 },
+            color_temperature_kelvin: 0,
+        };
+        device.set_lan_device_status(status.clone());
+        state.emit_state_event(&device);
+
+        let event = rx.try_recv().unwrap();
+        assert_eq!(event.id, "AA:BB:CC:DD:EE:FF:42:2A");
+        assert_eq!(event.source, "lan");
+        assert_eq!(event.state.brightness, 42);
+
+        // Same state with a newer timestamp doesn't produce an event
+        device.set_lan_device_status(status.clone());
+        state.emit_state_event(&device);
+        assert!(rx.try_recv().is_err());
+
+        status.brightness = 50;
+        device.set_lan_device_status(status);
+        state.emit_state_event(&device);
+        assert_eq!(rx.try_recv().unwrap().state.brightness, 50);
+    }
+}
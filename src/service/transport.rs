@@ -0,0 +1,114 @@
+//! Policies that control which transports are used to control and
+//! poll a device. The policy can be set for all devices via
+//! `general.transport`, and overridden per device in its `[device]`
+//! section of the config file.
+use crate::config::config;
+use crate::opt_env_var;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Lan,
+    Iot,
+    Platform,
+}
+
+impl Transport {
+    /// Returns the transport that produced a `DeviceState` with
+    /// the specified `source`, if it was produced by one
+    pub fn from_state_source(source: &str) -> Option<Self> {
+        match source {
+            "LAN API" => Some(Self::Lan),
+            "AWS IoT API" => Some(Self::Iot),
+            "PLATFORM API" => Some(Self::Platform),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransportPolicy {
+    /// Prefer the LAN API, then the IoT API, then the Platform API
+    #[default]
+    Auto,
+    /// Prefer the IoT API, then the LAN API, then the Platform API
+    PreferIot,
+    /// Control and poll the device only via the LAN API. Cloud
+    /// metadata, such as the list of scenes, is still used.
+    LanOnly,
+    /// Never send anything about this device to the Govee cloud;
+    /// this excludes the IoT and Platform APIs entirely
+    NeverCloud,
+}
+
+impl TransportPolicy {
+    /// Returns the policy that applies to devices that don't
+    /// have one configured in their device section
+    pub fn global_default() -> Self {
+        match opt_env_var("GOVEE_TRANSPORT") {
+            Ok(Some(policy)) => policy,
+            Ok(None) => config().general.transport.unwrap_or_default(),
+            Err(err) => {
+                log::error!("{err:#}, using the auto transport policy");
+                Self::Auto
+            }
+        }
+    }
+
+    pub fn allows(&self, transport: Transport) -> bool {
+        match self {
+            Self::Auto | Self::PreferIot => true,
+            Self::LanOnly | Self::NeverCloud => transport == Transport::Lan,
+        }
+    }
+
+    /// Returns true if the Platform API may be used to look up
+    /// information about the device, such as its scenes
+    pub fn allows_cloud_metadata(&self) -> bool {
+        *self != Self::NeverCloud
+    }
+
+    /// Returns true if the IoT API should be tried before the LAN API
+    pub fn prefers_iot(&self) -> bool {
+        *self == Self::PreferIot
+    }
+}
+
+impl FromStr for TransportPolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "prefer-iot" => Ok(Self::PreferIot),
+            "lan-only" => Ok(Self::LanOnly),
+            "never-cloud" => Ok(Self::NeverCloud),
+            _ => anyhow::bail!(
+                "Unknown transport policy {s}; \
+                 expected one of auto, prefer-iot, lan-only or never-cloud"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn policies() {
+        assert_eq!(
+            "prefer-iot".parse::<TransportPolicy>().unwrap(),
+            TransportPolicy::PreferIot
+        );
+        assert!("cloud-only".parse::<TransportPolicy>().is_err());
+
+        assert!(TransportPolicy::Auto.allows(Transport::Platform));
+        assert!(TransportPolicy::LanOnly.allows(Transport::Lan));
+        assert!(!TransportPolicy::LanOnly.allows(Transport::Iot));
+        assert!(TransportPolicy::LanOnly.allows_cloud_metadata());
+        assert!(!TransportPolicy::NeverCloud.allows(Transport::Platform));
+        assert!(!TransportPolicy::NeverCloud.allows_cloud_metadata());
+    }
+}
@@ -1,5 +1,7 @@
 #![allow(unused)]
+use crate::backoff::{send_with_breaker, upstream_for_account, UNDOC_API};
 use crate::cache::{cache_get, CacheComputeResult, CacheGetOptions};
+use crate::config::config;
 use crate::lan_api::{boolean_int, truthy};
 use crate::opt_env_var;
 use crate::platform_api::{
@@ -10,7 +12,7 @@ use reqwest::Method;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value as JsonValue};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use uuid::Uuid;
 
@@ -31,7 +33,7 @@ pub fn should_log_sensitive_data() -> bool {
     if let Ok(Some(v)) = opt_env_var::<String>("GOVEE_LOG_SENSITIVE_DATA") {
         truthy(&v).unwrap_or(false)
     } else {
-        false
+        config().general.log_sensitive_data.unwrap_or(false)
     }
 }
 
@@ -81,23 +83,26 @@ pub struct UndocApiArguments {
     pub govee_password: Option<String>,
 
     /// Where to store the AWS IoT key file.
-    #[arg(long, global = true, default_value = "/dev/shm/govee.iot.key")]
-    pub govee_iot_key: PathBuf,
+    /// If unspecified, uses /dev/shm/govee.iot.key
+    #[arg(long, global = true)]
+    pub govee_iot_key: Option<PathBuf>,
 
     /// Where to store the AWS IoT certificate file.
-    #[arg(long, global = true, default_value = "/dev/shm/govee.iot.cert")]
-    pub govee_iot_cert: PathBuf,
+    /// If unspecified, uses /dev/shm/govee.iot.cert
+    #[arg(long, global = true)]
+    pub govee_iot_cert: Option<PathBuf>,
 
-    /// Where to find the AWS root CA certificate
-    #[arg(long, global = true, default_value = "AmazonRootCA1.pem")]
-    pub amazon_root_ca: PathBuf,
+    /// Where to find the AWS root CA certificate.
+    /// If unspecified, uses AmazonRootCA1.pem
+    #[arg(long, global = true)]
+    pub amazon_root_ca: Option<PathBuf>,
 }
 
 impl UndocApiArguments {
     pub fn opt_email(&self) -> anyhow::Result<Option<String>> {
         match &self.govee_email {
             Some(key) => Ok(Some(key.to_string())),
-            None => opt_env_var("GOVEE_EMAIL"),
+            None => Ok(opt_env_var("GOVEE_EMAIL")?.or_else(|| config().govee.email.clone())),
         }
     }
 
@@ -105,7 +110,8 @@ impl UndocApiArguments {
         self.opt_email()?.ok_or_else(|| {
             anyhow::anyhow!(
                 "Please specify the govee account email either via the \
-                --govee-email parameter or by setting $GOVEE_EMAIL"
+                --govee-email parameter, by setting $GOVEE_EMAIL \
+                or via govee.email in the config file"
             )
         })
     }
@@ -113,7 +119,7 @@ impl UndocApiArguments {
     pub fn opt_password(&self) -> anyhow::Result<Option<String>> {
         match &self.govee_password {
             Some(key) => Ok(Some(key.to_string())),
-            None => opt_env_var("GOVEE_PASSWORD"),
+            None => Ok(opt_env_var("GOVEE_PASSWORD")?.or_else(|| config().govee.password.clone())),
         }
     }
 
@@ -121,11 +127,33 @@ impl UndocApiArguments {
         self.opt_password()?.ok_or_else(|| {
             anyhow::anyhow!(
                 "Please specify the govee account password either via the \
-                --govee-password parameter or by setting $GOVEE_PASSWORD"
+                --govee-password parameter, by setting $GOVEE_PASSWORD \
+                or via govee.password in the config file"
             )
         })
     }
 
+    pub fn iot_key_path(&self) -> PathBuf {
+        self.govee_iot_key
+            .clone()
+            .or_else(|| config().govee.iot_key.clone())
+            .unwrap_or_else(|| "/dev/shm/govee.iot.key".into())
+    }
+
+    pub fn iot_cert_path(&self) -> PathBuf {
+        self.govee_iot_cert
+            .clone()
+            .or_else(|| config().govee.iot_cert.clone())
+            .unwrap_or_else(|| "/dev/shm/govee.iot.cert".into())
+    }
+
+    pub fn amazon_root_ca_path(&self) -> PathBuf {
+        self.amazon_root_ca
+            .clone()
+            .or_else(|| config().govee.amazon_root_ca.clone())
+            .unwrap_or_else(|| "AmazonRootCA1.pem".into())
+    }
+
     pub fn api_client(&self) -> anyhow::Result<GoveeUndocumentedApi> {
         let email = self.email()?;
         let password = self.password()?;
@@ -138,6 +166,7 @@ pub struct GoveeUndocumentedApi {
     email: String,
     password: String,
     client_id: String,
+    account: Option<String>,
 }
 
 impl GoveeUndocumentedApi {
@@ -150,6 +179,39 @@ impl GoveeUndocumentedApi {
             email,
             password,
             client_id,
+            account: None,
+        }
+    }
+
+    /// Associates this client with one of the additional accounts
+    /// from the config file, so that its cached data is kept
+    /// separate from that of the primary account
+    pub fn with_account<A: Into<String>>(mut self, account: A) -> Self {
+        self.account.replace(account.into());
+        self
+    }
+
+    /// Returns the name of the circuit breaker upstream for this client
+    fn upstream(&self) -> String {
+        upstream_for_account(UNDOC_API, self.account.as_deref())
+    }
+
+    fn cache_key(&self, key: &str) -> String {
+        match &self.account {
+            Some(account) => format!("{account}:{key}"),
+            None => key.to_string(),
+        }
+    }
+
+    /// Returns the path to use for the IoT key or certificate file
+    /// of this account; additional accounts store theirs alongside
+    /// `path`, prefixed with the account name
+    pub fn account_file_path(&self, path: &Path) -> PathBuf {
+        match (&self.account, path.file_name()) {
+            (Some(account), Some(name)) => {
+                path.with_file_name(format!("{account}.{}", name.to_string_lossy()))
+            }
+            _ => path.to_path_buf(),
         }
     }
 
@@ -158,14 +220,14 @@ impl GoveeUndocumentedApi {
         cache_get(
             CacheGetOptions {
                 topic: "undoc-api",
-                key: "iot-key",
+                key: &self.cache_key("iot-key"),
                 soft_ttl: HALF_DAY,
                 hard_ttl: HALF_DAY,
                 negative_ttl: Duration::from_secs(10),
                 allow_stale: false,
             },
             async {
-                let response = reqwest::Client::builder()
+                let request = reqwest::Client::builder()
                     .timeout(Duration::from_secs(30))
                     .build()?
                     .request(Method::GET, "https://app2.govee.com/app/v1/account/iot/key")
@@ -175,9 +237,8 @@ impl GoveeUndocumentedApi {
                     .header("clientType", "1")
                     .header("iotVersion", "0")
                     .header("timestamp", ms_timestamp())
-                    .header("User-Agent", user_agent())
-                    .send()
-                    .await?;
+                    .header("User-Agent", user_agent());
+                let response = send_with_breaker(&self.upstream(), request).await?;
 
                 #[derive(Deserialize, Debug)]
                 #[allow(non_snake_case, dead_code)]
@@ -196,11 +257,11 @@ impl GoveeUndocumentedApi {
     }
 
     pub fn invalidate_account_login(&self) {
-        crate::cache::invalidate_key("undoc-api", "account-info").ok();
+        crate::cache::invalidate_key("undoc-api", &self.cache_key("account-info")).ok();
     }
 
     async fn login_account_impl(&self) -> anyhow::Result<CacheComputeResult<LoginAccountResponse>> {
-        let response = reqwest::Client::builder()
+        let request = reqwest::Client::builder()
             .timeout(Duration::from_secs(30))
             .build()?
             .request(
@@ -211,9 +272,8 @@ impl GoveeUndocumentedApi {
                 "email": self.email,
                 "password": self.password,
                 "client": &self.client_id,
-            }))
-            .send()
-            .await?;
+            }));
+        let response = send_with_breaker(&self.upstream(), request).await?;
 
         let resp: Response = http_response_body(response).await?;
 
@@ -233,7 +293,7 @@ impl GoveeUndocumentedApi {
         cache_get(
             CacheGetOptions {
                 topic: "undoc-api",
-                key: "account-info",
+                key: &self.cache_key("account-info"),
                 soft_ttl: HALF_DAY,
                 hard_ttl: HALF_DAY,
                 negative_ttl: FIFTEEN_MINS,
@@ -251,7 +311,7 @@ impl GoveeUndocumentedApi {
     }
 
     pub async fn get_device_list(&self, token: &str) -> anyhow::Result<DevicesResponse> {
-        let response = reqwest::Client::builder()
+        let request = reqwest::Client::builder()
             .timeout(Duration::from_secs(30))
             .build()?
             .request(
@@ -264,9 +324,8 @@ impl GoveeUndocumentedApi {
             .header("clientType", "1")
             .header("iotVersion", "0")
             .header("timestamp", ms_timestamp())
-            .header("User-Agent", user_agent())
-            .send()
-            .await?;
+            .header("User-Agent", user_agent());
+        let response = send_with_breaker(&self.upstream(), request).await?;
 
         if response.status() == reqwest::StatusCode::UNAUTHORIZED {
             self.invalidate_account_login();
@@ -278,7 +337,7 @@ impl GoveeUndocumentedApi {
     }
 
     pub fn invalidate_community_login(&self) {
-        crate::cache::invalidate_key("undoc-api", "community-login").ok();
+        crate::cache::invalidate_key("undoc-api", &self.cache_key("community-login")).ok();
     }
 
     /// Login to community-api.govee.com and return the bearer token
@@ -286,23 +345,22 @@ impl GoveeUndocumentedApi {
         cache_get(
             CacheGetOptions {
                 topic: "undoc-api",
-                key: "community-login",
+                key: &self.cache_key("community-login"),
                 soft_ttl: ONE_DAY,
                 hard_ttl: HALF_DAY,
                 negative_ttl: Duration::from_secs(10),
                 allow_stale: false,
             },
             async {
-                let response = reqwest::Client::builder()
+                let request = reqwest::Client::builder()
                     .timeout(Duration::from_secs(60))
                     .build()?
                     .request(Method::POST, "https://community-api.govee.com/os/v1/login")
                     .json(&serde_json::json!({
                         "email": self.email,
                         "password": self.password,
-                    }))
-                    .send()
-                    .await?;
+                    }));
+                let response = send_with_breaker(&self.upstream(), request).await?;
 
                 #[derive(Deserialize, Debug)]
                 #[allow(non_snake_case, dead_code)]
@@ -339,38 +397,51 @@ impl GoveeUndocumentedApi {
         .await
     }
 
+    fn scenes_cache_options(key: &str) -> CacheGetOptions<'_> {
+        CacheGetOptions {
+            topic: "undoc-api",
+            key,
+            soft_ttl: ONE_DAY,
+            hard_ttl: ONE_WEEK,
+            negative_ttl: Duration::from_secs(1),
+            allow_stale: true,
+        }
+    }
+
+    /// Supplies the scene library for `sku`, so that tests can
+    /// activate scenes without querying Govee
+    #[cfg(test)]
+    pub async fn set_scenes_for_device(
+        sku: &str,
+        categories: Vec<LightEffectCategory>,
+    ) -> anyhow::Result<()> {
+        let key = format!("scenes-{sku}");
+        cache_get(Self::scenes_cache_options(&key), async {
+            Ok(CacheComputeResult::Value(categories))
+        })
+        .await?;
+        Ok(())
+    }
+
     pub async fn get_scenes_for_device(sku: &str) -> anyhow::Result<Vec<LightEffectCategory>> {
         let key = format!("scenes-{sku}");
 
-        cache_get(
-            CacheGetOptions {
-                topic: "undoc-api",
-                key: &key,
-                soft_ttl: ONE_DAY,
-                hard_ttl: ONE_WEEK,
-                negative_ttl: Duration::from_secs(1),
-                allow_stale: true,
-            },
-            async {
-                let response = reqwest::Client::builder()
-                    .timeout(Duration::from_secs(10))
-                    .build()?
-                    .request(
-                        Method::GET,
-                        format!(
-                            "https://app2.govee.com/appsku/v1/light-effect-libraries?sku={sku}"
-                        ),
-                    )
-                    .header("AppVersion", APP_VERSION)
-                    .header("User-Agent", user_agent())
-                    .send()
-                    .await?;
+        cache_get(Self::scenes_cache_options(&key), async {
+            let request = reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()?
+                .request(
+                    Method::GET,
+                    format!("https://app2.govee.com/appsku/v1/light-effect-libraries?sku={sku}"),
+                )
+                .header("AppVersion", APP_VERSION)
+                .header("User-Agent", user_agent());
+            let response = send_with_breaker(UNDOC_API, request).await?;
 
-                let resp: LightEffectLibraryResponse = http_response_body(response).await?;
+            let resp: LightEffectLibraryResponse = http_response_body(response).await?;
 
-                Ok(CacheComputeResult::Value(resp.data.categories))
-            },
-        )
+            Ok(CacheComputeResult::Value(resp.data.categories))
+        })
         .await
     }
 
@@ -384,7 +455,7 @@ impl GoveeUndocumentedApi {
 
         for c in catalog {
             for s in c.scenes {
-                if let Some(param_id) = s.light_effects.get(0).map(|e| e.scence_param_id) {
+                if let Some(param_id) = s.light_effects.first().map(|e| e.scence_param_id) {
                     options.push(EnumOption {
                         name: s.scene_name,
                         value: json!({
@@ -413,14 +484,14 @@ impl GoveeUndocumentedApi {
         cache_get(
             CacheGetOptions {
                 topic: "undoc-api",
-                key: "one-click-shortcuts",
+                key: &self.cache_key("one-click-shortcuts"),
                 soft_ttl: ONE_DAY,
                 hard_ttl: ONE_WEEK,
                 negative_ttl: Duration::from_secs(1),
                 allow_stale: true,
             },
             async {
-                let response = reqwest::Client::builder()
+                let request = reqwest::Client::builder()
                     .timeout(Duration::from_secs(10))
                     .build()?
                     .request(
@@ -433,9 +504,8 @@ impl GoveeUndocumentedApi {
                     .header("clientType", "1")
                     .header("iotVersion", "0")
                     .header("timestamp", ms_timestamp())
-                    .header("User-Agent", user_agent())
-                    .send()
-                    .await?;
+                    .header("User-Agent", user_agent());
+                let response = send_with_breaker(&self.upstream(), request).await?;
 
                 if response.status() == reqwest::StatusCode::UNAUTHORIZED {
                     self.invalidate_community_login();
@@ -460,7 +530,12 @@ impl GoveeUndocumentedApi {
                     continue;
                 }
 
-                let name = format!("One-Click: {}: {}", group.name, oc.name);
+                // Shortcuts from additional accounts are labelled with
+                // the account name, as their names can otherwise clash
+                let name = match &self.account {
+                    Some(account) => format!("One-Click: {account}: {}: {}", group.name, oc.name),
+                    None => format!("One-Click: {}: {}", group.name, oc.name),
+                };
 
                 let mut entries = vec![];
                 for rule in oc.iot_rules {
@@ -925,6 +1000,22 @@ mod test {
     use super::*;
     use crate::platform_api::from_json;
 
+    #[test]
+    fn account_keys_and_paths() {
+        let primary = GoveeUndocumentedApi::new("a@example.com", "secret");
+        let cabin = GoveeUndocumentedApi::new("b@example.com", "secret").with_account("cabin");
+
+        assert_eq!(primary.cache_key("iot-key"), "iot-key");
+        assert_eq!(cabin.cache_key("iot-key"), "cabin:iot-key");
+
+        let path = Path::new("/dev/shm/govee.iot.key");
+        assert_eq!(primary.account_file_path(path), path);
+        assert_eq!(
+            cabin.account_file_path(path),
+            Path::new("/dev/shm/cabin.govee.iot.key")
+        );
+    }
+
     #[test]
     fn get_device_scenes() {
         let resp: DevicesResponse =